@@ -0,0 +1,3 @@
+-- Thumbnail rows are meaningless without the column, drop them first.
+DELETE FROM images WHERE variant != 0;
+ALTER TABLE images DROP COLUMN variant;
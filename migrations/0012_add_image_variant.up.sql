@@ -0,0 +1,3 @@
+-- Distinguish downloaded thumbnails from original images.
+-- 0 = original, 1 = small, 2 = regular
+ALTER TABLE images ADD COLUMN variant INTEGER CHECK (variant in (0, 1, 2)) NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS fanbox_creator_aliases;
@@ -0,0 +1,8 @@
+-- Human-readable folder names for fanbox creators, used by the per-creator download layout
+CREATE TABLE fanbox_creator_aliases (
+    creator_id TEXT PRIMARY KEY,
+    alias TEXT NOT NULL,
+
+    -- Two creators sharing a folder would mix up their files
+    UNIQUE(alias)
+);
@@ -0,0 +1,3 @@
+DROP INDEX illusts_series;
+ALTER TABLE illusts DROP COLUMN series_order;
+ALTER TABLE illusts DROP COLUMN series_id;
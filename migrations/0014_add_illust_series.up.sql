@@ -0,0 +1,4 @@
+-- Series the illustration belongs to, and its position within, NULL if not in a series
+ALTER TABLE illusts ADD COLUMN series_id INTEGER;
+ALTER TABLE illusts ADD COLUMN series_order INTEGER;
+CREATE INDEX illusts_series ON illusts (series_id, series_order);
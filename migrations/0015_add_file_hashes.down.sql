@@ -0,0 +1,3 @@
+ALTER TABLE images DROP COLUMN hash;
+ALTER TABLE fanbox_images DROP COLUMN hash;
+ALTER TABLE fanbox_files DROP COLUMN hash;
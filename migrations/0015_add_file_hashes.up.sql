@@ -0,0 +1,4 @@
+-- SHA-256 of the downloaded content, hex-encoded. NULL until hashed by `database file checksum-index`.
+ALTER TABLE images ADD COLUMN hash TEXT;
+ALTER TABLE fanbox_images ADD COLUMN hash TEXT;
+ALTER TABLE fanbox_files ADD COLUMN hash TEXT;
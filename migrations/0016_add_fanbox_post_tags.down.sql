@@ -0,0 +1 @@
+DROP TABLE fanbox_post_tags;
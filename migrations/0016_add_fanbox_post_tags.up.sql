@@ -0,0 +1,8 @@
+-- Tags of fanbox posts, sharing the tags table with pixiv illustrations
+CREATE TABLE fanbox_post_tags (
+    post_id INTEGER NOT NULL,
+    tag_id INTEGER NOT NULL,
+    PRIMARY KEY (post_id, tag_id),
+    FOREIGN KEY (post_id) REFERENCES fanbox_posts(id) ON DELETE CASCADE,
+    FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+DROP INDEX fanbox_posts_cover_path;
+ALTER TABLE fanbox_posts DROP COLUMN cover_downloaded_at;
+ALTER TABLE fanbox_posts DROP COLUMN cover_path;
+ALTER TABLE fanbox_posts DROP COLUMN cover_url;
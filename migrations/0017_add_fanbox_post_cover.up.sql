@@ -0,0 +1,5 @@
+-- Cover image of fanbox posts. cover_url is NULL if the post has no cover, cover_path if it's not downloaded.
+ALTER TABLE fanbox_posts ADD COLUMN cover_url TEXT;
+ALTER TABLE fanbox_posts ADD COLUMN cover_path TEXT;
+ALTER TABLE fanbox_posts ADD COLUMN cover_downloaded_at TEXT;
+CREATE UNIQUE INDEX fanbox_posts_cover_path ON fanbox_posts (cover_path);
@@ -0,0 +1,13 @@
+-- External links embedded in rich fanbox post bodies (urlEmbedMap).
+-- url is resolved from the embed content, and NULL if the embed type is unknown.
+CREATE TABLE fanbox_embeds (
+    id TEXT NOT NULL,
+    post_id INTEGER NOT NULL,
+    idx INTEGER NOT NULL,
+
+    embed_type TEXT NOT NULL,
+    url TEXT,
+
+    PRIMARY KEY (post_id, id),
+    FOREIGN KEY (post_id) REFERENCES fanbox_posts(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Whether the post is marked as containing adult content. NULL for posts synced before this was recorded.
+ALTER TABLE fanbox_posts ADD COLUMN has_adult_content BOOLEAN;
@@ -0,0 +1,2 @@
+DROP TABLE tag_translations;
+ALTER TABLE tags DROP COLUMN romaji;
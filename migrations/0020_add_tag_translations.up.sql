@@ -0,0 +1,11 @@
+-- Romanized reading of the tag, as given by pixiv in detailed fetches
+ALTER TABLE tags ADD COLUMN romaji TEXT;
+
+-- Translations of tags into other languages, keyed by pixiv's language code (e.g. `en`)
+CREATE TABLE tag_translations (
+    tag_id INTEGER NOT NULL,
+    lang TEXT NOT NULL,
+    translation TEXT NOT NULL,
+    PRIMARY KEY (tag_id, lang),
+    FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+DROP TABLE novel_tags;
+DROP TABLE novels;
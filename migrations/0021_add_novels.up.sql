@@ -0,0 +1,33 @@
+CREATE TABLE novels (
+    id INTEGER PRIMARY KEY,
+    title TEXT NOT NULL,
+    author_id INTEGER NOT NULL,
+    description TEXT NOT NULL,
+
+    x_restrict INTEGER CHECK (x_restrict in (0, 1, 2)) NOT NULL,
+    ai_type INTEGER CHECK (ai_type in (0, 1, 2)) NOT NULL,
+    text_count INTEGER NOT NULL,
+    series_id INTEGER,
+
+    create_date TEXT NOT NULL,
+    update_date TEXT NOT NULL,
+
+    -- NULL until fetched, and reset when the novel is updated
+    content TEXT,
+    content_fetched_at TEXT,
+
+    bookmark_id INTEGER,
+    bookmark_private BOOLEAN,
+
+    fetched_at TEXT NOT NULL,
+
+    FOREIGN KEY (author_id) REFERENCES authors(id)
+);
+
+CREATE TABLE novel_tags (
+    novel_id INTEGER NOT NULL,
+    tag_id INTEGER NOT NULL,
+    PRIMARY KEY (novel_id, tag_id),
+    FOREIGN KEY (novel_id) REFERENCES novels(id) ON DELETE CASCADE,
+    FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+);
@@ -0,0 +1,10 @@
+-- Plain text of the post body for searching, with rich bodies flattened to their paragraphs and headers
+ALTER TABLE fanbox_posts ADD COLUMN body_text TEXT;
+
+UPDATE fanbox_posts SET body_text = COALESCE((
+    SELECT group_concat(json_extract(block.value, '$.text'), char(10))
+    FROM json_each(fanbox_posts.body) AS block
+    WHERE json_extract(block.value, '$.type') IN ('p', 'header')
+), '') WHERE is_body_rich AND body IS NOT NULL AND body != '';
+
+UPDATE fanbox_posts SET body_text = body WHERE body_text IS NULL;
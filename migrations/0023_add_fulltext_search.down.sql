@@ -0,0 +1,9 @@
+DROP TRIGGER fanbox_posts_fts_update;
+DROP TRIGGER fanbox_posts_fts_delete;
+DROP TRIGGER fanbox_posts_fts_insert;
+DROP TABLE fanbox_posts_fts;
+
+DROP TRIGGER illusts_fts_update;
+DROP TRIGGER illusts_fts_delete;
+DROP TRIGGER illusts_fts_insert;
+DROP TABLE illusts_fts;
@@ -0,0 +1,43 @@
+-- Full-text indexes over titles, descriptions and fanbox bodies, kept in sync by triggers.
+-- The trigram tokenizer matches any substring of at least 3 characters, which also works for
+-- Japanese text without word boundaries.
+
+CREATE VIRTUAL TABLE illusts_fts USING fts5(
+    title, content_desc,
+    content='illusts', content_rowid='id', tokenize='trigram'
+);
+
+CREATE TRIGGER illusts_fts_insert AFTER INSERT ON illusts BEGIN
+    INSERT INTO illusts_fts (rowid, title, content_desc) VALUES (new.id, new.title, new.content_desc);
+END;
+
+CREATE TRIGGER illusts_fts_delete AFTER DELETE ON illusts BEGIN
+    INSERT INTO illusts_fts (illusts_fts, rowid, title, content_desc) VALUES ('delete', old.id, old.title, old.content_desc);
+END;
+
+CREATE TRIGGER illusts_fts_update AFTER UPDATE OF title, content_desc ON illusts BEGIN
+    INSERT INTO illusts_fts (illusts_fts, rowid, title, content_desc) VALUES ('delete', old.id, old.title, old.content_desc);
+    INSERT INTO illusts_fts (rowid, title, content_desc) VALUES (new.id, new.title, new.content_desc);
+END;
+
+INSERT INTO illusts_fts (illusts_fts) VALUES ('rebuild');
+
+CREATE VIRTUAL TABLE fanbox_posts_fts USING fts5(
+    title, body_text,
+    content='fanbox_posts', content_rowid='id', tokenize='trigram'
+);
+
+CREATE TRIGGER fanbox_posts_fts_insert AFTER INSERT ON fanbox_posts BEGIN
+    INSERT INTO fanbox_posts_fts (rowid, title, body_text) VALUES (new.id, new.title, new.body_text);
+END;
+
+CREATE TRIGGER fanbox_posts_fts_delete AFTER DELETE ON fanbox_posts BEGIN
+    INSERT INTO fanbox_posts_fts (fanbox_posts_fts, rowid, title, body_text) VALUES ('delete', old.id, old.title, old.body_text);
+END;
+
+CREATE TRIGGER fanbox_posts_fts_update AFTER UPDATE OF title, body_text ON fanbox_posts BEGIN
+    INSERT INTO fanbox_posts_fts (fanbox_posts_fts, rowid, title, body_text) VALUES ('delete', old.id, old.title, old.body_text);
+    INSERT INTO fanbox_posts_fts (rowid, title, body_text) VALUES (new.id, new.title, new.body_text);
+END;
+
+INSERT INTO fanbox_posts_fts (fanbox_posts_fts) VALUES ('rebuild');
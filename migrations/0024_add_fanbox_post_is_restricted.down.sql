@@ -0,0 +1 @@
+ALTER TABLE fanbox_posts DROP COLUMN is_restricted;
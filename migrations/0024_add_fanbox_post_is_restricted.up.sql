@@ -0,0 +1,4 @@
+-- Whether the post body was inaccessible (e.g. paywalled) when it was last stored
+ALTER TABLE fanbox_posts ADD COLUMN is_restricted BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE fanbox_posts SET is_restricted = body IS NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE illusts DROP COLUMN view_count;
+ALTER TABLE illusts DROP COLUMN comment_count;
+ALTER TABLE illusts DROP COLUMN like_count;
+ALTER TABLE illusts DROP COLUMN bookmark_count;
@@ -0,0 +1,5 @@
+-- Popularity stats at the time of the last detail fetch (by ID), NULL if the illustration was only synced from bookmarks
+ALTER TABLE illusts ADD COLUMN bookmark_count INTEGER;
+ALTER TABLE illusts ADD COLUMN like_count INTEGER;
+ALTER TABLE illusts ADD COLUMN comment_count INTEGER;
+ALTER TABLE illusts ADD COLUMN view_count INTEGER;
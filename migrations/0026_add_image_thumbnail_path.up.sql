@@ -0,0 +1,2 @@
+-- Locally generated thumbnail, relative to the pixiv base directory. NULL if none was generated.
+ALTER TABLE images ADD COLUMN thumbnail_path TEXT;
@@ -3,7 +3,18 @@ use std::collections::HashMap;
 use clap::Args;
 use futures::{StreamExt, pin_mut};
 
-use crate::util::TerminationCondition;
+use crate::{
+    data::pixiv::XRestrict,
+    util::{TerminationCondition, parse_cli_duration},
+};
+
+#[derive(Default)]
+struct Progress {
+    /// Illustrations stored or looked at, excluding those filtered out by `--x-restrict`
+    scanned: usize,
+    /// Illustrations that were inserted or updated
+    new: usize,
+}
 
 #[derive(Args)]
 pub struct Bookmarks {
@@ -15,17 +26,57 @@ pub struct Bookmarks {
     /// Initial offset
     offset: usize,
 
+    #[arg(long, alias = "max-cnt")]
+    /// Maximum number of scanned illustrations, including unchanged and skipped ones (alias: --max-cnt)
+    max_scan: Option<usize>,
+
     #[arg(long)]
-    /// Maximum number of fetched illustrations
-    max_cnt: Option<usize>,
+    /// Maximum number of newly inserted or updated illustrations
+    ///
+    /// Unchanged and skipped illustrations don't count, so this limits the amount of actual changes in an incremental sync.
+    max_new: Option<usize>,
 
     #[arg(short, long)]
     /// Fetch private bookmarks
     private: bool,
 
+    #[arg(long, conflicts_with = "private")]
+    /// Fetch public bookmarks, then private bookmarks
+    ///
+    /// `--termination on-hit` applies to each pass separately, while `--max-scan`, `--max-new` and `--max-duration` apply to both combined.
+    all_visibility: bool,
+
     #[arg(alias="term", long, value_enum, default_value_t = TerminationCondition::UntilEnd)]
     /// Termination condition (alias: --term)
     termination: TerminationCondition,
+
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    /// Only terminate after this many consecutive already existing, unchanged illustrations
+    ///
+    /// Implies `--termination on-hit`, which stops at the first one. A larger value tolerates old bookmarks that slipped in near the top.
+    stop_after_hits: Option<u64>,
+
+    #[arg(long)]
+    /// Stop at the first bookmark with an ID at or below this one, e.g. the latest stored bookmark ID
+    ///
+    /// Bookmarks are listed newest first, so everything after it is older. Applies to each pass of `--all-visibility` separately.
+    since: Option<u64>,
+
+    #[arg(long, alias = "timeout-total", value_parser = parse_cli_duration)]
+    /// Stop after this much time has elapsed, e.g. `30m` or `1h` (alias: --timeout-total)
+    ///
+    /// Checked between illustrations, so the one being fetched is always stored.
+    max_duration: Option<std::time::Duration>,
+
+    #[arg(long, value_enum)]
+    /// Only store bookmarks with this age restriction
+    ///
+    /// Other bookmarks, including masked ones whose restriction is unknown, are skipped without being stored or counted.
+    x_restrict: Option<XRestrict>,
+
+    #[arg(long)]
+    /// Only fetch and log what would be inserted or updated, without writing to the database
+    dry_run: bool,
 }
 
 impl Bookmarks {
@@ -34,52 +85,239 @@ impl Bookmarks {
         session: &crate::config::Session,
         db: &crate::db::Database,
     ) -> anyhow::Result<()> {
-        let bookmarks = crate::data::pixiv::get_bookmarks(
-            &session,
-            self.tag.as_deref(),
-            self.offset,
-            self.private,
-        )
-        .await;
-        pin_mut!(bookmarks);
+        let passes: &[bool] = if self.all_visibility {
+            &[false, true]
+        } else {
+            &[self.private]
+        };
+        let deadline = crate::util::deadline_after(self.max_duration);
         let mut tag_map_ctx: HashMap<String, u64> = HashMap::new();
-        let mut cnt = 0;
+        let mut progress = Progress::default();
+        for &hidden in passes {
+            if self.all_visibility {
+                tracing::info!(
+                    "Syncing {} bookmarks",
+                    if hidden { "private" } else { "public" }
+                );
+            }
+            let finished = self
+                .sync_pass(
+                    session,
+                    db,
+                    hidden,
+                    deadline,
+                    &mut tag_map_ctx,
+                    &mut progress,
+                )
+                .await?;
+            if finished {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync bookmarks of one visibility. Returns true if the whole sync should stop.
+    async fn sync_pass(
+        &self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+        hidden: bool,
+        deadline: Option<tokio::time::Instant>,
+        tag_map_ctx: &mut HashMap<String, u64>,
+        progress: &mut Progress,
+    ) -> anyhow::Result<bool> {
+        let bookmarks =
+            crate::data::pixiv::get_bookmarks(session, self.tag.as_deref(), self.offset, hidden)
+                .await;
+        self.store_bookmarks(db, bookmarks, deadline, tag_map_ctx, progress)
+            .await
+    }
+
+    /// Store fetched bookmarks until one of the termination conditions is met. Returns true if the whole sync should stop.
+    async fn store_bookmarks(
+        &self,
+        db: &crate::db::Database,
+        bookmarks: impl futures::Stream<Item = anyhow::Result<crate::data::pixiv::Illust>>,
+        deadline: Option<tokio::time::Instant>,
+        tag_map_ctx: &mut HashMap<String, u64>,
+        progress: &mut Progress,
+    ) -> anyhow::Result<bool> {
+        pin_mut!(bookmarks);
+        let hit_limit = match self.stop_after_hits {
+            Some(n) => Some(n),
+            None if self.termination == TerminationCondition::OnHit => Some(1),
+            None => None,
+        };
+        let mut hits = 0;
         while let Some(illust) = bookmarks.next().await {
+            if crate::interrupt::is_interrupted() {
+                return Ok(true);
+            }
             let illust = illust?;
-            let update_result = db.update_illust(&illust, &mut tag_map_ctx).await?;
-            let update_prompt = match update_result {
-                crate::db::IllustUpdateResult::Inserted => "INSERTED",
-                crate::db::IllustUpdateResult::BookmarkIDChanged => "BMIDCHANGED",
-                crate::db::IllustUpdateResult::Updated => "UPDATED",
-                crate::db::IllustUpdateResult::Skipped => "SKIPPED",
+            if let Some(since) = self.since
+                && let Some(ref bookmark) = illust.bookmark
+                && bookmark.id <= since
+            {
+                tracing::info!(
+                    "Reached bookmark {} of illustration {}, at or below --since {}. Terminating.",
+                    bookmark.id,
+                    illust.id,
+                    since
+                );
+                return Ok(false);
+            }
+            if let Some(x_restrict) = self.x_restrict
+                && illust.data.as_simple().map(|d| d.x_restrict) != Some(x_restrict)
+            {
+                tracing::debug!("Skipping {}: not matching --x-restrict", illust.id);
+                continue;
+            }
+            let update_result = if self.dry_run {
+                db.preview_update_illust(&illust, tag_map_ctx).await?
+            } else {
+                db.update_illust(&illust, tag_map_ctx).await?
             };
+            let update_prompt = update_result.label();
             tracing::info!(
-                "Queried {}: [{}] {}",
+                "{} {}: [{}] {}",
+                if self.dry_run { "Previewed" } else { "Queried" },
                 illust.id,
                 update_prompt,
                 illust.data.display_title()
             );
 
-            if update_result == crate::db::IllustUpdateResult::Updated
-                && self.termination == TerminationCondition::OnHit
+            if matches!(
+                update_result,
+                crate::db::IllustUpdateResult::Unchanged | crate::db::IllustUpdateResult::Skipped
+            ) {
+                hits += 1;
+            } else {
+                hits = 0;
+            }
+            if let Some(hit_limit) = hit_limit
+                && hits >= hit_limit
+            {
+                if hit_limit == 1 {
+                    tracing::info!(
+                        "Encountered an already existing illustration, which is unchanged. Terminating."
+                    );
+                } else {
+                    tracing::info!(
+                        "Encountered {} already existing, unchanged illustrations in a row. Terminating.",
+                        hits
+                    );
+                }
+                return Ok(false);
+            }
+
+            progress.scanned += 1;
+            if hits == 0 {
+                progress.new += 1;
+            }
+            if let Some(max_scan) = self.max_scan
+                && progress.scanned >= max_scan
             {
                 tracing::info!(
-                    "Encountered an already existing illustration, whose bookmark ID is unchanged. Terminating."
+                    "Reached the maximum number of scanned illustrations ({}, {} new or updated). Terminating.",
+                    max_scan,
+                    progress.new
                 );
-                break;
+                return Ok(true);
+            }
+            if let Some(max_new) = self.max_new
+                && progress.new >= max_new
+            {
+                tracing::info!(
+                    "Reached the maximum number of new or updated illustrations ({}, {} scanned). Terminating.",
+                    max_new,
+                    progress.scanned
+                );
+                return Ok(true);
             }
 
-            cnt += 1;
-            if let Some(max_count) = self.max_cnt
-                && cnt >= max_count
+            if let Some(deadline) = deadline
+                && tokio::time::Instant::now() >= deadline
             {
                 tracing::info!(
-                    "Reached the maximum number of fetched illustrations ({}). Terminating.",
-                    max_count
+                    "Reached the maximum duration after {} illustrations (last: {}). Terminating.",
+                    progress.scanned,
+                    illust.id
                 );
-                break;
+                return Ok(true);
             }
         }
-        Ok(())
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::db::tests::{bookmarked, memory_db};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        bookmarks: Bookmarks,
+    }
+
+    /// Sync the given bookmarks, newest first, and return the number of scanned ones
+    async fn sync(
+        db: &crate::db::Database,
+        args: &[&str],
+        illusts: Vec<crate::data::pixiv::Illust>,
+    ) -> usize {
+        let cli =
+            Cli::try_parse_from(std::iter::once("bookmarks").chain(args.iter().copied())).unwrap();
+        let mut progress = Progress::default();
+        cli.bookmarks
+            .store_bookmarks(
+                db,
+                futures::stream::iter(illusts.into_iter().map(Ok)),
+                None,
+                &mut HashMap::new(),
+                &mut progress,
+            )
+            .await
+            .unwrap();
+        progress.scanned
+    }
+
+    #[tokio::test]
+    async fn on_hit_stops_at_unchanged() {
+        let db = memory_db().await;
+        let existing = || {
+            vec![
+                bookmarked(3, 30, false, &["a"]),
+                bookmarked(2, 20, false, &[]),
+                bookmarked(1, 10, false, &[]),
+            ]
+        };
+        assert_eq!(sync(&db, &["--term", "on-hit"], existing()).await, 3);
+
+        // 5 is new, 3 has new bookmark tags and is updated, 2 is unchanged
+        let mut illusts = vec![bookmarked(5, 50, false, &[])];
+        illusts.extend(existing());
+        illusts[1] = bookmarked(3, 30, false, &["a", "b"]);
+        assert_eq!(sync(&db, &["--term", "on-hit"], illusts).await, 2);
+
+        // An unchanged illustration in between new ones only stops the sync with on-hit
+        let illusts = || {
+            vec![
+                bookmarked(7, 70, false, &[]),
+                bookmarked(3, 30, false, &["a", "b"]),
+                bookmarked(4, 40, true, &[]),
+                bookmarked(2, 20, false, &[]),
+                bookmarked(1, 10, false, &[]),
+            ]
+        };
+        assert_eq!(
+            sync(&db, &["--dry-run", "--term", "on-hit"], illusts()).await,
+            1
+        );
+        assert_eq!(sync(&db, &["--stop-after-hits", "2"], illusts()).await, 4);
     }
 }
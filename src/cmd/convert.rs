@@ -0,0 +1,286 @@
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use clap::Args;
+
+use crate::{
+    data::pixiv::{ImageVariant, UgoiraFrame},
+    util::DownloadIdSrc,
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum UgoiraFormat {
+    /// Animated GIF. Delays are rounded to 10ms, and colors are quantized
+    Gif,
+
+    /// Animated PNG, lossless
+    Apng,
+
+    /// Animated WebP, requires ffmpeg
+    Webp,
+
+    /// H.264 MP4, requires ffmpeg
+    Mp4,
+}
+
+impl UgoiraFormat {
+    fn ext(self) -> &'static str {
+        match self {
+            UgoiraFormat::Gif => "gif",
+            UgoiraFormat::Apng => "png",
+            UgoiraFormat::Webp => "webp",
+            UgoiraFormat::Mp4 => "mp4",
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct Convert {
+    #[clap(flatten)]
+    /// ID of the illustration
+    id: DownloadIdSrc<u64>,
+
+    /// Output format
+    #[arg(short, long, value_enum)]
+    format: UgoiraFormat,
+
+    /// Abort if failed
+    #[arg(long)]
+    abort_on_fail: bool,
+}
+
+impl Convert {
+    pub async fn run(
+        self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+    ) -> anyhow::Result<()> {
+        let mut collected_errs = Vec::new();
+        for id in self.id.read()? {
+            let id = id?;
+            if let Err(e) = convert_ugoira(session, db, id, self.format).await {
+                if self.abort_on_fail {
+                    return Err(e);
+                } else {
+                    tracing::error!("Failed to convert {}: {:?}", id, e);
+                    collected_errs.push((id, e));
+                }
+            }
+        }
+
+        if collected_errs.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} errors occurred during conversion",
+                collected_errs.len()
+            ))
+        }
+    }
+}
+
+/// Convert the downloaded ugoira zip of an illust into an animation next to it.
+///
+/// Skipped if the animation already exists.
+pub async fn convert_ugoira(
+    session: &crate::config::Session,
+    db: &crate::db::Database,
+    id: u64,
+    format: UgoiraFormat,
+) -> anyhow::Result<()> {
+    let (path, frames) = db.get_ugoira_for(id).await?.ok_or_else(|| {
+        anyhow::anyhow!(
+            "No downloaded ugoira for {}. Please run `pixivdwn download {}` first.",
+            id,
+            id
+        )
+    })?;
+    let zip_path = crate::util::resolve_pixiv_image_path(session, &path, ImageVariant::Original)?;
+    // Ugoira may be delivered as an animated image already, whatever the extension says
+    let is_zip = tokio::task::block_in_place(|| {
+        let file = std::fs::File::open(&zip_path)?;
+        crate::util::is_zip(&mut std::io::BufReader::new(file))
+    })
+    .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", zip_path.display(), e))?;
+    if !is_zip {
+        tracing::info!(
+            "{} is already an animated image, skipping conversion",
+            zip_path.display()
+        );
+        return Ok(());
+    }
+    let output = zip_path.with_extension(format.ext());
+    if tokio::fs::try_exists(&output).await? {
+        tracing::info!("{} already exists, skipping", output.display());
+        return Ok(());
+    }
+
+    let dir = output
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Invalid path: {}", output.display()))?;
+    let tmp = tempfile::NamedTempFile::with_prefix_in("pixivdwn_", dir)?;
+    tracing::info!(
+        "Converting {} ({} frames) into {}",
+        zip_path.display(),
+        frames.len(),
+        output.display()
+    );
+    tokio::task::block_in_place(|| match format {
+        UgoiraFormat::Gif => encode_gif(&zip_path, &frames, tmp.as_file()),
+        UgoiraFormat::Apng => encode_apng(&zip_path, &frames, tmp.as_file()),
+        UgoiraFormat::Webp | UgoiraFormat::Mp4 => {
+            encode_ffmpeg(&zip_path, &frames, tmp.path(), format)
+        }
+    })?;
+    tmp.persist(&output)?;
+    tracing::info!("Saved to {}", output.display());
+    Ok(())
+}
+
+/// Decode frames from the zip in order, along with their delay in ms
+fn decode_frames(
+    zip_path: &Path,
+    frames: &[UgoiraFrame],
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<(image::RgbaImage, u64)>>> {
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(zip_path)?)?;
+    let mut idx = 0;
+    Ok(std::iter::from_fn(move || {
+        let frame = frames.get(idx)?;
+        idx += 1;
+        let mut decode = || -> anyhow::Result<_> {
+            let mut content = Vec::new();
+            archive.by_name(&frame.file)?.read_to_end(&mut content)?;
+            let img = image::ImageReader::new(std::io::Cursor::new(content))
+                .with_guessed_format()?
+                .decode()?;
+            Ok((img.into_rgba8(), frame.delay))
+        };
+        Some(decode())
+    }))
+}
+
+fn encode_gif(zip_path: &Path, frames: &[UgoiraFrame], output: impl Write) -> anyhow::Result<()> {
+    let mut encoder = image::codecs::gif::GifEncoder::new_with_speed(output, 10);
+    encoder.set_repeat(image::codecs::gif::Repeat::Infinite)?;
+    for frame in decode_frames(zip_path, frames)? {
+        let (img, delay) = frame?;
+        let delay = image::Delay::from_numer_denom_ms(delay as u32, 1);
+        encoder.encode_frame(image::Frame::from_parts(img, 0, 0, delay))?;
+    }
+    Ok(())
+}
+
+fn encode_apng(zip_path: &Path, frames: &[UgoiraFrame], output: impl Write) -> anyhow::Result<()> {
+    let mut decoded = decode_frames(zip_path, frames)?.peekable();
+    let (width, height) = match decoded.peek() {
+        Some(Ok((img, _))) => img.dimensions(),
+        Some(Err(_)) => return Err(decoded.next().unwrap().unwrap_err()),
+        None => return Err(anyhow::anyhow!("Ugoira has no frames")),
+    };
+
+    let mut encoder = png::Encoder::new(output, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    let mut writer = encoder.write_header()?;
+    for frame in decoded {
+        let (img, delay) = frame?;
+        if img.dimensions() != (width, height) {
+            return Err(anyhow::anyhow!("Ugoira frames have different dimensions"));
+        }
+        writer.set_frame_delay(delay.min(u16::MAX as u64) as u16, 1000)?;
+        writer.write_image_data(img.as_raw())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn encode_ffmpeg(
+    zip_path: &Path,
+    frames: &[UgoiraFrame],
+    output: &Path,
+    format: UgoiraFormat,
+) -> anyhow::Result<()> {
+    // Extract frames, and describe their timing with a concat demuxer script
+    let tmp_dir = tempfile::tempdir()?;
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(zip_path)?)?;
+    let mut script = String::from("ffconcat version 1.0\n");
+    let mut last: Option<String> = None;
+    for (i, frame) in frames.iter().enumerate() {
+        // Frame names come from the API, so they are only used to look up the archive entry.
+        // The extracted frames are named by index, keeping them within the temporary directory.
+        let ext = Path::new(&frame.file)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .filter(|ext| ext.chars().all(|c| c.is_ascii_alphanumeric()))
+            .unwrap_or("jpg");
+        let name = format!("{:06}.{}", i, ext);
+        std::io::copy(
+            &mut archive.by_name(&frame.file)?,
+            &mut std::fs::File::create(tmp_dir.path().join(&name))?,
+        )?;
+        script.push_str(&format!(
+            "file {}\nduration {}\n",
+            name,
+            frame.delay as f64 / 1000.0
+        ));
+        last = Some(name);
+    }
+    // The concat demuxer ignores the duration of the last entry
+    if let Some(ref last) = last {
+        script.push_str(&format!("file {}\n", last));
+    }
+    let script_path = tmp_dir.path().join("frames.ffconcat");
+    std::fs::write(&script_path, script)?;
+
+    let mut cmd = std::process::Command::new("ffmpeg");
+    cmd.args([
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-y",
+        "-f",
+        "concat",
+        "-i",
+    ])
+    .arg(&script_path);
+    match format {
+        UgoiraFormat::Webp => cmd.args([
+            "-c:v",
+            "libwebp",
+            "-lossless",
+            "1",
+            "-loop",
+            "0",
+            "-f",
+            "webp",
+        ]),
+        UgoiraFormat::Mp4 => cmd.args([
+            "-c:v",
+            "libx264",
+            "-pix_fmt",
+            "yuv420p",
+            // yuv420p requires even dimensions
+            "-vf",
+            "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+            "-fps_mode",
+            "vfr",
+            "-movflags",
+            "+faststart",
+            "-f",
+            "mp4",
+        ]),
+        _ => unreachable!(),
+    };
+    cmd.arg(output);
+
+    let status = cmd
+        .status()
+        .map_err(|e| anyhow::anyhow!("Failed to run ffmpeg: {}", e))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("ffmpeg exited with {}", status));
+    }
+    Ok(())
+}
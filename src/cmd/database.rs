@@ -1,8 +1,14 @@
 use std::path::{Path, PathBuf};
 
 use clap::{Args, Subcommand};
+use futures::StreamExt;
 
-use crate::{cmd::fanbox, config::Session, util::DatabasePathFormat};
+use crate::{
+    cmd::fanbox,
+    config::Session,
+    data::pixiv::{IllustState, ImageVariant, Page},
+    util::{DatabasePathFormat, hash_file, resolve_pixiv_image_path},
+};
 
 #[derive(Args)]
 pub struct Database {
@@ -21,6 +27,14 @@ impl FileArgs {
         match self.cmd {
             FileCmd::Fsck(ref args) => args.run(session, db).await?,
             FileCmd::Canonicalize(ref args) => args.run(session, db).await?,
+            FileCmd::Verify(ref args) => args.run(session, db).await?,
+            FileCmd::ChecksumIndex(ref args) => args.run(session, db).await?,
+            FileCmd::MvBase(ref args) => args.run(session, db).await?,
+            FileCmd::Link(ref args) => args.run(session, db).await?,
+            FileCmd::Prune(ref args) => args.run(session, db).await?,
+            FileCmd::CleanTmp(ref args) => args.run(session)?,
+            FileCmd::Dedup(ref args) => args.run(session, db).await?,
+            FileCmd::Thumbnails(ref args) => args.run(session, db).await?,
         }
         Ok(())
     }
@@ -33,6 +47,114 @@ pub enum DatabaseCmd {
 
     /// File management
     File(FileArgs),
+
+    /// Re-probe dimensions of downloaded pixiv images, compare against the stored and API-reported ones, and mark mismatched illustrations as corrupted
+    ReconcileDimensions(ReconcileDimensionsArgs),
+
+    /// Compact the database file to reclaim unused space, or write a compacted copy
+    Vacuum(VacuumArgs),
+
+    /// Export illustrations and fanbox posts to CSV or JSON Lines files
+    Export(ExportArgs),
+
+    /// Import illustrations from a JSON Lines file written by `export -f jsonl`, without contacting pixiv
+    Import(ImportArgs),
+
+    /// Manually set the state of a stored illustration, e.g. to record it as masked without fetching it
+    SetState(SetStateArgs),
+}
+
+#[derive(Args)]
+pub struct SetStateArgs {
+    /// Illustration ID
+    id: u64,
+
+    /// New state
+    ///
+    /// `normal` is only accepted if the illustration's data was synced before, since it can't be restored without fetching.
+    #[arg(value_enum)]
+    state: IllustState,
+}
+
+impl SetStateArgs {
+    pub async fn run(&self, db: &crate::db::Database) -> anyhow::Result<()> {
+        let Some((orig, has_data)) = db.query_illust_state(self.id).await? else {
+            return Err(anyhow::anyhow!("Illustration {} not found", self.id));
+        };
+        if orig == self.state {
+            tracing::info!("Illustration {} is already {:?}", self.id, orig);
+            return Ok(());
+        }
+        if let IllustState::Normal = self.state
+            && !has_data
+        {
+            return Err(anyhow::anyhow!(
+                "Illustration {} has no stored data, sync it by ID to restore it to Normal",
+                self.id
+            ));
+        }
+        db.set_illust_state(self.id, self.state).await?;
+        tracing::info!("Illustration {}: {:?} -> {:?}", self.id, orig, self.state);
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct ImportArgs {
+    /// JSON Lines file of illustrations, or `-` for stdin
+    input: String,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum ExportFormat {
+    /// Comma-separated values with a header row. Tag lists are written as JSON arrays.
+    Csv,
+
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl ExportFormat {
+    fn ext(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Directory to write `illusts.<ext>` and `fanbox_posts.<ext>` into. Existing files are overwritten.
+    #[arg(short, long, default_value = ".")]
+    output_dir: PathBuf,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = ExportFormat::Csv)]
+    format: ExportFormat,
+
+    /// Don't export pixiv illustrations
+    #[arg(long)]
+    skip_illusts: bool,
+
+    /// Don't export fanbox posts
+    #[arg(long)]
+    skip_fanbox: bool,
+}
+
+#[derive(Args)]
+pub struct VacuumArgs {
+    /// Write a compacted copy to this path instead, leaving the database itself untouched. Must not exist yet.
+    #[arg(long)]
+    into: Option<PathBuf>,
+
+    /// Run `ANALYZE` first to refresh query planner statistics
+    #[arg(long)]
+    analyze: bool,
+
+    /// Run `PRAGMA optimize` first
+    #[arg(long)]
+    optimize: bool,
 }
 
 #[derive(Subcommand)]
@@ -42,6 +164,30 @@ pub enum FileCmd {
 
     /// Canonicalize downloaded paths
     Canonicalize(FileCanonicalizeArgs),
+
+    /// Decode downloaded pixiv images to check their integrity, and mark broken ones as corrupted
+    Verify(FileVerifyArgs),
+
+    /// Compute and store content hashes of downloaded files that don't have one yet
+    ChecksumIndex(FileChecksumIndexArgs),
+
+    /// Move a whole base directory, and rewrite absolute paths in the database
+    MvBase(FileMvBaseArgs),
+
+    /// Create a view of downloaded pixiv images, as a directory tree of symlinks named by a template
+    Link(FileLinkArgs),
+
+    /// Remove downloaded images of masked (or unlisted) illustrations from the database, and optionally from disk
+    Prune(FilePruneArgs),
+
+    /// Remove temporary files left behind by interrupted downloads
+    CleanTmp(FileCleanTmpArgs),
+
+    /// Replace downloaded files with identical content by hardlinks to a single copy
+    Dedup(FileDedupArgs),
+
+    /// Generate JPEG thumbnails of downloaded pixiv images under `<pixiv_base_dir>/.thumbs`
+    Thumbnails(FileThumbnailsArgs),
 }
 
 #[derive(Args)]
@@ -57,12 +203,20 @@ pub struct FileFsckArgs {
     /// Don't check fanbox files
     #[arg(long)]
     skip_fanbox_files: bool,
+
+    /// Also check the content of existing files: decode images and compare their dimensions, and compare sizes of fanbox files
+    #[arg(long)]
+    full: bool,
+
+    /// Print each discrepancy as a JSON object per line to stdout
+    #[arg(long)]
+    json: bool,
 }
 
 #[derive(Args)]
 pub struct FileCanonicalizeArgs {
     /// Resulting path format
-    #[arg(short, long, value_enum, default_value_t = DatabasePathFormat::Absolute)]
+    #[arg(short = 'F', long, value_enum, default_value_t = DatabasePathFormat::Absolute)]
     format: DatabasePathFormat,
 
     /// Don't check pixiv images
@@ -104,84 +258,556 @@ pub struct FileCanonicalizeArgs {
     /// Override old base directory for fanbox files
     #[arg(long)]
     fanbox_base_dir_old: Option<PathBuf>,
+
+    /// Override old directory for pixiv thumbnails
+    #[arg(long)]
+    thumb_dir_old: Option<PathBuf>,
+
+    /// Move fanbox images and files into per-creator subdirectories, same as `fanbox download --by-creator`
+    #[arg(long)]
+    fanbox_by_creator: bool,
+
+    /// When moving across devices, hash each file and its copy, and only remove the original if they match
+    ///
+    /// On a mismatch, the copy is removed, the original is kept, and canonicalization aborts.
+    /// Slow, as every copied file is read twice. Meant for large one-shot reorganizations.
+    #[arg(long)]
+    verify_no_data_loss: bool,
+}
+
+#[derive(Args)]
+pub struct FileVerifyArgs {
+    /// Re-download pages of corrupted illustrations, overwriting the broken files
+    #[arg(long)]
+    redownload: bool,
+
+    /// Path format of re-downloaded pages in the database, same as `download --database-path-format`
+    #[arg(long, value_enum, default_value_t = DatabasePathFormat::Absolute)]
+    database_path_format: DatabasePathFormat,
+
+    /// Save re-downloaded pages with this template, same as `download --output-template`
+    #[arg(long, value_name = "TEMPLATE", requires = "redownload")]
+    output_template: Option<String>,
+
+    /// Show progress bar
+    #[arg(short, long)]
+    progress: bool,
+}
+
+#[derive(Args)]
+pub struct FilePruneArgs {
+    /// Prune images of illustrations in this state
+    #[arg(long, value_enum, default_value_t = IllustState::Masked)]
+    state: IllustState,
+
+    /// Also delete the files from disk
+    ///
+    /// A file is only deleted if it matches the database, by its hash or else its dimensions. Mismatched or unverifiable files are kept, together with their rows.
+    #[arg(long)]
+    delete_files: bool,
+
+    /// Only print what would be removed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct FileCleanTmpArgs {
+    /// Only remove temporary files not modified for this long, e.g. `30m` or `1d`
+    ///
+    /// A download in progress keeps writing to its temporary file, so files of a concurrently running instance stay recent.
+    #[arg(long, value_parser = crate::util::parse_cli_duration, default_value = "1h")]
+    older_than: std::time::Duration,
+
+    /// Only print the files that would be removed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct FileDedupArgs {
+    /// Only print the duplicates and how much space would be reclaimed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct FileThumbnailsArgs {
+    /// Thumbnails fit into SIZE x SIZE pixels
+    #[arg(short, long, default_value_t = 512)]
+    size: u32,
+
+    /// Number of images to process at once
+    #[arg(short = 'j', long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+
+    /// Show progress bar
+    #[arg(short, long)]
+    progress: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum MvBaseTarget {
+    /// Pixiv base directory, including thumbnails stored inside it
+    Pixiv,
+
+    /// Pixiv thumbnail directory, if set separately
+    PixivThumb,
+
+    /// Fanbox base directory
+    Fanbox,
+}
+
+#[derive(Args)]
+pub struct FileMvBaseArgs {
+    /// Which base directory to move
+    #[arg(value_enum)]
+    target: MvBaseTarget,
+
+    /// New location of the base directory. Must not exist yet, unless `--skip-file` is given.
+    new_dir: PathBuf,
+
+    /// Override the old base directory, defaults to the configured one
+    #[arg(long)]
+    old_dir: Option<PathBuf>,
+
+    /// Skip updating db
+    #[arg(long)]
+    skip_db: bool,
+
+    /// Skip moving the directory, e.g. if it's already moved by other means
+    #[arg(long)]
+    skip_file: bool,
+
+    /// Perform a dry run
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct FileLinkArgs {
+    /// Directory to create the view in
+    view_dir: PathBuf,
+
+    /// Path of each link, relative to the view directory
+    ///
+    /// Placeholders: `{illust_id}`, `{page}`, `{ext}`, `{filename}`, `{title}`, `{author_id}`, `{author_name}`,
+    /// `{author_account}`, `{first_tag}` and `{first_bookmark_tag}`. Values are sanitized, and missing ones become `_`.
+    #[arg(
+        short,
+        long,
+        default_value = "{author_account}/{illust_id}_p{page}.{ext}"
+    )]
+    template: String,
+
+    /// Replace existing symlinks pointing elsewhere. Other existing files are never touched.
+    #[arg(long)]
+    overwrite: bool,
+
+    /// Perform a dry run
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct FileChecksumIndexArgs {
+    /// Don't hash pixiv images
+    #[arg(long)]
+    skip_pixiv: bool,
+
+    /// Don't hash fanbox images
+    #[arg(long)]
+    skip_fanbox_images: bool,
+
+    /// Don't hash fanbox files
+    #[arg(long)]
+    skip_fanbox_files: bool,
+
+    /// Number of files to hash at once
+    #[arg(short = 'j', long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+
+    /// Show progress bar
+    #[arg(short, long)]
+    progress: bool,
+}
+
+#[derive(Args)]
+pub struct ReconcileDimensionsArgs {
+    /// Only compare against stored dimensions, without fetching pages from the API
+    #[arg(long)]
+    offline: bool,
+
+    /// Show progress bar
+    #[arg(short, long)]
+    progress: bool,
+}
+
+impl VacuumArgs {
+    pub async fn run(&self, db: &crate::db::Database) -> anyhow::Result<()> {
+        if let Some(into) = &self.into
+            && into.try_exists()?
+        {
+            return Err(anyhow::anyhow!("{} already exists", into.display()));
+        }
+
+        let file_size = |path: &Path| std::fs::metadata(path).map(|m| m.len()).ok();
+        let path = db.file_path();
+        let before = path.as_deref().and_then(file_size);
+        db.vacuum(self.into.as_deref(), self.analyze, self.optimize)
+            .await?;
+        let after = self.into.as_deref().or(path.as_deref()).and_then(file_size);
+
+        match (before, after) {
+            (Some(before), Some(after)) => {
+                tracing::info!("Vacuumed {} bytes into {} bytes", before, after)
+            }
+            _ => tracing::info!("Vacuumed"),
+        }
+        Ok(())
+    }
+}
+
+/// Exported column: name, SQL expression, and whether the value is a JSON array
+type ExportColumn = (&'static str, &'static str, bool);
+
+const ILLUST_EXPORT_COLUMNS: &[ExportColumn] = &[
+    ("id", "il.id", false),
+    ("title", "il.title", false),
+    ("author_id", "il.author_id", false),
+    ("author_name", "a.name", false),
+    ("author_account", "a.account", false),
+    ("illust_state", "il.illust_state", false),
+    ("illust_type", "il.illust_type", false),
+    ("page_count", "il.page_count", false),
+    ("create_date", "il.create_date", false),
+    ("update_date", "il.update_date", false),
+    ("x_restrict", "il.x_restrict", false),
+    ("ai_type", "il.ai_type", false),
+    ("bookmark_id", "il.bookmark_id", false),
+    ("bookmark_private", "il.bookmark_private", false),
+    ("series_id", "il.series_id", false),
+    ("series_order", "il.series_order", false),
+    ("content_desc", "il.content_desc", false),
+    ("content_is_howto", "il.content_is_howto", false),
+    ("content_is_original", "il.content_is_original", false),
+    ("corrupted", "il.corrupted", false),
+    ("last_fetch", "il.last_fetch", false),
+    ("last_successful_fetch", "il.last_successful_fetch", false),
+    (
+        "tags",
+        "(SELECT json_group_array(tag) FROM (SELECT t.tag FROM illust_tags it JOIN tags t ON t.id = it.tag_id WHERE it.illust_id = il.id ORDER BY it.rowid))",
+        true,
+    ),
+    (
+        "bookmark_tags",
+        "(SELECT json_group_array(tag) FROM (SELECT t.tag FROM illust_bookmark_tags it JOIN tags t ON t.id = it.tag_id WHERE it.illust_id = il.id ORDER BY it.rowid))",
+        true,
+    ),
+];
+
+const FANBOX_POST_EXPORT_COLUMNS: &[ExportColumn] = &[
+    ("id", "p.id", false),
+    ("creator_id", "p.creator_id", false),
+    ("title", "p.title", false),
+    ("fee", "p.fee", false),
+    ("has_adult_content", "p.has_adult_content", false),
+    ("published_datetime", "p.published_datetime", false),
+    ("updated_datetime", "p.updated_datetime", false),
+    ("fetched_at", "p.fetched_at", false),
+    ("is_body_rich", "p.is_body_rich", false),
+    ("body", "p.body", false),
+    ("cover_url", "p.cover_url", false),
+    ("cover_path", "p.cover_path", false),
+    (
+        "tags",
+        "(SELECT json_group_array(tag) FROM (SELECT t.tag FROM fanbox_post_tags pt JOIN tags t ON t.id = pt.tag_id WHERE pt.post_id = p.id ORDER BY pt.rowid))",
+        true,
+    ),
+];
+
+impl ExportArgs {
+    pub async fn run(&self, db: &crate::db::Database) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.output_dir)?;
+        if !self.skip_illusts {
+            self.export(
+                db,
+                "illusts",
+                ILLUST_EXPORT_COLUMNS,
+                "illusts il LEFT JOIN authors a ON a.id = il.author_id ORDER BY il.id",
+            )
+            .await?;
+        }
+        if !self.skip_fanbox {
+            self.export(
+                db,
+                "fanbox_posts",
+                FANBOX_POST_EXPORT_COLUMNS,
+                "fanbox_posts p ORDER BY p.id",
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn export(
+        &self,
+        db: &crate::db::Database,
+        name: &str,
+        columns: &[ExportColumn],
+        from: &str,
+    ) -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let path = self
+            .output_dir
+            .join(format!("{}.{}", name, self.format.ext()));
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        if let ExportFormat::Csv = self.format {
+            let header: Vec<_> = columns.iter().map(|(name, _, _)| csv_field(name)).collect();
+            writeln!(out, "{}", header.join(","))?;
+        }
+
+        let exprs: Vec<_> = columns.iter().map(|(_, expr, _)| *expr).collect();
+        let sql = format!("SELECT {} FROM {}", exprs.join(", "), from);
+        let mut rows = std::pin::pin!(db.query_raw_stream(&sql));
+        let mut cnt = 0usize;
+        while let Some(row) = rows.next().await {
+            let row = row?;
+            match self.format {
+                ExportFormat::Csv => {
+                    let mut fields = Vec::with_capacity(columns.len());
+                    for i in 0..columns.len() {
+                        fields.push(match crate::util::db_value_to_json(&row, i)? {
+                            serde_json::Value::Null => String::new(),
+                            serde_json::Value::String(s) => csv_field(&s),
+                            val => val.to_string(),
+                        });
+                    }
+                    writeln!(out, "{}", fields.join(","))?;
+                }
+                ExportFormat::Jsonl => {
+                    let mut obj = serde_json::Map::new();
+                    for (i, (name, _, is_json)) in columns.iter().enumerate() {
+                        let mut val = crate::util::db_value_to_json(&row, i)?;
+                        if *is_json && let serde_json::Value::String(s) = &val {
+                            val = serde_json::from_str(s)?;
+                        }
+                        obj.insert(name.to_string(), val);
+                    }
+                    writeln!(out, "{}", serde_json::Value::Object(obj))?;
+                }
+            }
+            cnt += 1;
+        }
+        out.flush()?;
+
+        tracing::info!("Exported {} rows into {}", cnt, path.display());
+        Ok(())
+    }
+}
+
+impl ImportArgs {
+    pub async fn run(&self, db: &crate::db::Database) -> anyhow::Result<()> {
+        use std::io::BufRead;
+
+        let reader: Box<dyn std::io::Read> = if self.input == "-" {
+            Box::new(std::io::stdin())
+        } else {
+            Box::new(std::fs::File::open(&self.input)?)
+        };
+
+        let mut tag_map_ctx = std::collections::HashMap::new();
+        let (mut inserted, mut updated, mut unchanged, mut skipped, mut invalid) =
+            (0usize, 0usize, 0usize, 0usize, 0usize);
+        for (lineno, line) in std::io::BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let illust = serde_json::from_str::<crate::data::pixiv::ExportedIllust>(&line)
+                .map_err(anyhow::Error::from)
+                .and_then(crate::data::pixiv::Illust::try_from);
+            let illust = match illust {
+                Ok(illust) => illust,
+                Err(e) => {
+                    tracing::error!("Invalid illust at line {}: {}", lineno + 1, e);
+                    invalid += 1;
+                    continue;
+                }
+            };
+
+            let update_result = db.update_illust(&illust, &mut tag_map_ctx).await?;
+            match update_result {
+                crate::db::IllustUpdateResult::Inserted => inserted += 1,
+                crate::db::IllustUpdateResult::BookmarkIDChanged
+                | crate::db::IllustUpdateResult::Updated => updated += 1,
+                crate::db::IllustUpdateResult::Unchanged => unchanged += 1,
+                crate::db::IllustUpdateResult::Skipped => skipped += 1,
+            }
+        }
+
+        tracing::info!(
+            "Imported illusts: {} inserted, {} updated, {} unchanged, {} skipped",
+            inserted,
+            updated,
+            unchanged,
+            skipped
+        );
+        if invalid > 0 {
+            return Err(anyhow::anyhow!("{} invalid lines", invalid));
+        }
+        Ok(())
+    }
+}
+
+/// Quote a CSV field if needed, as per RFC 4180
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// A discrepancy between the database and the filesystem
+enum FsckProblem {
+    Missing,
+
+    /// The file exists, but doesn't match what's recorded in the database
+    Mismatch(String),
 }
 
 impl FileFsckArgs {
     pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
-        let mut failed = 0usize;
+        let (mut missing, mut mismatched) = (0usize, 0usize);
+        let mut report = |kind: &str, id: String, path: &Path, problem: FsckProblem| {
+            let (name, detail) = match problem {
+                FsckProblem::Missing => {
+                    missing += 1;
+                    tracing::error!("Missing {} {} ({})", kind, path.display(), id);
+                    ("missing", None)
+                }
+                FsckProblem::Mismatch(detail) => {
+                    mismatched += 1;
+                    tracing::error!(
+                        "Mismatched {} {} ({}): {}",
+                        kind,
+                        path.display(),
+                        id,
+                        detail
+                    );
+                    ("mismatch", Some(detail))
+                }
+            };
+            if self.json {
+                let line = serde_json::json!({
+                    "kind": kind,
+                    "id": id,
+                    "path": path,
+                    "problem": name,
+                    "detail": detail,
+                });
+                println!("{}", line);
+            }
+        };
+
         if !self.skip_pixiv {
-            let entries = db.query_image_paths().await?;
+            let entries = db.query_image_dimensions().await?;
             for ent in entries {
-                if let Some(p) = ent.path
-                    && !Self::check(&p, session.get_pixiv_base_dir()).await?
-                {
-                    failed += 1;
-                    tracing::error!("Missing pixiv image {} ({}_p{})", p, ent.id.0, ent.id.1);
+                let path = resolve_pixiv_image_path(session, &ent.path, ent.variant)?;
+                let id = format!("{}_p{}, {:?}", ent.illust_id, ent.page, ent.variant);
+                if let Some(problem) = self.check(&path, || {
+                    let (width, height) = probe_dim(&path, ent.is_ugoira)?;
+                    // Older rows may not have dimensions recorded, in which case decoding is all we can check
+                    if ent.width.is_some_and(|w| w != width)
+                        || ent.height.is_some_and(|h| h != height)
+                    {
+                        return Ok(Some(format!(
+                            "probed {}x{}, stored {}x{}",
+                            width,
+                            height,
+                            ent.width.unwrap_or_default(),
+                            ent.height.unwrap_or_default()
+                        )));
+                    }
+                    Ok(None)
+                })? {
+                    report("pixiv image", id, &path, problem);
                 }
             }
         }
 
         if !self.skip_fanbox_images {
-            let entries = db.query_fanbox_image_paths().await?;
+            let entries = db.query_fanbox_image_dimensions().await?;
             for ent in entries {
-                if let Some(p) = ent.path
-                    && !Self::check(&p, session.get_fanbox_base_dir()).await?
-                {
-                    failed += 1;
-                    tracing::error!(
-                        "Missing fanbox image {} ({}_{}_{})",
-                        p,
-                        ent.id.1,
-                        ent.id.2,
-                        ent.id.0
-                    );
+                let Some(path) = ent.path else { continue };
+                let path = resolve_fanbox_path(session, &path)?;
+                let id = format!("{}_{}_{}", ent.post_id, ent.idx, ent.id);
+                if let Some(problem) = self.check(&path, || {
+                    let file = std::io::BufReader::new(std::fs::File::open(&path)?);
+                    let (width, height) = crate::util::get_image_dim(file, &path, None)?;
+                    if (width as i64, height as i64) != (ent.width, ent.height) {
+                        return Ok(Some(format!(
+                            "probed {}x{}, stored {}x{}",
+                            width, height, ent.width, ent.height
+                        )));
+                    }
+                    Ok(None)
+                })? {
+                    report("fanbox image", id, &path, problem);
                 }
             }
         }
 
         if !self.skip_fanbox_files {
-            let entries = db.query_fanbox_file_paths().await?;
+            let entries = db.query_fanbox_file_sizes().await?;
             for ent in entries {
-                if let Some(p) = ent.path
-                    && !Self::check(&p, session.get_fanbox_base_dir()).await?
-                {
-                    failed += 1;
-                    tracing::error!(
-                        "Missing fanbox file {} ({}_{}_{})",
-                        p,
-                        ent.id.1,
-                        ent.id.2,
-                        ent.id.0
-                    );
+                let Some(path) = ent.path else { continue };
+                let path = resolve_fanbox_path(session, &path)?;
+                let id = format!("{}_{}_{}", ent.post_id, ent.idx, ent.id);
+                if let Some(problem) = self.check(&path, || {
+                    let size = std::fs::metadata(&path)?.len();
+                    if size as i64 != ent.size {
+                        return Ok(Some(format!("size {}, stored {}", size, ent.size)));
+                    }
+                    Ok(None)
+                })? {
+                    report("fanbox file", id, &path, problem);
                 }
             }
         }
 
-        if failed > 0 {
-            Err(anyhow::anyhow!("{} files missing", failed))
-        } else {
-            Ok(())
+        match (missing, mismatched) {
+            (0, 0) => Ok(()),
+            (_, 0) => Err(anyhow::anyhow!("{} files missing", missing)),
+            _ => Err(anyhow::anyhow!(
+                "{} files missing, {} files mismatched",
+                missing,
+                mismatched
+            )),
         }
     }
 
-    async fn check(path: &str, base_dir: anyhow::Result<&PathBuf>) -> anyhow::Result<bool> {
-        // Path may be absolute or relative
-        let full_path = if std::path::Path::new(path).is_absolute() {
-            std::path::PathBuf::from(path)
-        } else if let Ok(base_dir) = base_dir {
-            let mut p = base_dir.clone();
-            p.push(path);
-            p
-        } else {
-            return Err(anyhow::anyhow!(
-                "Relative path {} requires specified base dir: {}",
-                path,
-                base_dir.unwrap_err()
-            ));
-        };
-        tracing::debug!("Checking path {}", full_path.display());
-
-        Ok(full_path.try_exists()?)
+    /// Check that the file exists, and with `--full`, that it matches the database according to `verify`
+    ///
+    /// `verify` returns a description of the mismatch, if any. Errors from it are reported as mismatches too.
+    fn check(
+        &self,
+        path: &Path,
+        verify: impl FnOnce() -> anyhow::Result<Option<String>>,
+    ) -> anyhow::Result<Option<FsckProblem>> {
+        tracing::debug!("Checking path {}", path.display());
+        if !path.try_exists()? {
+            return Ok(Some(FsckProblem::Missing));
+        }
+        if !self.full {
+            return Ok(None);
+        }
+        Ok(match tokio::task::block_in_place(verify) {
+            Ok(mismatch) => mismatch.map(FsckProblem::Mismatch),
+            Err(e) => Some(FsckProblem::Mismatch(format!("failed to read: {}", e))),
+        })
     }
 }
 
@@ -191,21 +817,24 @@ impl FileCanonicalizeArgs {
             let entries = db.query_image_paths().await?;
             let base_dir = session.get_pixiv_base_dir()?;
             let base_dir_old = self.base_dir_old.as_ref().unwrap_or(base_dir);
+            let thumb_dir = session.get_pixiv_thumb_dir()?;
+            let thumb_dir_old = self.thumb_dir_old.as_ref().unwrap_or(&thumb_dir);
             for ent in entries {
+                let (base_dir_old, base_dir) = if ent.id.2.is_thumbnail() {
+                    (thumb_dir_old, &thumb_dir)
+                } else {
+                    (base_dir_old, base_dir)
+                };
                 if let Some(cur) = ent.path {
                     // Use original filename for images
                     // This also handles modified filenames (e.g. hash suffixes for older versions)
-                    let filename = cur.split('/').last().unwrap();
-                    let written_path = self.adjust(&cur, base_dir_old, &filename, base_dir).await?;
+                    let filename = cur.split('/').next_back().unwrap();
+                    let written_path = self.adjust(&cur, base_dir_old, filename, base_dir).await?;
                     let new_path_str = &written_path
                         .to_str()
                         .ok_or_else(|| anyhow::anyhow!("Failed to convert path"))?;
                     if !self.skip_db && !self.dry_run {
-                        db.update_image_path_move(
-                            &cur,
-                            Some(*new_path_str),
-                        )
-                        .await?;
+                        db.update_image_path_move(&cur, Some(*new_path_str)).await?;
                     }
                 }
             }
@@ -221,6 +850,8 @@ impl FileCanonicalizeArgs {
                         db,
                         fanbox::FanboxAttachmentType::Image,
                         &ent.id.0,
+                        self.fanbox_by_creator,
+                        None,
                     )
                     .await?
                     .1;
@@ -228,7 +859,7 @@ impl FileCanonicalizeArgs {
                     if !self.skip_db && !self.dry_run {
                         db.update_fanbox_image_path(
                             &ent.id.0,
-                            &written_path
+                            written_path
                                 .to_str()
                                 .ok_or_else(|| anyhow::anyhow!("Failed to convert path"))?,
                         )
@@ -248,6 +879,8 @@ impl FileCanonicalizeArgs {
                         db,
                         fanbox::FanboxAttachmentType::File,
                         &ent.id.0,
+                        self.fanbox_by_creator,
+                        None,
                     )
                     .await?
                     .1;
@@ -255,7 +888,7 @@ impl FileCanonicalizeArgs {
                     if !self.skip_db && !self.dry_run {
                         db.update_fanbox_file_path(
                             &ent.id.0,
-                            &written_path
+                            written_path
                                 .to_str()
                                 .ok_or_else(|| anyhow::anyhow!("Failed to convert path"))?,
                         )
@@ -271,11 +904,11 @@ impl FileCanonicalizeArgs {
     async fn adjust(
         &self,
         cur: &str,
-        base_dir_old: &PathBuf,
+        base_dir_old: &Path,
         filename: &str,
-        base_dir: &PathBuf,
+        base_dir: &Path,
     ) -> anyhow::Result<PathBuf> {
-        let mut target_path = base_dir.clone();
+        let mut target_path = base_dir.to_path_buf();
         target_path.push(filename);
         // We use absolute here because the target file does not exist yet
         let target_path_full = std::path::absolute(target_path.as_path())?;
@@ -284,7 +917,7 @@ impl FileCanonicalizeArgs {
         let cur_full_path = if cur_path.is_absolute() {
             std::path::PathBuf::from(cur)
         } else {
-            let mut p = base_dir_old.clone();
+            let mut p = base_dir_old.to_path_buf();
             p.push(cur);
             p
         }
@@ -302,7 +935,7 @@ impl FileCanonicalizeArgs {
                 cur_resolved_path.display(),
                 target_path.display()
             );
-            if !self.skip_file && !cur_full_path.is_ok() {
+            if !self.skip_file && cur_full_path.is_err() {
                 return Err(anyhow::anyhow!(
                     "{} -> {}: Source file does not exist",
                     cur_resolved_path.display(),
@@ -327,7 +960,11 @@ impl FileCanonicalizeArgs {
                     if target_exists {
                         tracing::warn!("Overwriting existing file {}", target_path.display());
                     }
-                    Self::mv(&cur_full_path.unwrap(), &target_path).await?;
+                    if let Some(parent) = target_path.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    let cur_full_path = cur_full_path.unwrap();
+                    Self::mv(&cur_full_path, &target_path, self.verify_no_data_loss).await?;
                 } else if let Ok(cur_full_path) = cur_full_path
                     && cur_full_path.exists()
                 {
@@ -348,9 +985,12 @@ impl FileCanonicalizeArgs {
         Ok(written_path)
     }
 
-    async fn mv(from: impl AsRef<Path>, to: impl AsRef<Path>) -> anyhow::Result<()> {
+    /// Move a file, falling back to copy + remove across devices
+    ///
+    /// With `verify`, the source and the copy are hashed before the source is removed. On a mismatch, the copy is removed instead and the source is kept.
+    /// A rename doesn't touch the content, so it's not checked.
+    async fn mv(from: impl AsRef<Path>, to: impl AsRef<Path>, verify: bool) -> anyhow::Result<()> {
         // First, try normal rename
-        // FIXME: preserve mtime
         let result = tokio::fs::rename(from.as_ref(), to.as_ref()).await;
         if result.is_ok() {
             return Ok(());
@@ -363,25 +1003,1000 @@ impl FileCanonicalizeArgs {
         }
 
         // Do copy + remove
-        tokio::fs::copy(from.as_ref(), to.as_ref()).await?;
+        let src_digest = if verify {
+            Some(tokio::task::block_in_place(|| hash_file(from.as_ref()))?)
+        } else {
+            None
+        };
+        let (from_buf, to_buf) = (from.as_ref().to_path_buf(), to.as_ref().to_path_buf());
+        tokio::task::spawn_blocking(move || copy_file_with_times(&from_buf, &to_buf)).await??;
+        if let Some(src_digest) = src_digest {
+            let dst_digest = tokio::task::block_in_place(|| hash_file(to.as_ref()))?;
+            if src_digest != dst_digest {
+                tokio::fs::remove_file(to.as_ref()).await?;
+                return Err(anyhow::anyhow!(
+                    "{} -> {}: Content changed while copying (sha256 {} -> {}), kept the source and aborting",
+                    from.as_ref().display(),
+                    to.as_ref().display(),
+                    hex::encode(src_digest),
+                    hex::encode(dst_digest)
+                ));
+            }
+        }
         tokio::fs::remove_file(from.as_ref()).await?;
         Ok(())
     }
 }
 
-impl Database {
-    pub async fn run(self, session: &Session, dburl: &str) -> anyhow::Result<()> {
-        match self.cmd {
-            DatabaseCmd::Setup => self.setup(dburl).await,
-            DatabaseCmd::File(file) => {
-                let db = crate::db::Database::load(dburl).await?;
-                file.run(session, &db).await
-            }
-        }
-    }
+impl FileVerifyArgs {
+    pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
+        let entries = db.query_image_dimensions().await?;
+        let bar = progress_bar(self.progress, entries.len());
 
-    pub async fn setup(self, dburl: &str) -> anyhow::Result<()> {
-        crate::db::Database::setup(dburl).await?;
-        Ok(())
+        // Corrupted illusts, grouped by the variant to re-download
+        let mut corrupted: Vec<(ImageVariant, Vec<u64>)> = Vec::new();
+        for ent in entries {
+            bar.inc(1);
+
+            let path = resolve_pixiv_image_path(session, &ent.path, ent.variant)?;
+            let problem = match tokio::task::block_in_place(|| probe_dim(&path, ent.is_ugoira)) {
+                Err(e) => Some(format!("failed to decode: {}", e)),
+                // Older rows may not have dimensions recorded, in which case decoding is all we can check
+                Ok((width, height))
+                    if ent.width.is_some_and(|w| w != width)
+                        || ent.height.is_some_and(|h| h != height) =>
+                {
+                    Some(format!(
+                        "probed {}x{}, stored {}x{}",
+                        width,
+                        height,
+                        ent.width.unwrap_or_default(),
+                        ent.height.unwrap_or_default()
+                    ))
+                }
+                Ok(_) => None,
+            };
+
+            if let Some(problem) = problem {
+                bar.suspend(|| {
+                    tracing::error!(
+                        "Corrupted pixiv image {} ({}_p{}): {}",
+                        path.display(),
+                        ent.illust_id,
+                        ent.page,
+                        problem
+                    )
+                });
+                db.set_illust_corrupted(ent.illust_id as u64, true).await?;
+
+                let ids = match corrupted.iter_mut().find(|(v, _)| *v == ent.variant) {
+                    Some((_, ids)) => ids,
+                    None => {
+                        corrupted.push((ent.variant, Vec::new()));
+                        &mut corrupted.last_mut().unwrap().1
+                    }
+                };
+                // Entries are ordered by illust
+                if ids.last() != Some(&(ent.illust_id as u64)) {
+                    ids.push(ent.illust_id as u64);
+                }
+            }
+        }
+        bar.finish();
+
+        let cnt: usize = corrupted.iter().map(|(_, ids)| ids.len()).sum();
+        if cnt == 0 {
+            return Ok(());
+        }
+        if !self.redownload {
+            return Err(anyhow::anyhow!("{} illustrations corrupted", cnt));
+        }
+
+        let mut failed = 0usize;
+        for (variant, ids) in corrupted {
+            for id in ids {
+                tracing::info!("Re-downloading corrupted illustration {}", id);
+                let download = crate::cmd::download::Download::for_redownload(
+                    id,
+                    variant,
+                    self.database_path_format,
+                    self.output_template.clone(),
+                )?;
+                match download.run(session, db).await {
+                    Ok(()) => db.set_illust_corrupted(id, false).await?,
+                    Err(e) => {
+                        tracing::error!("Failed to re-download {}: {:?}", id, e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+
+        if failed > 0 {
+            Err(anyhow::anyhow!(
+                "{} of {} corrupted illustrations failed to re-download",
+                failed,
+                cnt
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl FileMvBaseArgs {
+    pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
+        let old_dir = match self.old_dir {
+            Some(ref dir) => dir.clone(),
+            None => match self.target {
+                MvBaseTarget::Pixiv => session.get_pixiv_base_dir()?.clone(),
+                MvBaseTarget::PixivThumb => session.get_pixiv_thumb_dir()?,
+                MvBaseTarget::Fanbox => session.get_fanbox_base_dir()?.clone(),
+            },
+        };
+        // Stored absolute paths are canonicalized, but the old directory is already gone if moved by other means
+        let old_dir = old_dir
+            .canonicalize()
+            .or_else(|_| std::path::absolute(&old_dir))?;
+        let new_dir = std::path::absolute(&self.new_dir)?;
+
+        if !self.skip_file {
+            if !old_dir.is_dir() {
+                return Err(anyhow::anyhow!("{} is not a directory", old_dir.display()));
+            }
+            if new_dir.try_exists()? {
+                return Err(anyhow::anyhow!("{} already exists", new_dir.display()));
+            }
+        } else if !new_dir.is_dir() {
+            return Err(anyhow::anyhow!(
+                "{} is not a directory in skip file mode",
+                new_dir.display()
+            ));
+        }
+
+        tracing::info!("{} -> {}", old_dir.display(), new_dir.display());
+        if !self.skip_file && !self.dry_run {
+            if let Some(parent) = new_dir.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            Self::mv(&old_dir, &new_dir).await?;
+        }
+        let new_dir = new_dir.canonicalize().unwrap_or(new_dir);
+
+        if !self.skip_db {
+            let old_str = old_dir
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("Failed to convert path"))?;
+            let new_str = new_dir
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("Failed to convert path"))?;
+            // Relative paths are relative to the base directory, and stay valid as-is. So do generated thumbnails, which are always relative to the pixiv base directory and move along with it
+            let rewritten = match self.target {
+                MvBaseTarget::Pixiv | MvBaseTarget::PixivThumb => {
+                    db.rewrite_image_path_prefix(old_str, new_str, self.dry_run)
+                        .await?
+                }
+                MvBaseTarget::Fanbox => {
+                    db.rewrite_fanbox_path_prefix(old_str, new_str, self.dry_run)
+                        .await?
+                }
+            };
+            if self.dry_run {
+                tracing::info!("Would rewrite {} absolute paths", rewritten);
+            } else {
+                tracing::info!("Rewrote {} absolute paths", rewritten);
+            }
+        }
+
+        if !self.dry_run {
+            tracing::info!(
+                "Remember to point the base directory setting to {}",
+                new_dir.display()
+            );
+        }
+        Ok(())
+    }
+
+    async fn mv(from: &Path, to: &Path) -> anyhow::Result<()> {
+        let result = tokio::fs::rename(from, to).await;
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() != std::io::ErrorKind::CrossesDevices => return Err(e.into()),
+            Err(_) => {}
+        }
+
+        // Do copy + remove
+        tracing::info!("Moving across devices, copying instead");
+        let (from, to) = (from.to_path_buf(), to.to_path_buf());
+        tokio::task::spawn_blocking(move || {
+            copy_dir_all(&from, &to)?;
+            std::fs::remove_dir_all(&from)
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+fn copy_dir_all(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            copy_file_with_times(&entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy a file, then carry over its modification and access times, which `std::fs::copy` doesn't
+fn copy_file_with_times(from: &Path, to: &Path) -> std::io::Result<()> {
+    let meta = std::fs::metadata(from)?;
+    std::fs::copy(from, to)?;
+    let times = std::fs::FileTimes::new()
+        .set_modified(meta.modified()?)
+        .set_accessed(meta.accessed()?);
+    std::fs::File::options()
+        .write(true)
+        .open(to)?
+        .set_times(times)
+}
+
+impl FileLinkArgs {
+    pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
+        let entries = db.query_image_link_entries().await?;
+        let mut seen = std::collections::HashSet::new();
+        let (mut created, mut unchanged, mut skipped) = (0usize, 0usize, 0usize);
+        for ent in entries {
+            let target = resolve_pixiv_image_path(session, &ent.path, ImageVariant::Original)?;
+            let target = std::path::absolute(&target)?;
+            let filename = target
+                .file_name()
+                .and_then(|f| f.to_str())
+                .map(str::to_owned);
+            let ext = target
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(str::to_owned);
+            let rel = crate::util::render_path_template(&self.template, |name| {
+                Some(match name {
+                    "illust_id" => Some(ent.illust_id.to_string()),
+                    "page" => Some(ent.page.to_string()),
+                    "ext" => ext.clone(),
+                    "filename" => filename.clone(),
+                    "title" => ent.title.clone(),
+                    "author_id" => ent.author_id.map(|id| id.to_string()),
+                    "author_name" => ent.author_name.clone(),
+                    "author_account" => ent.author_account.clone(),
+                    "first_tag" => ent.first_tag.clone(),
+                    "first_bookmark_tag" => ent.first_bookmark_tag.clone(),
+                    _ => return None,
+                })
+            })?;
+            let link = self.view_dir.join(&rel);
+
+            if !seen.insert(rel) {
+                tracing::warn!(
+                    "{} ({}_p{}) maps to the same link {} as a previous image, skipping",
+                    target.display(),
+                    ent.illust_id,
+                    ent.page,
+                    link.display()
+                );
+                skipped += 1;
+                continue;
+            }
+
+            let existing = match tokio::fs::symlink_metadata(&link).await {
+                Ok(meta) => Some(meta),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(meta) = &existing {
+                if !meta.file_type().is_symlink() {
+                    tracing::warn!("{} exists and is not a symlink, skipping", link.display());
+                    skipped += 1;
+                    continue;
+                }
+                if tokio::fs::read_link(&link).await? == target {
+                    unchanged += 1;
+                    continue;
+                }
+                if !self.overwrite {
+                    tracing::warn!(
+                        "{} already links elsewhere, skipping. Use --overwrite to replace it",
+                        link.display()
+                    );
+                    skipped += 1;
+                    continue;
+                }
+            }
+
+            tracing::info!("{} -> {}", link.display(), target.display());
+            if self.dry_run {
+                created += 1;
+                continue;
+            }
+            if existing.is_some() {
+                tokio::fs::remove_file(&link).await?;
+            }
+            if let Some(parent) = link.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            symlink_file(&target, &link)?;
+            created += 1;
+        }
+
+        tracing::info!(
+            "{} {} links, {} unchanged, {} skipped",
+            if self.dry_run {
+                "Would create"
+            } else {
+                "Created"
+            },
+            created,
+            unchanged,
+            skipped
+        );
+        Ok(())
+    }
+}
+
+fn symlink_file(original: &Path, link: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(original, link);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(original, link);
+}
+
+#[derive(Clone, Copy)]
+enum HashTarget {
+    PixivImage,
+    FanboxImage,
+    FanboxFile,
+}
+
+impl FileChecksumIndexArgs {
+    pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
+        // (target, path as stored in the database, resolved path)
+        let mut entries: Vec<(HashTarget, String, PathBuf)> = Vec::new();
+        if !self.skip_pixiv {
+            for (path, variant) in db.query_unhashed_image_paths().await? {
+                let full_path = resolve_pixiv_image_path(session, &path, variant)?;
+                entries.push((HashTarget::PixivImage, path, full_path));
+            }
+        }
+        if !self.skip_fanbox_images {
+            for path in db.query_unhashed_fanbox_image_paths().await? {
+                let full_path = resolve_fanbox_path(session, &path)?;
+                entries.push((HashTarget::FanboxImage, path, full_path));
+            }
+        }
+        if !self.skip_fanbox_files {
+            for path in db.query_unhashed_fanbox_file_paths().await? {
+                let full_path = resolve_fanbox_path(session, &path)?;
+                entries.push((HashTarget::FanboxFile, path, full_path));
+            }
+        }
+
+        let bar = progress_bar(self.progress, entries.len());
+
+        // Each hash is stored as soon as it's computed, so an interrupted run picks up where it left off
+        let mut results = futures::stream::iter(entries)
+            .map(|(target, path, full_path)| async move {
+                let hash = tokio::task::spawn_blocking(move || hash_file(&full_path)).await?;
+                Ok::<_, anyhow::Error>((target, path, hash))
+            })
+            .buffer_unordered(self.concurrency as usize);
+
+        let mut hashed = 0usize;
+        let mut failed = 0usize;
+        while let Some(result) = results.next().await {
+            let (target, path, hash) = result?;
+            bar.inc(1);
+            let hash = match hash {
+                Ok(hash) => hex::encode(hash),
+                Err(e) => {
+                    bar.suspend(|| tracing::error!("Failed to hash {}: {}", path, e));
+                    failed += 1;
+                    continue;
+                }
+            };
+            match target {
+                HashTarget::PixivImage => db.update_image_hash(&path, &hash).await?,
+                HashTarget::FanboxImage => db.update_fanbox_image_hash(&path, &hash).await?,
+                HashTarget::FanboxFile => db.update_fanbox_file_hash(&path, &hash).await?,
+            };
+            hashed += 1;
+        }
+        bar.finish();
+
+        tracing::info!("Newly hashed {} files", hashed);
+        if failed > 0 {
+            Err(anyhow::anyhow!("{} files failed to hash", failed))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl ReconcileDimensionsArgs {
+    pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
+        let entries = db.query_image_dimensions().await?;
+        let bar = progress_bar(self.progress, entries.len());
+
+        // Pages of the last fetched illust. None if the fetch failed
+        let mut api_pages: Option<(i64, Option<Vec<Page>>)> = None;
+        let mut failed = 0usize;
+        for ent in entries {
+            bar.inc(1);
+
+            let path = resolve_pixiv_image_path(session, &ent.path, ent.variant)?;
+            let probed = match tokio::task::block_in_place(|| probe_dim(&path, ent.is_ugoira)) {
+                Ok(dim) => dim,
+                Err(e) => {
+                    failed += 1;
+                    bar.suspend(|| {
+                        tracing::error!(
+                            "Failed to probe {}_p{} at {}: {}",
+                            ent.illust_id,
+                            ent.page,
+                            path.display(),
+                            e
+                        )
+                    });
+                    db.set_illust_corrupted(ent.illust_id as u64, true).await?;
+                    continue;
+                }
+            };
+
+            let mut mismatches = Vec::new();
+            if (ent.width, ent.height) != (Some(probed.0), Some(probed.1)) {
+                mismatches.push(format!("stored {}", Self::fmt_dim(ent.width, ent.height)));
+            }
+
+            // API dimensions are of the original page, and ugoira frames may be resized within the zip
+            if !self.offline && !ent.is_ugoira && !ent.variant.is_thumbnail() {
+                if api_pages.as_ref().map(|(id, _)| *id) != Some(ent.illust_id) {
+                    let illust_id = ent.illust_id as u64;
+                    let pages = match crate::retry::with_retries(
+                        crate::retry::RetryPolicy::global(),
+                        format!("pages of illust {}", illust_id),
+                        || crate::data::pixiv::get_illust_pages(session, illust_id),
+                    )
+                    .await
+                    {
+                        Ok(pages) => Some(pages),
+                        Err(e) => {
+                            bar.suspend(|| {
+                                tracing::warn!(
+                                    "Failed to fetch pages of {}, skipping API comparison: {}",
+                                    ent.illust_id,
+                                    e
+                                )
+                            });
+                            None
+                        }
+                    };
+                    api_pages = Some((ent.illust_id, pages));
+                }
+
+                if let Some((_, Some(ref pages))) = api_pages
+                    && let Some(page) = pages.get(ent.page as usize)
+                    && (page.width as i64, page.height as i64) != probed
+                {
+                    mismatches.push(format!("API {}x{}", page.width, page.height));
+                }
+            }
+
+            if !mismatches.is_empty() {
+                failed += 1;
+                bar.suspend(|| {
+                    tracing::error!(
+                        "Corrupted pixiv image {} ({}_p{}): probed {}x{}, {}",
+                        path.display(),
+                        ent.illust_id,
+                        ent.page,
+                        probed.0,
+                        probed.1,
+                        mismatches.join(", ")
+                    )
+                });
+                db.set_illust_corrupted(ent.illust_id as u64, true).await?;
+            }
+        }
+        bar.finish();
+
+        if failed > 0 {
+            Err(anyhow::anyhow!(
+                "{} images mismatched or unreadable",
+                failed
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fmt_dim(width: Option<i64>, height: Option<i64>) -> String {
+        let fmt = |d: Option<i64>| d.map(|d| d.to_string()).unwrap_or("?".to_owned());
+        format!("{}x{}", fmt(width), fmt(height))
+    }
+}
+
+impl FilePruneArgs {
+    pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
+        if matches!(self.state, IllustState::Normal) {
+            return Err(anyhow::anyhow!(
+                "Refusing to prune images of normal illustrations"
+            ));
+        }
+
+        let entries = db.query_images_by_illust_state(self.state).await?;
+        let (mut pruned, mut kept) = (0usize, 0usize);
+        for ent in entries {
+            let path = resolve_pixiv_image_path(session, &ent.path, ent.variant)?;
+            let id = format!("{}_p{}, {:?}", ent.illust_id, ent.page, ent.variant);
+
+            let delete_file = if self.delete_files && path.try_exists()? {
+                match tokio::task::block_in_place(|| Self::verify(&ent, &path)) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        tracing::warn!("Keeping {} ({}): {}", path.display(), id, e);
+                        kept += 1;
+                        continue;
+                    }
+                }
+            } else {
+                false
+            };
+
+            tracing::info!(
+                "{} {} ({}){}",
+                if self.dry_run {
+                    "Would prune"
+                } else {
+                    "Pruning"
+                },
+                path.display(),
+                id,
+                if delete_file { ", deleting file" } else { "" }
+            );
+            pruned += 1;
+            if self.dry_run {
+                continue;
+            }
+            if delete_file {
+                tokio::fs::remove_file(&path).await?;
+                // The generated thumbnail goes with its page, a missing one is fine
+                if let Some(ref thumbnail) = ent.thumbnail_path {
+                    let thumbnail = session.get_pixiv_base_dir()?.join(thumbnail);
+                    match tokio::fs::remove_file(&thumbnail).await {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+            }
+            db.update_image_path_move(&ent.path, None).await?;
+        }
+
+        tracing::info!(
+            "{} {} images, kept {} mismatched files",
+            if self.dry_run {
+                "Would prune"
+            } else {
+                "Pruned"
+            },
+            pruned,
+            kept
+        );
+        Ok(())
+    }
+
+    /// Check that the file on disk is what the database recorded, so an unrelated file is never deleted
+    fn verify(ent: &crate::db::PruneImageEntry, path: &Path) -> anyhow::Result<()> {
+        if let Some(ref hash) = ent.hash {
+            let digest = hex::encode(hash_file(path)?);
+            if digest != *hash {
+                return Err(anyhow::anyhow!("hash {}, stored {}", digest, hash));
+            }
+            return Ok(());
+        }
+
+        let (Some(stored_width), Some(stored_height)) = (ent.width, ent.height) else {
+            return Err(anyhow::anyhow!(
+                "neither hash nor dimensions are stored to verify against, run `database file checksum-index` first"
+            ));
+        };
+        let (width, height) = probe_dim(path, ent.is_ugoira)?;
+        if (width, height) != (stored_width, stored_height) {
+            return Err(anyhow::anyhow!(
+                "probed {}x{}, stored {}x{}",
+                width,
+                height,
+                stored_width,
+                stored_height
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FileDedupArgs {
+    pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
+        // Files of each hash, in the order they were downloaded, so the oldest copy is kept
+        let mut groups: Vec<(String, Vec<PathBuf>)> = Vec::new();
+        let mut group_of = std::collections::HashMap::new();
+        let mut add = |hash: String, path: PathBuf| {
+            let idx = *group_of.entry(hash.clone()).or_insert_with(|| {
+                groups.push((hash, Vec::new()));
+                groups.len() - 1
+            });
+            groups[idx].1.push(path);
+        };
+        for (hash, path, variant) in db.query_hashed_image_paths().await? {
+            add(hash, resolve_pixiv_image_path(session, &path, variant)?);
+        }
+        for (hash, path) in db.query_hashed_fanbox_image_paths().await? {
+            add(hash, resolve_fanbox_path(session, &path)?);
+        }
+        for (hash, path) in db.query_hashed_fanbox_file_paths().await? {
+            add(hash, resolve_fanbox_path(session, &path)?);
+        }
+
+        let (mut linked, mut skipped, mut reclaimed) = (0usize, 0usize, 0u64);
+        for (hash, paths) in groups {
+            if paths.len() < 2 {
+                continue;
+            }
+            let (l, s, r) = tokio::task::block_in_place(|| self.dedup_group(&hash, paths))?;
+            linked += l;
+            skipped += s;
+            reclaimed += r;
+        }
+
+        tracing::info!(
+            "{} {} duplicates, reclaiming {}. Skipped {}",
+            if self.dry_run { "Would link" } else { "Linked" },
+            linked,
+            indicatif::HumanBytes(reclaimed),
+            skipped
+        );
+        Ok(())
+    }
+
+    /// Link the files of a group to the first one that still has the hashed content. Returns (linked, skipped, bytes reclaimed)
+    fn dedup_group(&self, hash: &str, paths: Vec<PathBuf>) -> anyhow::Result<(usize, usize, u64)> {
+        let (mut linked, mut skipped, mut reclaimed) = (0, 0, 0);
+        let mut canonical: Option<(PathBuf, std::fs::Metadata)> = None;
+        for path in paths {
+            let meta = match std::fs::metadata(&path) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    tracing::warn!("Skipping {}: {}", path.display(), e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if let Some((ref canonical_path, ref canonical_meta)) = canonical {
+                if path == *canonical_path || same_file(canonical_meta, &meta) {
+                    continue;
+                }
+                if !same_device(canonical_meta, &meta) {
+                    tracing::warn!(
+                        "Skipping {}: on a different device than {}",
+                        path.display(),
+                        canonical_path.display()
+                    );
+                    skipped += 1;
+                    continue;
+                }
+            }
+
+            // The file may have changed since it was hashed, never replace it then
+            let digest = match hash_file(&path) {
+                Ok(digest) => hex::encode(digest),
+                Err(e) => {
+                    tracing::warn!("Skipping {}: {}", path.display(), e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if digest != hash {
+                tracing::warn!(
+                    "Skipping {}: hash {}, stored {}",
+                    path.display(),
+                    digest,
+                    hash
+                );
+                skipped += 1;
+                continue;
+            }
+
+            let Some((ref canonical_path, _)) = canonical else {
+                canonical = Some((path, meta));
+                continue;
+            };
+            tracing::info!(
+                "{} {} -> {}",
+                if self.dry_run {
+                    "Would link"
+                } else {
+                    "Linking"
+                },
+                path.display(),
+                canonical_path.display()
+            );
+            if !self.dry_run
+                && let Err(e) = replace_with_hardlink(canonical_path, &path)
+            {
+                tracing::warn!("Failed to link {}: {}", path.display(), e);
+                skipped += 1;
+                continue;
+            }
+            linked += 1;
+            reclaimed += meta.len();
+        }
+        Ok((linked, skipped, reclaimed))
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(unix)]
+fn same_device(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev()
+}
+
+// Not detectable on stable Rust, linking fails across volumes instead
+#[cfg(not(unix))]
+fn same_file(_: &std::fs::Metadata, _: &std::fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn same_device(_: &std::fs::Metadata, _: &std::fs::Metadata) -> bool {
+    true
+}
+
+/// Atomically replace `path` by a hardlink to `original`, so it's never missing on failure
+fn replace_with_hardlink(original: &Path, path: &Path) -> std::io::Result<()> {
+    let mut tmp_name = std::ffi::OsString::from(".pixivdwn_dedup_");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    let tmp = path.with_file_name(tmp_name);
+    if tmp.exists() {
+        std::fs::remove_file(&tmp)?;
+    }
+    std::fs::hard_link(original, &tmp)?;
+    std::fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+impl FileThumbnailsArgs {
+    pub async fn run(&self, session: &Session, db: &crate::db::Database) -> anyhow::Result<()> {
+        let base_dir = session.get_pixiv_base_dir()?;
+        let entries = db.query_thumbnail_candidates().await?;
+
+        let bar = progress_bar(self.progress, entries.len());
+
+        let size = self.size;
+        let mut results = futures::stream::iter(entries)
+            .map(|path| async move {
+                let full_path = resolve_pixiv_image_path(session, &path, ImageVariant::Original)?;
+                let Some(thumbnail) = crate::util::thumbnail_rel_path(base_dir, &full_path) else {
+                    return Ok((path, None, Ok(false)));
+                };
+                let thumbnail_full = base_dir.join(&thumbnail);
+                let generated = tokio::task::spawn_blocking(move || {
+                    crate::util::generate_thumbnail(&full_path, &thumbnail_full, size)
+                })
+                .await?;
+                Ok::<_, anyhow::Error>((path, Some(thumbnail), generated))
+            })
+            .buffer_unordered(self.concurrency as usize);
+
+        let (mut generated, mut fresh, mut failed, mut outside) = (0usize, 0usize, 0usize, 0usize);
+        while let Some(result) = results.next().await {
+            let (path, thumbnail, result) = result?;
+            bar.inc(1);
+            let Some(thumbnail) = thumbnail else {
+                bar.suspend(|| {
+                    tracing::warn!(
+                        "Skipping thumbnail of {}: not within the pixiv base directory",
+                        path
+                    )
+                });
+                outside += 1;
+                continue;
+            };
+            match result {
+                Ok(true) => generated += 1,
+                Ok(false) => fresh += 1,
+                Err(e) => {
+                    bar.suspend(|| {
+                        tracing::error!("Failed to generate thumbnail of {}: {}", path, e)
+                    });
+                    failed += 1;
+                    continue;
+                }
+            }
+            let thumbnail = thumbnail
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("Failed to convert path to UTF-8"))?;
+            db.update_image_thumbnail(&path, Some(thumbnail)).await?;
+        }
+        bar.finish();
+
+        tracing::info!(
+            "Generated {} thumbnails, {} already up to date, {} skipped outside the base directory",
+            generated,
+            fresh,
+            outside
+        );
+        if failed > 0 {
+            Err(anyhow::anyhow!("{} thumbnails failed to generate", failed))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl FileCleanTmpArgs {
+    pub fn run(&self, session: &Session) -> anyhow::Result<()> {
+        // Temporary files are always created directly inside the directory they're downloaded into
+        let mut dirs = Vec::new();
+        if let Ok(dir) = session.get_pixiv_base_dir() {
+            dirs.push(dir.clone());
+            dirs.push(session.get_pixiv_thumb_dir()?);
+        }
+        if let Ok(dir) = session.get_fanbox_base_dir() {
+            dirs.push(dir.clone());
+        }
+        dirs.sort();
+        dirs.dedup();
+
+        let now = std::time::SystemTime::now();
+        let (mut removed, mut recent) = (0usize, 0usize);
+        for dir in dirs {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                if !entry.file_name().to_string_lossy().starts_with("pixivdwn_")
+                    || !entry.file_type()?.is_file()
+                {
+                    continue;
+                }
+                let path = entry.path();
+                let age = now
+                    .duration_since(entry.metadata()?.modified()?)
+                    .unwrap_or_default();
+                if age < self.older_than {
+                    tracing::debug!("Keeping recent temporary file {}", path.display());
+                    recent += 1;
+                    continue;
+                }
+
+                tracing::info!(
+                    "{} {}",
+                    if self.dry_run {
+                        "Would remove"
+                    } else {
+                        "Removing"
+                    },
+                    path.display()
+                );
+                if !self.dry_run {
+                    std::fs::remove_file(&path)?;
+                }
+                removed += 1;
+            }
+        }
+
+        tracing::info!(
+            "{} {} temporary files, kept {} recent ones",
+            if self.dry_run {
+                "Would remove"
+            } else {
+                "Removed"
+            },
+            removed,
+            recent
+        );
+        Ok(())
+    }
+}
+
+/// Progress bar over `len` entries, hidden unless `show`
+fn progress_bar(show: bool, len: usize) -> indicatif::ProgressBar {
+    let bar = if show {
+        indicatif::ProgressBar::new(len as u64)
+    } else {
+        indicatif::ProgressBar::hidden()
+    };
+    bar.set_style(
+        indicatif::ProgressStyle::with_template(
+            "ETA {eta_precise} {elapsed_precise} | {wide_bar} {pos}/{len}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    bar
+}
+
+/// Decode the dimensions of a downloaded pixiv image, or the first frame of an ugoira
+fn probe_dim(path: &Path, is_ugoira: bool) -> anyhow::Result<(i64, i64)> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let (width, height) = if is_ugoira && !crate::util::is_zip(&mut file)? {
+        crate::util::guess_image_dim(file)?
+    } else if is_ugoira {
+        let mut archive = zip::ZipArchive::new(file)?;
+        let mut frame = archive.by_index(0)?;
+        let name = frame.name().to_owned();
+        // Entries can't seek if compressed, but a single frame is small enough to buffer
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut frame, &mut content)?;
+        crate::util::get_image_dim(std::io::Cursor::new(content), name, None)?
+    } else {
+        crate::util::get_image_dim(file, path, None)?
+    };
+    Ok((width as i64, height as i64))
+}
+
+fn resolve_fanbox_path(session: &Session, path: &str) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(session.get_fanbox_base_dir()?.join(path))
+    }
+}
+
+impl Database {
+    pub async fn run(self, session: &Session, dburl: &str) -> anyhow::Result<()> {
+        if let DatabaseCmd::Setup = self.cmd {
+            return self.setup(dburl).await;
+        }
+
+        let db = crate::db::Database::load(dburl).await?;
+        let ret = match self.cmd {
+            DatabaseCmd::Setup => unreachable!(),
+            DatabaseCmd::File(file) => file.run(session, &db).await,
+            DatabaseCmd::ReconcileDimensions(args) => args.run(session, &db).await,
+            DatabaseCmd::Export(args) => args.run(&db).await,
+            DatabaseCmd::Import(args) => args.run(&db).await,
+            DatabaseCmd::Vacuum(args) => args.run(&db).await,
+            DatabaseCmd::SetState(args) => args.run(&db).await,
+        };
+        crate::db::close_after(db, ret).await
+    }
+
+    pub async fn setup(self, dburl: &str) -> anyhow::Result<()> {
+        let db = crate::db::Database::setup(dburl).await?;
+        db.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
     }
 }
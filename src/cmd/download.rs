@@ -1,10 +1,19 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+};
 
-use clap::Args;
+use clap::{Args, Parser};
+use futures::StreamExt;
 
 use crate::{
-    data::pixiv::{IllustType, Page, PixivRequest},
-    util::{DatabasePathFormat, DownloadIdSrc, DownloadOverwriteBehavior, DownloadResult},
+    data::{
+        file::{BatchProgress, DownloadError, DownloadFailures, ExpectedContent},
+        pixiv::{IllustType, ImageVariant, Page, PixivRequest},
+    },
+    util::{
+        Checkpoint, DatabasePathFormat, DownloadIdSrc, DownloadOverwriteBehavior, DownloadResult,
+    },
 };
 
 #[derive(clap::ValueEnum, Clone, Copy)]
@@ -22,10 +31,26 @@ pub struct Download {
     /// ID of the illustration
     id: DownloadIdSrc<u64>,
 
+    /// Download the illustrations matching a query, given as `pixivdwn query` arguments up to the end of the command line
+    ///
+    /// E.g. `pixivdwn download -j 4 --from-query -t <TAG> -d missing`. The `--format` of the query is ignored.
+    #[arg(long, group = "DownloadIdSrc", num_args = 0.., allow_hyphen_values = true, value_name = "QUERY_ARGS")]
+    from_query: Option<Vec<String>>,
+
+    /// `--from-query` parsed by `parse_from_query`
+    #[arg(skip)]
+    query: Option<crate::cmd::query::Query>,
+
     /// Abort if failed
     #[arg(long)]
     abort_on_fail: bool,
 
+    /// Fail on file names that don't match pixiv's known URL format, instead of saving them with a warning
+    ///
+    /// Useful for detecting changes in pixiv's URLs.
+    #[arg(long)]
+    strict: bool,
+
     /// Dry run, only fetch and print the info
     #[arg(long)]
     dry_run: bool,
@@ -42,6 +67,31 @@ pub struct Download {
     #[arg(short = 't', long)]
     download_type: Option<DownloadType>,
 
+    /// Only download thumbnails of each page instead of the originals.
+    ///
+    /// Thumbnails are saved into the pixiv thumbnail directory, and recorded separately from the originals in the database. Ugoira are downloaded as their first frame.
+    #[arg(long, conflicts_with = "download_type")]
+    thumbnails_only: bool,
+
+    /// Size of thumbnails to download
+    #[arg(long, value_enum, default_value_t = ThumbnailSize::Regular, requires = "thumbnails_only")]
+    thumbnail_size: ThumbnailSize,
+
+    /// Size of each page to download. `regular` and `small` are the same as `--thumbnails-only` with that `--thumbnail-size`, and are saved into the pixiv thumbnail directory.
+    #[arg(long, value_enum, default_value_t = DownloadSize::Original, conflicts_with = "thumbnails_only")]
+    size: DownloadSize,
+
+    /// Also generate a JPEG thumbnail of each downloaded page, fitting into SIZE x SIZE pixels
+    ///
+    /// Saved under `<base_dir>/.thumbs`, mirroring the page's path. Unlike `--thumbnails-only`, this downscales the original locally. See `pixivdwn database file thumbnails`.
+    #[arg(
+        long,
+        alias = "thumbnail",
+        value_name = "SIZE",
+        conflicts_with = "thumbnails_only"
+    )]
+    generate_thumbnail: Option<u32>,
+
     /// Show progress bar. The download speed is based on the *UNZIPPED* stream, so don't be surprised if it exceeds your bandwidth.
     #[arg(short, long)]
     progress: bool,
@@ -49,6 +99,135 @@ pub struct Download {
     /// Force downloading existing pages
     #[arg(long, value_enum, default_value_t = OnExistingBehavior::Verify)]
     on_existing: OnExistingBehavior,
+
+    /// Skip existing pages only if their file is still present and intact, and re-download them otherwise.
+    ///
+    /// Files with a hash recorded by `database file checksum-index` are compared against it, others only need to exist.
+    /// Changed files are kept with a hash suffix, just like `--on-existing verify`.
+    #[arg(long, conflicts_with = "on_existing")]
+    skip_existing_by_hash: bool,
+
+    /// Before reusing an existing page, check that its file is not suspiciously small, and re-download it otherwise.
+    ///
+    /// Catches truncated or empty files that were recorded as complete, e.g. after a crash. Such pages are always overwritten, even with `--on-existing skip`.
+    #[arg(long, alias = "skip-if-smaller-than")]
+    reverify_size: bool,
+
+    /// Minimum plausible file size in bytes for `--reverify-size`
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 1024,
+        requires = "reverify_size"
+    )]
+    reverify_min_size: u64,
+
+    /// Record progress in a checkpoint, and skip already completed IDs if the same list was interrupted before.
+    ///
+    /// The checkpoint is stored in `~/.config/pixivdwn/checkpoints` (or under `$XDG_CONFIG_HOME`), and is cleared once the whole list completes without error.
+    #[arg(long)]
+    resume: bool,
+
+    /// Number of illustrations to download at once. Requests are still throttled by the global delay, but their transfers may overlap.
+    #[arg(short = 'j', long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+
+    /// Also convert downloaded ugoira into an animation next to the zip. See `pixivdwn convert`.
+    #[arg(long, value_enum, conflicts_with = "thumbnails_only")]
+    convert: Option<crate::cmd::convert::UgoiraFormat>,
+
+    /// Write a metadata sidecar next to each page, with the title, tags, author, creation date and x_restrict from the database.
+    ///
+    /// Existing sidecars are kept unless `--on-existing overwrite` is given. Pages skipped by `--on-existing skip` still get their missing sidecars.
+    #[arg(long, value_enum)]
+    sidecar: Option<SidecarFormat>,
+
+    /// Don't check that responses are images (or zips for ugoira) before saving them.
+    ///
+    /// By default, a response with any other `Content-Type`, e.g. an HTML error page, fails the download.
+    #[arg(long)]
+    no_mime_check: bool,
+
+    /// Save pages as this path relative to the base directory, instead of the original filename.
+    ///
+    /// Placeholders: `{illust_id}`, `{page}`, `{ext}`, `{filename}` (the original one), `{title}`, `{author_id}`, `{author_name}` and `{author_account}`.
+    /// e.g. `{author_account}/{illust_id}_p{page}.{ext}`. Unsafe characters in values are replaced, and missing values become `_`.
+    #[arg(long, value_name = "TEMPLATE")]
+    output_template: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum SidecarFormat {
+    /// `<filename>.json`
+    Json,
+
+    /// `<filename>.xmp`, which photo managers can read
+    Xmp,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum DownloadSize {
+    /// The original file
+    Original,
+
+    /// Longest side limited to 1200px
+    Regular,
+
+    /// Square-cropped, 540px
+    Small,
+}
+
+impl From<DownloadSize> for ImageVariant {
+    fn from(size: DownloadSize) -> Self {
+        match size {
+            DownloadSize::Original => ImageVariant::Original,
+            DownloadSize::Regular => ImageVariant::Regular,
+            DownloadSize::Small => ImageVariant::Small,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum ThumbnailSize {
+    /// Square-cropped, 540px
+    Small,
+
+    /// Longest side limited to 1200px
+    Regular,
+}
+
+impl From<ThumbnailSize> for ImageVariant {
+    fn from(size: ThumbnailSize) -> Self {
+        match size {
+            ThumbnailSize::Small => ImageVariant::Small,
+            ThumbnailSize::Regular => ImageVariant::Regular,
+        }
+    }
+}
+
+/// Arguments of `--from-query`, parsed the same way as `pixivdwn query`
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct FromQuery {
+    #[command(flatten)]
+    query: crate::cmd::query::Query,
+}
+
+/// A download started by another command, starting from the defaults of `pixivdwn download`
+#[derive(Parser)]
+struct Redownload {
+    #[command(flatten)]
+    download: Download,
+}
+
+/// Outcome of checking an existing page for `--skip-existing-by-hash`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ExistingCheck {
+    /// Not checked, because the flag is not given or there's no existing page
+    Unchecked,
+    Intact,
+    Missing,
+    Changed,
 }
 
 #[derive(clap::ValueEnum, Clone, Copy, Eq, PartialEq)]
@@ -64,32 +243,170 @@ pub enum OnExistingBehavior {
 }
 
 impl Download {
+    /// Parse the arguments of `--from-query`, so that mistakes in them are reported before the database is opened
+    pub fn parse_from_query(&mut self) -> Result<(), clap::Error> {
+        if let Some(ref args) = self.from_query {
+            let query = FromQuery::try_parse_from(
+                std::iter::once("download --from-query".to_string()).chain(args.iter().cloned()),
+            )?;
+            self.query = Some(query.query);
+        }
+        Ok(())
+    }
+
+    /// Overwrite all pages of an illustration, e.g. when the downloaded files are corrupted
+    ///
+    /// Everything else is the same as a plain `pixivdwn download <ID>`.
+    pub fn for_redownload(
+        id: u64,
+        variant: ImageVariant,
+        database_path_format: DatabasePathFormat,
+        output_template: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let mut download = Redownload::try_parse_from([
+            "download".to_owned(),
+            "--abort-on-fail".to_owned(),
+            "--on-existing=overwrite".to_owned(),
+            id.to_string(),
+        ])?
+        .download;
+        download.database_path_format = database_path_format;
+        download.output_template = output_template;
+        download.size = match variant {
+            ImageVariant::Original => DownloadSize::Original,
+            ImageVariant::Regular => DownloadSize::Regular,
+            ImageVariant::Small => DownloadSize::Small,
+        };
+        Ok(download)
+    }
+
+    /// The rendition of each page to download, from `--size` or `--thumbnails-only`
+    fn variant(&self) -> ImageVariant {
+        if self.thumbnails_only {
+            self.thumbnail_size.into()
+        } else {
+            self.size.into()
+        }
+    }
+
     pub async fn run(
-        self,
+        mut self,
         session: &crate::config::Session,
         db: &crate::db::Database,
     ) -> anyhow::Result<()> {
+        if self.query.is_none() {
+            self.parse_from_query()
+                .map_err(|e| anyhow::anyhow!(e.render().to_string()))?;
+        }
+        // Same as the conflicts of `--thumbnails-only`, which clap can't check for a value of `--size`
+        if self.size != DownloadSize::Original
+            && (self.download_type.is_some()
+                || self.generate_thumbnail.is_some()
+                || self.convert.is_some())
+        {
+            return Err(anyhow::anyhow!(
+                "--size regular or small can't be combined with --download-type, --generate-thumbnail or --convert"
+            ));
+        }
+        if let Some(ref template) = self.output_template {
+            // Fail on unknown placeholders before downloading anything
+            render_output_path(template, None, 0, "")?;
+        }
+
+        let ids = match self.query {
+            Some(ref query) => {
+                let ids = query.query_ids(db).await?;
+                tracing::info!("Query matched {} illustrations", ids.len());
+                Box::new(ids.into_iter().map(Ok))
+            }
+            None => self.id.read()?,
+        };
+        let (ids, checkpoint) = if self.resume {
+            let ids = ids.collect::<anyhow::Result<Vec<_>>>()?;
+            let checkpoint = Checkpoint::new(session.get_pixiv_base_dir()?, &ids)?;
+            let ids = checkpoint.resume(ids)?.map(Ok);
+            (
+                Box::new(ids) as Box<dyn Iterator<Item = _>>,
+                Some(checkpoint),
+            )
+        } else {
+            (ids, None)
+        };
+
+        crate::data::file::update_overlap_transfers(self.concurrency > 1);
+        let (min_len, max_len) = ids.size_hint();
+        let batch = BatchProgress::new(
+            self.progress,
+            (max_len == Some(min_len)).then_some(min_len as u64),
+        );
+        let batch = batch.as_ref();
+
+        // The same illust downloaded concurrently would race on its files
+        let mut seen = HashSet::new();
+        let ids = ids.filter(|id| match id {
+            Ok(id) => seen.insert(*id),
+            Err(_) => true,
+        });
+        // Ongoing downloads still finish on Ctrl-C
+        let ids = ids.take_while(|_| !crate::interrupt::is_interrupted());
+
+        let this = &self;
+        let mut results = futures::stream::iter(ids.enumerate())
+            .map(|(seq, id)| async move {
+                let id = id?;
+                Ok::<_, anyhow::Error>((seq, id, this.single(id, session, db, batch).await))
+            })
+            .buffer_unordered(self.concurrency as usize);
+
         let mut collected_errs = Vec::new();
-        for id in self.id.read()? {
-            let id = id?;
-            if let Err(e) = self.single(id, session, db).await {
+        // Successes that completed out of order, waiting for all prior IDs to complete
+        let mut pending = BTreeMap::new();
+        let mut next_seq = 0;
+        while let Some(result) = results.next().await {
+            let (seq, id, result) = result?;
+            if let Some(batch) = batch {
+                batch.inc();
+            }
+            if let Err(e) = result {
                 if self.abort_on_fail {
                     return Err(e);
                 } else {
                     tracing::error!("Failed to download {}: {:?}", id, e);
                     collected_errs.push((id, e));
                 }
+            } else if let Some(ref checkpoint) = checkpoint {
+                // Only advance past contiguous successes, so failed IDs are retried on resume
+                pending.insert(seq, id);
+                let mut last = None;
+                while let Some(id) = pending.remove(&next_seq) {
+                    last = Some(id);
+                    next_seq += 1;
+                }
+                if let Some(last) = last {
+                    checkpoint.save(last)?;
+                }
             };
         }
+        if let Some(batch) = batch {
+            batch.finish();
+        }
 
         if collected_errs.is_empty() {
+            // An interrupted run is resumed from the checkpoint
+            if let Some(checkpoint) = checkpoint
+                && !crate::interrupt::is_interrupted()
+            {
+                checkpoint.clear()?;
+            }
             Ok(())
         } else {
-            // TODO: use thiserror
-            Err(anyhow::anyhow!(
-                "{} errors occurred during download",
-                collected_errs.len()
-            ))
+            Err(DownloadFailures {
+                failures: collected_errs
+                    .into_iter()
+                    .map(|(id, e)| (id.to_string(), e))
+                    .collect(),
+            }
+            .into())
         }
     }
 
@@ -98,25 +415,50 @@ impl Download {
         id: u64,
         session: &crate::config::Session,
         db: &crate::db::Database,
+        batch: Option<&BatchProgress>,
     ) -> anyhow::Result<()> {
+        let variant = self.variant();
+        let base_dir = if variant.is_thumbnail() {
+            session.get_pixiv_thumb_dir()?
+        } else {
+            session.get_pixiv_base_dir()?.clone()
+        };
+
         if self.mkdir {
-            std::fs::create_dir_all(session.get_pixiv_base_dir()?)?;
+            std::fs::create_dir_all(&base_dir)?;
         }
 
-        let illust_type = db.get_illust_type(id).await?.ok_or_else(|| {
-            anyhow::anyhow!(
-                "{} not found in DB. Please run `pixivdwn illust {}` first.",
-                id,
-                id
-            )
-        })?;
+        let illust_type = db
+            .get_illust_type(id)
+            .await?
+            .ok_or(DownloadError::IllustNotInDatabase(id))?;
         let induced_download_type = match illust_type {
             IllustType::Ugoira => DownloadType::Ugoira,
             _ => DownloadType::Image,
         };
-        let download_type = self.download_type.unwrap_or(induced_download_type);
+        let download_type = if variant.is_thumbnail() {
+            // Thumbnails are only available as pages, ugoira included
+            DownloadType::Image
+        } else {
+            self.download_type.unwrap_or(induced_download_type)
+        };
 
-        let existing: HashMap<usize, String> = db.get_image_existing_for(id).await?.collect();
+        let mut existing: HashMap<usize, String> = HashMap::new();
+        let mut existing_hashes: HashMap<usize, String> = HashMap::new();
+        for (page, path, hash) in db.get_image_existing_for(id, variant).await? {
+            if let Some(hash) = hash {
+                existing_hashes.insert(page, hash);
+            }
+            existing.insert(page, path);
+        }
+        let resolve_existing = |path: &str| {
+            let path = std::path::Path::new(path);
+            if path.is_absolute() {
+                path.to_path_buf()
+            } else {
+                base_dir.join(path)
+            }
+        };
 
         enum DownloadSource {
             Page(Page),
@@ -124,21 +466,46 @@ impl Download {
         }
 
         impl DownloadSource {
-            fn get_url(&self) -> &str {
+            fn get_url(&self, variant: ImageVariant) -> &str {
                 match self {
-                    DownloadSource::Page(page) => &page.urls.original,
+                    DownloadSource::Page(page) => page.urls.get(variant),
                     DownloadSource::UgoiraMeta(meta) => &meta.original_src,
                 }
             }
 
-            fn get_dimension(&self, path: impl AsRef<Path>) -> anyhow::Result<(u64, u64)> {
+            fn get_dimension(
+                &self,
+                variant: ImageVariant,
+                path: impl AsRef<Path>,
+            ) -> anyhow::Result<(u64, u64)> {
                 match self {
-                    DownloadSource::Page(page) => Ok((page.width, page.height)),
+                    DownloadSource::Page(page) if !variant.is_thumbnail() => {
+                        Ok((page.width, page.height))
+                    }
+                    DownloadSource::Page(_) => {
+                        // Page dimensions are of the original, so probe the thumbnail itself
+                        let file = std::io::BufReader::new(std::fs::File::open(path.as_ref())?);
+                        let (width, height) =
+                            crate::util::get_image_dim(file, path.as_ref(), None)?;
+                        Ok((width as u64, height as u64))
+                    }
                     DownloadSource::UgoiraMeta(meta) => {
-                        let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
-                        let mut file = archive.by_name(&meta.frames[0].file)?;
+                        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+                        if !crate::util::is_zip(&mut file)? {
+                            // Delivered as an animated image, e.g. WebP or APNG
+                            let (width, height) = crate::util::guess_image_dim(file)?;
+                            return Ok((width as u64, height as u64));
+                        }
+
+                        let mut archive = zip::ZipArchive::new(file)?;
+                        // Entries can't seek if compressed, but a single frame is small enough to buffer
+                        let mut frame = Vec::new();
+                        std::io::Read::read_to_end(
+                            &mut archive.by_name(&meta.frames[0].file)?,
+                            &mut frame,
+                        )?;
                         let (width, height) = crate::util::get_image_dim(
-                            &mut file,
+                            std::io::Cursor::new(frame),
                             &meta.frames[0].file,
                             Some(&meta.mime_type),
                         )?;
@@ -157,26 +524,128 @@ impl Download {
 
         let sources: Box<dyn ExactSizeIterator<Item = DownloadSource>> = match download_type {
             DownloadType::Image => {
-                let pages = crate::data::pixiv::get_illust_pages(session, id).await?;
+                let pages = crate::retry::with_retries(
+                    crate::retry::RetryPolicy::global(),
+                    format!("pages of illust {}", id),
+                    || crate::data::pixiv::get_illust_pages(session, id),
+                )
+                .await?;
                 Box::new(pages.into_iter().map(DownloadSource::Page))
             }
             DownloadType::Ugoira => {
-                let meta = crate::data::pixiv::get_illust_ugoira_meta(session, id).await?;
+                let meta = crate::retry::with_retries(
+                    crate::retry::RetryPolicy::global(),
+                    format!("ugoira meta of illust {}", id),
+                    || crate::data::pixiv::get_illust_ugoira_meta(session, id),
+                )
+                .await?;
+                if meta.frames.is_empty() {
+                    tracing::warn!("Ugoira {} has no frames, skipping", id);
+                    return Ok(());
+                }
                 Box::new(std::iter::once(DownloadSource::UgoiraMeta(meta)))
             }
         };
 
+        let meta = if (self.sidecar.is_some() && !self.dry_run) || self.output_template.is_some() {
+            Some(
+                db.get_illust_sidecar(id)
+                    .await?
+                    .ok_or(DownloadError::IllustNotInDatabase(id))?,
+            )
+        } else {
+            None
+        };
+        let sidecar = match (self.sidecar, &meta) {
+            (Some(format), Some(meta)) if !self.dry_run => Some((format, meta)),
+            _ => None,
+        };
+        let overwrite_sidecar = self.on_existing == OnExistingBehavior::Overwrite;
+
         let tot_len = sources.len();
         tracing::info!("Downloading {} sources...", tot_len);
 
         for (idx, src) in sources.enumerate() {
-            if self.on_existing == OnExistingBehavior::Skip && existing.contains_key(&idx) {
+            let undersized = match existing.get(&idx) {
+                Some(existing) if self.reverify_size => {
+                    let existing_full_path = resolve_existing(existing);
+                    match tokio::fs::metadata(&existing_full_path).await {
+                        Ok(meta) if meta.len() < self.reverify_min_size => {
+                            tracing::warn!(
+                                "Source {}/{}: Existing file {} is only {} bytes, re-downloading",
+                                idx + 1,
+                                tot_len,
+                                existing_full_path.display(),
+                                meta.len()
+                            );
+                            true
+                        }
+                        Ok(_) => false,
+                        // Missing files are reported below
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                _ => false,
+            };
+
+            let check = match existing.get(&idx) {
+                Some(existing) if self.skip_existing_by_hash && !undersized => {
+                    let existing_full_path = resolve_existing(existing);
+                    if !tokio::fs::try_exists(&existing_full_path).await? {
+                        tracing::warn!(
+                            "Source {}/{}: Existing file {} is missing, re-downloading",
+                            idx + 1,
+                            tot_len,
+                            existing_full_path.display()
+                        );
+                        ExistingCheck::Missing
+                    } else if let Some(hash) = existing_hashes.get(&idx) {
+                        let digest = tokio::task::block_in_place(|| {
+                            crate::util::hash_file(&existing_full_path)
+                        })?;
+                        if hex::encode(digest) == *hash {
+                            ExistingCheck::Intact
+                        } else {
+                            tracing::warn!(
+                                "Source {}/{}: Existing file {} doesn't match its recorded hash, re-downloading",
+                                idx + 1,
+                                tot_len,
+                                existing_full_path.display()
+                            );
+                            ExistingCheck::Changed
+                        }
+                    } else {
+                        ExistingCheck::Intact
+                    }
+                }
+                _ => ExistingCheck::Unchecked,
+            };
+
+            if (self.on_existing == OnExistingBehavior::Skip
+                && existing.contains_key(&idx)
+                && !undersized)
+                || check == ExistingCheck::Intact
+            {
                 tracing::info!("Source {}/{}: Skipping", idx + 1, tot_len);
+                if let Some((format, meta)) = sidecar {
+                    let existing_path = std::path::Path::new(&existing[&idx]);
+                    write_sidecar(format, meta, idx, &base_dir.join(existing_path), false)?;
+                }
                 continue;
             }
 
-            let url = src.get_url();
-            let filename = url.split('/').last().unwrap();
+            let url = src.get_url(variant);
+            let filename = url.split('/').next_back().unwrap();
+            let target = match self.output_template {
+                Some(ref template) => {
+                    let path = render_output_path(template, meta.as_ref(), idx, filename)?;
+                    path.to_str()
+                        .ok_or_else(|| anyhow::anyhow!("Failed to convert path to UTF-8"))?
+                        .to_owned()
+                }
+                None => filename.to_owned(),
+            };
 
             match src {
                 DownloadSource::Page(ref page) => {
@@ -193,30 +662,36 @@ impl Download {
                     // As of 2025-12, the filename format is:
                     // <illust-id>[-<hash>]_p<page>.<ext>
                     // The hash part might be used to handle same-day reuploads, but we're not sure
+                    // Thumbnails additionally carry a size suffix, e.g. _master1200 or _square1200
                     let filename_re = regex::Regex::new(&format!(
-                        r"^{}(-[0-9a-f]+)?_p{}\.\w+$",
+                        r"^{}(-[0-9a-f]+)?_p{}{}\.\w+$",
                         id,
                         idx, // Page index starts with 0
+                        if variant.is_thumbnail() { r"_\w+" } else { "" },
                     ))?;
                     if !filename_re.is_match(filename) {
-                        return Err(anyhow::anyhow!("Unexpected filename format: {}", filename));
+                        self.unexpected_filename(filename)?;
                     }
                 }
                 DownloadSource::UgoiraMeta(ref meta) => {
                     tracing::info!("Ugoira pack {}: {} from {}", filename, meta.mime_type, url);
 
                     // We assume ugoira can also have the hash component, although not seen in the wild.
+                    // Usually a zip of frames, but may also be an animated image.
                     let filename_re = regex::Regex::new(&format!(
-                        r"^{}(-[0-9a-f]+)?_ugoira([0-9]+x[0-9]+)\.zip$",
+                        r"^{}(-[0-9a-f]+)?_ugoira([0-9]+x[0-9]+)\.(zip|webp|png|apng|gif)$",
                         id,
                     ))?;
                     if !filename_re.is_match(filename) {
-                        return Err(anyhow::anyhow!("Unexpected filename format: {}", filename));
+                        self.unexpected_filename(filename)?;
                     }
                 }
             }
 
-            let overwrite_behavior = if let Some(existing) = existing.get(&idx) {
+            let overwrite_behavior = if check == ExistingCheck::Missing {
+                // The recorded row is superseded by the new download
+                DownloadOverwriteBehavior::Free
+            } else if let Some(existing) = existing.get(&idx) {
                 // Resolve old path, check if it exists. If no, errors
                 let existing_path = std::path::Path::new(&existing);
                 if !tokio::fs::try_exists(existing_path).await? {
@@ -232,10 +707,16 @@ impl Download {
                 let existing_full_path = if existing_path.is_absolute() {
                     existing_path.to_path_buf()
                 } else {
-                    session.get_pixiv_base_dir()?.join(existing_path)
+                    base_dir.join(existing_path)
                 };
 
                 match self.on_existing {
+                    _ if undersized => DownloadOverwriteBehavior::Overwrite {
+                        old: Some(existing_full_path),
+                    },
+                    _ if check == ExistingCheck::Changed => DownloadOverwriteBehavior::Compare {
+                        old: existing_full_path,
+                    },
                     OnExistingBehavior::Skip => unreachable!(),
                     OnExistingBehavior::Verify => DownloadOverwriteBehavior::Compare {
                         old: existing_full_path,
@@ -248,12 +729,28 @@ impl Download {
                 DownloadOverwriteBehavior::Free
             };
 
+            if self.output_template.is_some() {
+                tracing::info!("Source {}/{}: Saving as {}", idx + 1, tot_len, target);
+            }
+
             if !self.dry_run {
                 match self
-                    .download_file(session, url, filename, overwrite_behavior)
+                    .download_file(
+                        session,
+                        &base_dir,
+                        url,
+                        &target,
+                        match download_type {
+                            _ if self.no_mime_check => ExpectedContent::Any,
+                            DownloadType::Image => ExpectedContent::Image,
+                            DownloadType::Ugoira => ExpectedContent::Ugoira,
+                        },
+                        overwrite_behavior,
+                        batch,
+                    )
                     .await?
                 {
-                    DownloadResult::Unchanged { size } => {
+                    DownloadResult::Unchanged { size, digest } => {
                         tracing::info!(
                             "Source {}/{}: Unchanged ({} bytes), refresh",
                             idx + 1,
@@ -264,13 +761,27 @@ impl Download {
                             unreachable!();
                         };
                         assert!(
-                            db.update_image_path_refresh(&old).await?,
+                            db.update_image_path_refresh(old).await?,
                             "Fail to refresh, possible db race"
                         );
+                        db.update_image_hash(old, &hex::encode(digest)).await?;
+                        if let Some((format, meta)) = sidecar {
+                            let old = base_dir.join(old);
+                            write_sidecar(format, meta, idx, &old, overwrite_sidecar)?;
+                        }
+                    }
+                    DownloadResult::Skipped { final_path } => {
+                        tracing::warn!(
+                            "Source {}/{}: Skipped, {} is in the way. Move it away, or use `--on-existing overwrite` to replace it",
+                            idx + 1,
+                            tot_len,
+                            final_path.display()
+                        );
                     }
                     DownloadResult::Written {
                         written_path,
                         final_path,
+                        digest,
                         old,
                         ..
                     } => {
@@ -282,11 +793,20 @@ impl Download {
                         );
                         if let Some(existing) = existing.get(&idx) {
                             match old {
+                                crate::util::DownloadOldResult::Stale
+                                    if check == ExistingCheck::Missing =>
+                                {
+                                    // The recorded file is gone, replace its record
+                                    assert!(
+                                        db.update_image_path_move(existing, None).await?,
+                                        "Fail to remove missing record, possible db race"
+                                    );
+                                }
                                 crate::util::DownloadOldResult::Stale => {} // Does nothing
                                 crate::util::DownloadOldResult::Overwritten => {
                                     // Delete old
                                     assert!(
-                                        db.update_image_path_move(&existing, None).await?,
+                                        db.update_image_path_move(existing, None).await?,
                                         "Fail to update path for overwritten, possible db race"
                                     );
                                 }
@@ -295,9 +815,11 @@ impl Download {
                                         anyhow::anyhow!("Failed to convert path to UTF-8")
                                     })?;
                                     assert!(
-                                        db.update_image_path_move(&existing, Some(new)).await?,
+                                        db.update_image_path_move(existing, Some(new)).await?,
                                         "Fail to update path for moved, possible db race"
                                     );
+                                    // Its thumbnail mirrors the original path, which now belongs to the new download
+                                    db.update_image_thumbnail(new, None).await?;
                                 }
                             }
                         }
@@ -306,8 +828,13 @@ impl Download {
                             .to_str()
                             .ok_or_else(|| anyhow::anyhow!("Failed to convert path to UTF-8"))?;
 
-                        let (width, height) =
-                            tokio::task::block_in_place(|| src.get_dimension(final_path))?;
+                        if let Some((format, meta)) = sidecar {
+                            write_sidecar(format, meta, idx, &final_path, overwrite_sidecar)?;
+                        }
+
+                        let (width, height) = tokio::task::block_in_place(|| {
+                            src.get_dimension(variant, &final_path)
+                        })?;
                         db.insert_image(
                             id,
                             idx,
@@ -316,32 +843,238 @@ impl Download {
                             width,
                             height,
                             src.ugoira_frames(),
+                            variant,
+                            &hex::encode(digest),
                         )
                         .await?;
+
+                        if let Some(size) = self.generate_thumbnail
+                            && matches!(download_type, DownloadType::Image)
+                        {
+                            // The page itself is saved, so a failed thumbnail is left for `database file thumbnails`
+                            match Self::write_thumbnail(&base_dir, &final_path, size) {
+                                Ok(thumbnail) => {
+                                    db.update_image_thumbnail(written_path, Some(&thumbnail))
+                                        .await?;
+                                }
+                                Err(e) => tracing::warn!(
+                                    "Failed to generate thumbnail of {}: {}",
+                                    final_path.display(),
+                                    e
+                                ),
+                            }
+                        }
                     }
                 }
             }
         }
 
+        if let Some(format) = self.convert
+            && matches!(download_type, DownloadType::Ugoira)
+            && !self.dry_run
+        {
+            crate::cmd::convert::convert_ugoira(session, db, id, format).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The file is still saved under its name, unless `--strict`
+    fn unexpected_filename(&self, filename: &str) -> anyhow::Result<()> {
+        if self.strict {
+            return Err(anyhow::anyhow!("Unexpected filename format: {}", filename));
+        }
+        tracing::warn!(
+            "Unexpected filename format: {}, pixiv may have changed its URLs. Saving anyway",
+            filename
+        );
         Ok(())
     }
 
+    /// Generate the thumbnail of a saved page, returning its path relative to the base directory
+    fn write_thumbnail(base_dir: &Path, page: &Path, size: u32) -> anyhow::Result<String> {
+        let thumbnail = crate::util::thumbnail_rel_path(base_dir, page)
+            .ok_or_else(|| anyhow::anyhow!("not within {}", base_dir.display()))?;
+        tokio::task::block_in_place(|| {
+            crate::util::generate_thumbnail(page, &base_dir.join(&thumbnail), size)
+        })?;
+        thumbnail
+            .into_os_string()
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("Failed to convert path to UTF-8"))
+    }
+
+    #[expect(clippy::too_many_arguments)]
     async fn download_file(
         &self,
         session: &crate::config::Session,
+        base_dir: &Path,
         url: &str,
         filename: &str,
+        expected: ExpectedContent,
         overwrite_behavior: DownloadOverwriteBehavior,
+        batch: Option<&BatchProgress>,
     ) -> anyhow::Result<DownloadResult> {
+        if let Some(dir) = Path::new(filename).parent() {
+            tokio::fs::create_dir_all(base_dir.join(dir)).await?;
+        }
         crate::util::download_then_persist(
             PixivRequest(session),
-            session.get_pixiv_base_dir()?,
+            base_dir,
             filename,
             self.database_path_format,
             url,
+            expected,
             overwrite_behavior,
-            self.progress,
+            crate::data::file::progress_bar(self.progress, batch),
         )
         .await
     }
 }
+
+/// Fill `--output-template` for a page. Without metadata, only the placeholders themselves are checked.
+fn render_output_path(
+    template: &str,
+    meta: Option<&crate::db::IllustSidecar>,
+    page: usize,
+    filename: &str,
+) -> anyhow::Result<std::path::PathBuf> {
+    let ext = filename.rsplit_once('.').map(|(_, ext)| ext.to_owned());
+    crate::util::render_path_template(template, |name| {
+        Some(match name {
+            "illust_id" => meta.map(|m| m.illust_id.to_string()),
+            "page" => Some(page.to_string()),
+            "ext" => ext.clone(),
+            "filename" => Some(filename.to_owned()),
+            "title" => meta.and_then(|m| m.title.clone()),
+            "author_id" => meta.and_then(|m| m.author_id.map(|id| id.to_string())),
+            "author_name" => meta.and_then(|m| m.author_name.clone()),
+            "author_account" => meta.and_then(|m| m.author_account.clone()),
+            _ => return None,
+        })
+    })
+}
+
+#[derive(serde::Serialize)]
+struct PageSidecar<'a> {
+    #[serde(flatten)]
+    illust: &'a crate::db::IllustSidecar,
+    page: usize,
+}
+
+/// Write the sidecar of a page next to its image, replacing the old one atomically
+fn write_sidecar(
+    format: SidecarFormat,
+    meta: &crate::db::IllustSidecar,
+    page: usize,
+    image: &Path,
+    overwrite: bool,
+) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let (Some(dir), Some(filename)) = (image.parent(), image.file_name()) else {
+        return Err(anyhow::anyhow!("Invalid image path {}", image.display()));
+    };
+    let mut sidecar_name = filename.to_os_string();
+    sidecar_name.push(match format {
+        SidecarFormat::Json => ".json",
+        SidecarFormat::Xmp => ".xmp",
+    });
+    let sidecar_path = dir.join(sidecar_name);
+    if !overwrite && sidecar_path.try_exists()? {
+        return Ok(());
+    }
+
+    let content = match format {
+        SidecarFormat::Json => serde_json::to_string_pretty(&PageSidecar { illust: meta, page })?,
+        SidecarFormat::Xmp => render_xmp(meta, page),
+    };
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(content.as_bytes())?;
+    tmp.persist(&sidecar_path)?;
+    tracing::debug!("Wrote sidecar {}", sidecar_path.display());
+    Ok(())
+}
+
+fn render_xmp(meta: &crate::db::IllustSidecar, page: usize) -> String {
+    fn escape(s: &str) -> String {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    let mut props = Vec::new();
+    if let Some(title) = &meta.title {
+        props.push(format!(
+            "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>",
+            escape(title)
+        ));
+    }
+    if let Some(author) = &meta.author_name {
+        props.push(format!(
+            "<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>",
+            escape(author)
+        ));
+    }
+    if !meta.tags.is_empty() {
+        let tags: Vec<_> = meta
+            .tags
+            .iter()
+            .map(|t| format!("<rdf:li>{}</rdf:li>", escape(t)))
+            .collect();
+        props.push(format!(
+            "<dc:subject><rdf:Bag>{}</rdf:Bag></dc:subject>",
+            tags.concat()
+        ));
+    }
+    props.push(format!(
+        "<dc:source>https://www.pixiv.net/artworks/{}</dc:source>",
+        meta.illust_id
+    ));
+    if let Some(date) = &meta.create_date {
+        props.push(format!("<xmp:CreateDate>{}</xmp:CreateDate>", date));
+    }
+    props.push(format!(
+        "<pixivdwn:IllustId>{}</pixivdwn:IllustId>",
+        meta.illust_id
+    ));
+    props.push(format!("<pixivdwn:Page>{}</pixivdwn:Page>", page));
+    if let Some(author_id) = meta.author_id {
+        props.push(format!(
+            "<pixivdwn:AuthorId>{}</pixivdwn:AuthorId>",
+            author_id
+        ));
+    }
+    if let Some(account) = &meta.author_account {
+        props.push(format!(
+            "<pixivdwn:AuthorAccount>{}</pixivdwn:AuthorAccount>",
+            escape(account)
+        ));
+    }
+    if let Some(x_restrict) = meta.x_restrict {
+        props.push(format!(
+            "<pixivdwn:XRestrict>{}</pixivdwn:XRestrict>",
+            x_restrict
+        ));
+    }
+
+    let props: Vec<_> = props.iter().map(|p| format!("   {}\n", p)).collect();
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+            " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+            "  <rdf:Description rdf:about=\"\"\n",
+            "    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n",
+            "    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n",
+            "    xmlns:pixivdwn=\"https://github.com/CircuitCoder/pixivdwn/ns/1.0/\">\n",
+            "{}",
+            "  </rdf:Description>\n",
+            " </rdf:RDF>\n",
+            "</x:xmpmeta>\n",
+            "<?xpacket end=\"w\"?>\n",
+        ),
+        props.concat()
+    )
+}
@@ -2,7 +2,11 @@ use clap::{Args, Subcommand};
 use futures::StreamExt;
 
 use crate::{
-    data::fanbox::FanboxRequest,
+    data::{
+        fanbox::FanboxRequest,
+        file::{BatchProgress, DownloadError, DownloadFailures, ExpectedContent},
+    },
+    db::{FanboxBodyStorage, QueryParam},
     util::{
         DatabasePathFormat, DownloadIdSrc, DownloadOverwriteBehavior, DownloadResult,
         TerminationCondition,
@@ -21,6 +25,16 @@ pub enum FanboxAttachmentType {
     Image,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum FanboxDownloadType {
+    File,
+    Image,
+    /// Both images and files, the type of each ID is looked up in the database
+    Both,
+    /// Cover image of a post, identified by the post ID
+    Cover,
+}
+
 #[derive(Args)]
 #[group(required = false, multiple = false)]
 pub struct FanboxSyncSrc {
@@ -49,13 +63,107 @@ pub struct FanboxSyncArgs {
     #[arg(long, default_value_t = false)]
     skip_failed: bool,
 
-    /// Maximum number of retries for post fetch
-    #[arg(short, long, default_value_t = 0)]
-    retries: usize,
+    /// Don't store posts without any image or file attachment.
+    ///
+    /// Posts whose body is inaccessible (e.g. not supported at the required tier) are still stored, since their attachments are unknown.
+    #[arg(long)]
+    only_with_attachments: bool,
 
-    /// Exponential backoff base for retries
+    /// Stop after this much time has elapsed, e.g. `30m` or `1h` (alias: --timeout-total)
+    ///
+    /// Checked between posts, so the one being synced is always stored.
+    #[arg(long, alias = "timeout-total", value_parser = crate::util::parse_cli_duration)]
+    max_duration: Option<std::time::Duration>,
+
+    #[command(flatten)]
+    body_storage: BodyStorageArgs,
+
+    /// Fail on rich post bodies with unmapped images, files or embeds, instead of ignoring them with a warning.
+    ///
+    /// Useful for detecting changes in Fanbox's API.
     #[arg(long)]
-    retry_backoff: Option<usize>,
+    strict_json: bool,
+}
+
+/// What to keep of post bodies when storing posts
+#[derive(Args)]
+pub struct BodyStorageArgs {
+    /// Don't store post bodies. An empty body is recorded instead, while attachments and metadata are still stored.
+    #[arg(long)]
+    no_store_body: bool,
+
+    /// Store rich post bodies as plain text (their paragraphs and headers) instead of the serialized blocks
+    ///
+    /// Saves space, but images, files and embeds referenced by the body are only kept as attachments, and `fanbox prune --offline` skips these posts.
+    #[arg(long, conflicts_with = "no_store_body")]
+    rich_body_as_plaintext: bool,
+}
+
+impl BodyStorageArgs {
+    fn storage(&self) -> FanboxBodyStorage {
+        if self.no_store_body {
+            FanboxBodyStorage::Empty
+        } else if self.rich_body_as_plaintext {
+            FanboxBodyStorage::PlainText
+        } else {
+            FanboxBodyStorage::Full
+        }
+    }
+}
+
+/// Store a fetched post along with its attachments and embeds, logging what changed
+async fn store_post(
+    db: &crate::db::Database,
+    detail: &mut crate::data::fanbox::FetchPostDetail,
+    storage: FanboxBodyStorage,
+) -> anyhow::Result<()> {
+    let updated = db.update_fanbox_post(detail, storage).await?;
+    let prompt = match updated {
+        crate::db::FanboxPostUpdateResult::Inserted => "Inserted",
+        crate::db::FanboxPostUpdateResult::Updated => "Updated",
+        crate::db::FanboxPostUpdateResult::Skipped => "Skipped",
+    };
+
+    tracing::info!("{} post {} - {}", prompt, detail.post.id, detail.post.title);
+
+    if let Some(ref user) = detail.post.user
+        && let Some(alias) = crate::util::sanitize_path_component(&user.name)
+        && db
+            .add_fanbox_creator_alias(&detail.post.creator_id, &alias)
+            .await?
+    {
+        tracing::info!("  Aliased creator {} as {}", detail.post.creator_id, alias);
+    }
+
+    if let Some(ref mut body) = detail.body {
+        for (idx, file) in body.files() {
+            let added = db.add_fanbox_file(detail.post.id, idx, file).await?;
+            if added {
+                tracing::info!("  Added {}: file {} - {}", idx, file.id, file.name);
+            }
+        }
+
+        for (idx, image) in body.images() {
+            let added = db.add_fanbox_image(detail.post.id, idx, image).await?;
+            if added {
+                tracing::info!("  Added {}: image {}", idx, image.id);
+            }
+        }
+
+        for (idx, embed) in body.url_embeds() {
+            let added = db.add_fanbox_url_embed(detail.post.id, idx, embed).await?;
+            if added {
+                tracing::info!(
+                    "  Added {}: embed {} - {}",
+                    idx,
+                    embed.id,
+                    embed.url.as_deref().unwrap_or("<unresolved>")
+                );
+            }
+        }
+    }
+
+    Ok(())
 }
 
 impl FanboxSyncArgs {
@@ -65,73 +173,61 @@ impl FanboxSyncArgs {
         db: &crate::db::Database,
         id: u64,
     ) -> anyhow::Result<()> {
-        let mut tries = 0;
-        let mut backoff = self.retry_backoff;
-        let mut detail = loop {
-            match crate::data::fanbox::fetch_post(session, id).await {
-                Err(e) => {
-                    tracing::warn!("Failed to fetch post {}: {}", id, e);
-                    if tries == self.retries {
-                        tracing::error!(
-                            "Failed to fetch post {} after {} tries",
-                            id,
-                            1 + self.retries
-                        );
-
-                        return Err(e);
-                    }
-                    tries += 1;
-                    if let Some(ref mut b) = backoff {
-                        tracing::info!("Backing off for {}ms", *b);
-                        tokio::time::sleep(std::time::Duration::from_millis(*b as u64)).await;
-                        *b *= 2;
-                    }
-                }
-                Ok(d) => break d,
-            }
-        };
-
-        let updated = db.update_fanbox_post(&detail).await?;
-        let prompt = match updated {
-            crate::db::FanboxPostUpdateResult::Inserted => "Inserted",
-            crate::db::FanboxPostUpdateResult::Updated => "Updated",
-            crate::db::FanboxPostUpdateResult::Skipped => "Skipped",
-        };
-
-        tracing::info!("{} post {} - {}", prompt, id, detail.post.title);
-
-        if let Some(ref mut body) = detail.body {
-            for (idx, file) in body.files() {
-                let added = db.add_fanbox_file(detail.post.id, idx, file).await?;
-                if added {
-                    tracing::info!("  Added {}: file {} - {}", idx, file.id, file.name);
-                }
-            }
+        let mut detail = crate::retry::with_retries(
+            crate::retry::RetryPolicy::global(),
+            format!("post {}", id),
+            || crate::data::fanbox::fetch_post(session, id),
+        )
+        .await?;
 
-            for (idx, image) in body.images() {
-                let added = db.add_fanbox_image(detail.post.id, idx, image).await?;
-                if added {
-                    tracing::info!("  Added {}: image {}", idx, image.id);
-                }
-            }
+        if self.only_with_attachments
+            && let Some(ref mut body) = detail.body
+            && body.images().next().is_none()
+            && body.files().next().is_none()
+        {
+            tracing::info!(
+                "Skipping post {} - {}: no attachments",
+                id,
+                detail.post.title
+            );
+            return Ok(());
         }
 
+        store_post(db, &mut detail, self.body_storage.storage()).await?;
         Ok(())
     }
 
+    /// Returns if the sync stopped early due to `--max-duration` or Ctrl-C
     async fn sync_creator(
         &self,
         session: &crate::config::Session,
         db: &crate::db::Database,
         creator: &str,
-    ) -> anyhow::Result<()> {
+        deadline: Option<tokio::time::Instant>,
+    ) -> anyhow::Result<bool> {
         let mut posts = Box::pin(crate::data::fanbox::fetch_author_posts(
             session,
             creator,
             self.skip_pages.unwrap_or(0),
         ));
 
+        let mut cnt = 0;
         while let Some(post) = posts.next().await.transpose()? {
+            if crate::interrupt::is_interrupted() {
+                return Ok(true);
+            }
+            if let Some(deadline) = deadline
+                && tokio::time::Instant::now() >= deadline
+            {
+                tracing::info!(
+                    "Reached the maximum duration after {} posts of creator {}. Terminating.",
+                    cnt,
+                    creator
+                );
+                return Ok(true);
+            }
+            cnt += 1;
+
             let orig = db.query_fanbox_post_status(post.id).await?;
             if let Some(orig) = orig
                 && !orig.needs_update(&post)
@@ -151,19 +247,25 @@ impl FanboxSyncArgs {
             }
 
             let ret = self.sync_post(session, db, post.id).await;
-            if !self.skip_failed && ret.is_err() {
-                return ret;
+            if !self.skip_failed {
+                ret?;
             }
         }
-        Ok(())
+        Ok(false)
     }
 
     async fn sync_all(
         &self,
         session: &crate::config::Session,
         db: &crate::db::Database,
+        deadline: Option<tokio::time::Instant>,
     ) -> anyhow::Result<()> {
-        let creators = crate::data::fanbox::fetch_supporting_list(session).await?;
+        let creators = crate::retry::with_retries(
+            crate::retry::RetryPolicy::global(),
+            "supported creators",
+            || crate::data::fanbox::fetch_supporting_list(session),
+        )
+        .await?;
         for creator in creators {
             tracing::info!(
                 "Syncing creator {} ({})",
@@ -174,7 +276,12 @@ impl FanboxSyncArgs {
                     .unwrap_or("?"),
                 creator.creator_id
             );
-            self.sync_creator(session, db, &creator.creator_id).await?;
+            if self
+                .sync_creator(session, db, &creator.creator_id, deadline)
+                .await?
+            {
+                break;
+            }
         }
         Ok(())
     }
@@ -184,13 +291,40 @@ impl FanboxSyncArgs {
         session: &crate::config::Session,
         db: &crate::db::Database,
     ) -> anyhow::Result<()> {
+        crate::data::fanbox::set_strict_json(self.strict_json);
+        let deadline = crate::util::deadline_after(self.max_duration);
         if let Some(p) = self.src.post {
             self.sync_post(session, db, p).await
         } else if let Some(ref c) = self.src.creator {
-            self.sync_creator(session, db, c).await
+            self.sync_creator(session, db, c, deadline).await?;
+            Ok(())
         } else {
-            self.sync_all(session, db).await
+            self.sync_all(session, db, deadline).await
+        }
+    }
+}
+
+/// `<creator_id>=<folder_name>` pair
+#[derive(Clone)]
+pub struct CreatorAlias {
+    creator_id: String,
+    alias: String,
+}
+
+impl std::str::FromStr for CreatorAlias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (creator_id, alias) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <creator_id>=<folder_name>, got `{}`", s))?;
+        if crate::util::sanitize_path_component(alias).as_deref() != Some(alias) {
+            return Err(format!("`{}` is not a valid folder name", alias));
         }
+        Ok(CreatorAlias {
+            creator_id: creator_id.to_owned(),
+            alias: alias.to_owned(),
+        })
     }
 }
 
@@ -198,12 +332,16 @@ impl FanboxSyncArgs {
 pub struct FanboxDownloadArgs {
     /// Type of the downloaded item
     #[arg(value_enum)]
-    r#type: FanboxAttachmentType,
+    r#type: FanboxDownloadType,
 
     #[clap(flatten)]
-    /// ID of the image / file
+    /// ID of the image / file, or the post for covers
     id: DownloadIdSrc<String>,
 
+    /// Download all items of the given type in a post, in the order they appear. Can appear multiple times.
+    #[arg(long, group = "DownloadIdSrc")]
+    post: Vec<u64>,
+
     /// Abort if failed
     #[arg(long)]
     abort_on_fail: bool,
@@ -219,6 +357,33 @@ pub struct FanboxDownloadArgs {
     /// Show progress bar. The download speed is based on the *UNZIPPED* stream, so don't be surprised if it exceeds your bandwidth.
     #[arg(short, long)]
     progress: bool,
+
+    /// Number of items to download at once. Requests are still throttled by the global delay, but their transfers may overlap.
+    #[arg(short = 'j', long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+
+    /// Save into a subdirectory per creator, named `<alias> (<creator_id>)`, or the creator ID if not aliased.
+    ///
+    /// The ID keeps creators apart when they share an alias, or one's alias is another's ID. Aliases are derived from the creator's name during sync, and can be overridden with `--creator-alias`.
+    #[arg(long)]
+    by_creator: bool,
+
+    /// Set the alias of a creator, in the form of `<creator_id>=<folder_name>`. Can appear multiple times.
+    ///
+    /// Aliases are saved in the database, so later downloads and `database file canonicalize` use the same folder.
+    #[arg(long)]
+    creator_alias: Vec<CreatorAlias>,
+
+    /// Don't check that images and covers are served as images before saving them. Files are never checked.
+    #[arg(long)]
+    no_mime_check: bool,
+
+    /// Save images and files as this path relative to the base directory, instead of `<post_id>_<idx>_<id>[_<name>].<ext>`.
+    ///
+    /// Placeholders: `{post_id}`, `{idx}`, `{id}`, `{name}` (files only), `{ext}`, `{title}` (of the post), `{creator_id}` and `{creator}` (the creator subdirectory, see `--by-creator`).
+    /// Unsafe characters in values are replaced, and missing values become `_`. Covers keep their default name.
+    #[arg(long, value_name = "TEMPLATE")]
+    output_template: Option<String>,
 }
 
 impl FanboxDownloadArgs {
@@ -227,60 +392,203 @@ impl FanboxDownloadArgs {
         session: &crate::config::Session,
         db: &crate::db::Database,
         id: &str,
-    ) -> anyhow::Result<()> {
-        let (url, filename) = get_download_spec(db, self.r#type, id).await?;
-        let DownloadResult::Written {
-            written_path,
-            final_path,
-            size,
-            ..
-        } = crate::util::download_then_persist(
-            FanboxRequest(session),
-            session.get_fanbox_base_dir()?,
-            &filename,
-            self.database_path_format,
-            &url,
-            DownloadOverwriteBehavior::Overwrite { old: None },
-            self.progress,
-        )
-        .await?
-        else {
-            unreachable!()
+        batch: Option<&BatchProgress>,
+    ) -> anyhow::Result<FanboxAttachmentType> {
+        let ty = match self.r#type {
+            FanboxDownloadType::File => FanboxAttachmentType::File,
+            FanboxDownloadType::Image => FanboxAttachmentType::Image,
+            FanboxDownloadType::Cover => unreachable!("Covers are downloaded by download_cover"),
+            FanboxDownloadType::Both => {
+                if db.query_fanbox_image_download_spec(id).await?.is_some() {
+                    FanboxAttachmentType::Image
+                } else if db.query_fanbox_file_download_spec(id).await?.is_some() {
+                    FanboxAttachmentType::File
+                } else {
+                    return Err(anyhow::anyhow!(
+                        "Neither image nor file {} found in database",
+                        id
+                    ));
+                }
+            }
+        };
+        let expected = match ty {
+            FanboxAttachmentType::Image => self.expected_image(),
+            FanboxAttachmentType::File => ExpectedContent::Any,
+        };
+        let (url, filename) =
+            get_download_spec(db, ty, id, self.by_creator, self.output_template.as_deref()).await?;
+        let downloaded = match self
+            .download_to(session, &url, &filename, expected, batch)
+            .await
+        {
+            Err(e) if is_expired_url(&e) => {
+                tracing::warn!("URL of {} seems to have expired, refreshing: {}", id, e);
+                refresh_post_urls(session, db, ty, id).await?;
+                let (url, filename) =
+                    get_download_spec(db, ty, id, self.by_creator, self.output_template.as_deref())
+                        .await?;
+                self.download_to(session, &url, &filename, expected, batch)
+                    .await
+            }
+            result => result,
         };
-        let updated = match self.r#type {
+        let (written_path, final_path, size, hash) = downloaded?;
+        let updated = match ty {
             FanboxAttachmentType::Image => {
                 let (width, height) = crate::util::get_image_dim(
-                    std::fs::File::open(&final_path)?,
+                    std::io::BufReader::new(std::fs::File::open(&final_path)?),
                     &final_path,
                     None,
                 )?;
                 db.update_fanbox_image_download(
-                    &id,
+                    id,
                     written_path.to_str().unwrap(),
                     width as i64,
                     height as i64,
+                    &hash,
                 )
                 .await?
             }
             FanboxAttachmentType::File => {
-                db.update_fanbox_file_download(&id, written_path.to_str().unwrap(), size as i64)
-                    .await?
+                db.update_fanbox_file_download(
+                    id,
+                    written_path.to_str().unwrap(),
+                    size as i64,
+                    &hash,
+                )
+                .await?
             }
         };
 
         assert!(
             updated,
             "{} {} should exist in database. Possible DB race",
-            match self.r#type {
+            match ty {
                 FanboxAttachmentType::File => "File",
                 FanboxAttachmentType::Image => "Image",
             },
             id
         );
 
+        Ok(ty)
+    }
+
+    async fn download_cover(
+        &self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+        id: &str,
+        batch: Option<&BatchProgress>,
+    ) -> anyhow::Result<()> {
+        let post_id: u64 = id
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid post ID {}", id))?;
+        let spec = db
+            .query_fanbox_cover_download_spec(post_id)
+            .await?
+            .ok_or_else(|| DownloadError::NotInDatabase(format!("Post {} (or its cover)", id)))?;
+        let ext = spec
+            .url
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.rsplit('/').next())
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, ext)| ext)
+            .ok_or_else(|| anyhow::anyhow!("Cannot infer extension of cover {}", spec.url))?;
+        let filename = with_creator_dir(
+            format!("{}_cover.{}", post_id, ext),
+            &spec.creator_dir,
+            self.by_creator,
+        );
+
+        let (written_path, _, _, _) = self
+            .download_to(session, &spec.url, &filename, self.expected_image(), batch)
+            .await?;
+        let updated = db
+            .update_fanbox_cover_download(post_id, written_path.to_str().unwrap())
+            .await?;
+        assert!(
+            updated,
+            "Post {} should exist in database. Possible DB race",
+            id
+        );
         Ok(())
     }
 
+    /// IDs of the items of `--post`, ordered by post, then by position within the post
+    async fn post_item_ids(&self, db: &crate::db::Database) -> anyhow::Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for &post in &self.post {
+            if matches!(self.r#type, FanboxDownloadType::Cover) {
+                ids.push(post.to_string());
+                continue;
+            }
+
+            let mut items = Vec::new();
+            if matches!(
+                self.r#type,
+                FanboxDownloadType::Image | FanboxDownloadType::Both
+            ) {
+                items.extend(db.query_fanbox_post_image_ids(post).await?);
+            }
+            if matches!(
+                self.r#type,
+                FanboxDownloadType::File | FanboxDownloadType::Both
+            ) {
+                items.extend(db.query_fanbox_post_file_ids(post).await?);
+            }
+            if items.is_empty() {
+                tracing::warn!("Post {} has no items to download", post);
+            }
+            items.sort_by_key(|(idx, _)| *idx);
+            ids.extend(items.into_iter().map(|(_, id)| id));
+        }
+        Ok(ids)
+    }
+
+    fn expected_image(&self) -> ExpectedContent {
+        if self.no_mime_check {
+            ExpectedContent::Any
+        } else {
+            ExpectedContent::Image
+        }
+    }
+
+    /// Download into the fanbox base directory. Returns (path for database, final path, size, hex-encoded SHA-256)
+    async fn download_to(
+        &self,
+        session: &crate::config::Session,
+        url: &str,
+        filename: &str,
+        expected: ExpectedContent,
+        batch: Option<&BatchProgress>,
+    ) -> anyhow::Result<(std::path::PathBuf, std::path::PathBuf, usize, String)> {
+        if let Some(dir) = std::path::Path::new(filename).parent() {
+            tokio::fs::create_dir_all(session.get_fanbox_base_dir()?.join(dir)).await?;
+        }
+        let DownloadResult::Written {
+            written_path,
+            final_path,
+            size,
+            digest,
+            ..
+        } = crate::util::download_then_persist(
+            FanboxRequest(session),
+            session.get_fanbox_base_dir()?,
+            filename,
+            self.database_path_format,
+            url,
+            expected,
+            DownloadOverwriteBehavior::Overwrite { old: None },
+            crate::data::file::progress_bar(self.progress, batch),
+        )
+        .await?
+        else {
+            unreachable!()
+        };
+        Ok((written_path, final_path, size, hex::encode(digest)))
+    }
+
     pub async fn run(
         self,
         session: &crate::config::Session,
@@ -290,27 +598,104 @@ impl FanboxDownloadArgs {
             tokio::fs::create_dir_all(session.get_fanbox_base_dir()?).await?;
         }
 
+        if let Some(ref template) = self.output_template {
+            // Fail on unknown placeholders before downloading anything
+            OutputTemplateValues::default().render(template, "")?;
+        }
+        crate::data::file::update_overlap_transfers(self.concurrency > 1);
+
+        for alias in &self.creator_alias {
+            db.set_fanbox_creator_alias(&alias.creator_id, &alias.alias)
+                .await?;
+        }
+
+        let ids = if self.post.is_empty() {
+            self.id.read()?
+        } else {
+            Box::new(self.post_item_ids(db).await?.into_iter().map(Ok))
+        };
+
+        let (min_len, max_len) = ids.size_hint();
+        let batch = BatchProgress::new(
+            self.progress,
+            (max_len == Some(min_len)).then_some(min_len as u64),
+        );
+        let batch = batch.as_ref();
+
+        // The same item downloaded concurrently would race on its file
+        let mut seen = std::collections::HashSet::new();
+        let ids = ids.filter(|id| match id {
+            Ok(id) => seen.insert(id.clone()),
+            Err(_) => true,
+        });
+        // Ongoing downloads still finish on Ctrl-C
+        let ids = ids.take_while(|_| !crate::interrupt::is_interrupted());
+
+        let this = &self;
+        let mut results = futures::stream::iter(ids)
+            .map(|id| async move {
+                let id = id?;
+                // None for covers
+                let result = match this.r#type {
+                    FanboxDownloadType::Cover => this
+                        .download_cover(session, db, &id, batch)
+                        .await
+                        .map(|()| None),
+                    _ => this
+                        .download_single(session, db, &id, batch)
+                        .await
+                        .map(Some),
+                };
+                Ok::<_, anyhow::Error>((id, result))
+            })
+            .buffer_unordered(self.concurrency as usize);
+
         let mut collected_errs = Vec::new();
-        for id in self.id.read()? {
-            let id = id?;
-            if let Err(e) = self.download_single(session, db, &id).await {
-                if self.abort_on_fail {
-                    return Err(e);
-                } else {
-                    tracing::error!("Failed to download {}: {:?}", id, e);
-                    collected_errs.push((id, e));
+        let (mut image_cnt, mut file_cnt, mut cover_cnt) = (0, 0, 0);
+        while let Some(result) = results.next().await {
+            let (id, result) = result?;
+            if let Some(batch) = batch {
+                batch.inc();
+            }
+            match result {
+                Ok(Some(FanboxAttachmentType::Image)) => {
+                    tracing::info!("Downloaded image {}", id);
+                    image_cnt += 1
+                }
+                Ok(Some(FanboxAttachmentType::File)) => {
+                    tracing::info!("Downloaded file {}", id);
+                    file_cnt += 1
+                }
+                Ok(None) => {
+                    tracing::info!("Downloaded cover of post {}", id);
+                    cover_cnt += 1
+                }
+                Err(e) => {
+                    if self.abort_on_fail {
+                        return Err(e);
+                    } else {
+                        tracing::error!("Failed to download {}: {:?}", id, e);
+                        collected_errs.push((id, e));
+                    }
                 }
             };
         }
+        if let Some(batch) = batch {
+            batch.finish();
+        }
+        if matches!(self.r#type, FanboxDownloadType::Cover) {
+            tracing::info!("Downloaded {} cover(s)", cover_cnt);
+        } else {
+            tracing::info!("Downloaded {} image(s) and {} file(s)", image_cnt, file_cnt);
+        }
 
         if collected_errs.is_empty() {
             Ok(())
         } else {
-            // TODO: use thiserror
-            Err(anyhow::anyhow!(
-                "{} errors occurred during download",
-                collected_errs.len()
-            ))
+            Err(DownloadFailures {
+                failures: collected_errs,
+            }
+            .into())
         }
     }
 }
@@ -324,6 +709,28 @@ pub enum QueryOrder {
     PostDesc,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum Format {
+    /// Count only
+    Count,
+
+    /// ID only
+    ID,
+
+    /// Output in JSON
+    Json,
+}
+
+impl From<Format> for crate::util::RowsFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Count => crate::util::RowsFormat::Count,
+            Format::ID => crate::util::RowsFormat::ID,
+            Format::Json => crate::util::RowsFormat::Json,
+        }
+    }
+}
+
 #[derive(Args)]
 pub struct FanboxAttachmentArgs {
     /// Type of the queried item
@@ -337,14 +744,32 @@ pub struct FanboxAttachmentArgs {
     #[arg(short, long)]
     post: Option<u64>,
 
+    /// Tag of the related post, can appear multiple times to specify multiple tags (AND)
+    #[arg(short, long)]
+    tag: Vec<String>,
+
     /// Specify the download state
     #[arg(short, long)]
     downloaded: Option<bool>,
 
+    /// Only items downloaded at or after this time.
+    ///
+    /// Accepts RFC 3339 (e.g. `2024-01-31T12:00:00+09:00`), or `YYYY-MM-DD HH:MM:SS` / `YYYY-MM-DD` in UTC.
+    #[arg(long, value_parser = crate::util::parse_cli_datetime)]
+    downloaded_after: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only items downloaded at or before this time. Same format as `--downloaded-after`, a date alone includes that whole day.
+    #[arg(long, value_parser = crate::util::parse_cli_datetime_end)]
+    downloaded_before: Option<chrono::DateTime<chrono::Utc>>,
+
     /// Ordering of the returned ids
     #[arg(short, long, value_enum, default_value_t = QueryOrder::PostDesc)]
     order: QueryOrder,
 
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::ID)]
+    format: Format,
+
     /// Print SQL query
     #[arg(long)]
     print_sql: bool,
@@ -366,15 +791,47 @@ impl FanboxAttachmentArgs {
             FanboxAttachmentType::Image => "fanbox_images",
         };
 
-        // TODO: output format
-        let mut sql = format!("SELECT id from {}", tbl);
+        let mut sql = format!(
+            "SELECT {} from {}",
+            match self.format {
+                Format::Count => "COUNT(*) as count",
+                Format::ID => "id",
+                Format::Json => "*",
+            },
+            tbl
+        );
         let mut wheres = Vec::new();
+        let mut params = Vec::new();
 
         if let Some(ref id) = self.id {
-            wheres.push(format!("id = '{}'", id));
+            wheres.push("id = ?".to_string());
+            params.push(QueryParam::Text(id.clone()));
         }
         if let Some(post) = self.post {
-            wheres.push(format!("post_id = {}", post));
+            wheres.push("post_id = ?".to_string());
+            params.push(QueryParam::Int(post as i64));
+        }
+        if !self.tag.is_empty() {
+            // Same as tags in `pixivdwn query`: no requested tag may be missing from the post, and all of them must exist
+            let tags = serde_json::to_string(&self.tag)?;
+            wheres.push(format!(
+                r#"NOT EXISTS (
+                  SELECT id FROM tags
+                  WHERE tag IN (SELECT json_each.value FROM json_each(?))
+                  AND id NOT IN (
+                    SELECT tag_id FROM fanbox_post_tags WHERE fanbox_post_tags.post_id = {}.post_id
+                  )
+                )"#,
+                tbl
+            ));
+            params.push(QueryParam::Text(tags.clone()));
+
+            wheres.push(
+                "(SELECT COUNT(*) FROM tags WHERE tag IN (SELECT json_each.value FROM json_each(?))) = ?"
+                    .to_string(),
+            );
+            params.push(QueryParam::Text(tags));
+            params.push(QueryParam::Int(self.tag.len() as i64));
         }
         if let Some(downloaded) = self.downloaded {
             let predicate = if downloaded {
@@ -382,7 +839,18 @@ impl FanboxAttachmentArgs {
             } else {
                 "path IS NULL"
             };
-            wheres.push(predicate.to_owned())
+            wheres.push(predicate.to_string())
+        }
+        for (pred, bound) in [
+            ("downloaded_at >= datetime(?)", self.downloaded_after),
+            ("downloaded_at <= datetime(?)", self.downloaded_before),
+        ] {
+            if let Some(bound) = bound {
+                wheres.push(pred.to_string());
+                params.push(QueryParam::Text(
+                    bound.format("%Y-%m-%d %H:%M:%S").to_string(),
+                ));
+            }
         }
 
         if !wheres.is_empty() {
@@ -390,31 +858,436 @@ impl FanboxAttachmentArgs {
             sql.push_str(&wheres.join(" AND "));
         }
 
-        match self.order {
-            QueryOrder::PostAsc => sql.push_str(" ORDER BY post_id ASC, idx ASC"),
-            QueryOrder::PostDesc => sql.push_str(" ORDER BY post_id DESC, idx ASC"),
+        if !matches!(self.format, Format::Count) {
+            match self.order {
+                QueryOrder::PostAsc => sql.push_str(" ORDER BY post_id ASC, idx ASC"),
+                QueryOrder::PostDesc => sql.push_str(" ORDER BY post_id DESC, idx ASC"),
+            }
         }
 
         if self.print_sql {
-            println!("{}", sql);
+            crate::util::print_sql(&sql, &params);
         }
 
         if self.dry_run {
             return Ok(());
         }
 
-        let result = db.query_raw(&sql).await?;
-        use sqlx::Row;
+        let result = db.query_raw(&sql, &params).await?;
+        crate::util::print_query_rows::<String>(result, self.format.into())?;
+
+        Ok(())
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum PostQueryOrder {
+    /// Order by post ID, ascending
+    IdAsc,
+
+    /// Order by post ID, descending
+    IdDesc,
+
+    /// Order by publish date, ascending
+    PublishedAsc,
+
+    /// Order by publish date, descending
+    PublishedDesc,
+}
+
+#[derive(Args)]
+pub struct FanboxQueryArgs {
+    /// ID of some specific post
+    id: Option<u64>,
+
+    /// Creator ID
+    #[arg(short, long)]
+    creator: Option<String>,
+
+    /// Tag, can appear multiple times to specify multiple tags (AND)
+    #[arg(short, long)]
+    tag: Vec<String>,
+
+    /// Whether the post is marked as adult content. Posts synced by older versions have it unknown, and match neither.
+    #[arg(long)]
+    adult: Option<bool>,
+
+    /// Only posts requiring at least this fee (JPY). Free posts have a fee of 0.
+    #[arg(long)]
+    min_fee: Option<u64>,
+
+    /// Only posts requiring at most this fee (JPY)
+    #[arg(long)]
+    max_fee: Option<u64>,
+
+    /// Whether the post body is in the rich format. Posts with inaccessible bodies match neither.
+    #[arg(long)]
+    rich: Option<bool>,
+
+    /// Whether the post body is accessible. Bodies are inaccessible if the post requires a higher fee than supported.
+    #[arg(long)]
+    accessible: Option<bool>,
+
+    /// Only posts whose body was inaccessible when synced, same as `--accessible false`
+    #[arg(long, conflicts_with = "accessible")]
+    restricted: bool,
+
+    /// Only posts whose title or body contains this text, case-insensitively
+    ///
+    /// Rich bodies are searched by their paragraphs and headers. Uses the full-text index for 3 or more characters.
+    #[arg(short, long)]
+    search: Option<String>,
+
+    /// Only posts published at or after this time.
+    ///
+    /// Accepts RFC 3339 (e.g. `2024-01-31T12:00:00+09:00`), or `YYYY-MM-DD HH:MM:SS` / `YYYY-MM-DD` in UTC.
+    #[arg(long, value_parser = crate::util::parse_cli_datetime)]
+    published_after: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only posts published at or before this time. Same format as `--published-after`, a date alone includes that whole day.
+    #[arg(long, value_parser = crate::util::parse_cli_datetime_end)]
+    published_before: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only posts updated at or after this time. Same format as `--published-after`.
+    #[arg(long, value_parser = crate::util::parse_cli_datetime)]
+    updated_after: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only posts updated at or before this time. Same format as `--published-after`, a date alone includes that whole day.
+    #[arg(long, value_parser = crate::util::parse_cli_datetime_end)]
+    updated_before: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Ordering
+    #[arg(short, long, value_enum, default_value_t = PostQueryOrder::IdDesc)]
+    order: PostQueryOrder,
+
+    /// Limit
+    #[arg(short, long)]
+    limit: Option<usize>,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::ID)]
+    format: Format,
+
+    /// Print SQL query
+    #[arg(long)]
+    print_sql: bool,
+
+    /// Dry-run
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl FanboxQueryArgs {
+    pub async fn run(&self, db: &crate::db::Database) -> anyhow::Result<()> {
+        // Same SQL concat as `pixivdwn query`
+        let mut sql = format!(
+            "SELECT {} FROM fanbox_posts",
+            match self.format {
+                Format::Count => "COUNT(*) as count",
+                Format::ID => "id",
+                Format::Json => "*",
+            }
+        );
+        let mut wheres = Vec::new();
+        let mut params = Vec::new();
+
+        if let Some(id) = self.id {
+            wheres.push("id = ?".to_string());
+            params.push(QueryParam::Int(id as i64));
+        }
+        if let Some(ref creator) = self.creator {
+            wheres.push("creator_id = ?".to_string());
+            params.push(QueryParam::Text(creator.clone()));
+        }
+        if !self.tag.is_empty() {
+            let tags = serde_json::to_string(&self.tag)?;
+            wheres.push(
+                r#"NOT EXISTS (
+                  SELECT id FROM tags
+                  WHERE tag IN (SELECT json_each.value FROM json_each(?))
+                  AND id NOT IN (
+                    SELECT tag_id FROM fanbox_post_tags WHERE post_id = fanbox_posts.id
+                  )
+                )"#
+                .to_string(),
+            );
+            params.push(QueryParam::Text(tags.clone()));
+
+            wheres.push(
+                "(SELECT COUNT(*) FROM tags WHERE tag IN (SELECT json_each.value FROM json_each(?))) = ?"
+                    .to_string(),
+            );
+            params.push(QueryParam::Text(tags));
+            params.push(QueryParam::Int(self.tag.len() as i64));
+        }
+        if let Some(ref search) = self.search {
+            wheres.push(crate::util::search_condition(
+                "fanbox_posts",
+                &["title", "body_text"],
+                search,
+                &mut params,
+            ));
+        }
+        for (col, value) in [
+            ("has_adult_content", self.adult),
+            ("is_body_rich", self.rich),
+        ] {
+            if let Some(value) = value {
+                wheres.push(format!("{} = ?", col));
+                params.push(QueryParam::Int(value as i64));
+            }
+        }
+        let accessible = if self.restricted {
+            Some(false)
+        } else {
+            self.accessible
+        };
+        if let Some(accessible) = accessible {
+            wheres.push(if accessible {
+                "NOT is_restricted".to_string()
+            } else {
+                "is_restricted".to_string()
+            });
+        }
+        for (op, bound) in [(">=", self.min_fee), ("<=", self.max_fee)] {
+            if let Some(bound) = bound {
+                wheres.push(format!("fee {} ?", op));
+                params.push(QueryParam::Int(bound as i64));
+            }
+        }
+
+        // Stored dates are already in UTC
+        for (col, op, bound) in [
+            ("published_datetime", ">=", self.published_after),
+            ("published_datetime", "<=", self.published_before),
+            ("updated_datetime", ">=", self.updated_after),
+            ("updated_datetime", "<=", self.updated_before),
+        ] {
+            if let Some(bound) = bound {
+                wheres.push(format!("{} {} datetime(?)", col, op));
+                params.push(QueryParam::Text(
+                    bound.format("%Y-%m-%d %H:%M:%S").to_string(),
+                ));
+            }
+        }
+
+        if !wheres.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&wheres.join(" AND "));
+        }
+
+        if !matches!(self.format, Format::Count) {
+            sql.push_str(" ORDER BY ");
+            match self.order {
+                PostQueryOrder::IdAsc => sql.push_str("id ASC"),
+                PostQueryOrder::IdDesc => sql.push_str("id DESC"),
+                PostQueryOrder::PublishedAsc => sql.push_str("published_datetime ASC, id ASC"),
+                PostQueryOrder::PublishedDesc => sql.push_str("published_datetime DESC, id DESC"),
+            }
 
-        for row in result {
-            let id: &str = row.try_get("id")?;
-            println!("{}", id);
+            if let Some(limit) = self.limit {
+                sql.push_str(" LIMIT ?");
+                params.push(QueryParam::Int(limit as i64));
+            }
+        }
+
+        if self.print_sql {
+            crate::util::print_sql(&sql, &params);
         }
 
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let result = db.query_raw(&sql, &params).await?;
+        crate::util::print_query_rows::<i64>(result, self.format.into())?;
+
         Ok(())
     }
 }
 
+#[derive(Args)]
+pub struct FanboxResyncRestrictedArgs {
+    /// Only resync posts from this creator
+    #[arg(short, long)]
+    creator: Option<String>,
+
+    /// Skip failed posts instead of aborting. Still fails at the end if any post failed.
+    #[arg(long)]
+    skip_failed: bool,
+
+    #[command(flatten)]
+    body_storage: BodyStorageArgs,
+}
+
+impl FanboxResyncRestrictedArgs {
+    pub async fn run(
+        &self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+    ) -> anyhow::Result<()> {
+        let ids = db
+            .query_restricted_fanbox_post_ids(self.creator.as_deref())
+            .await?;
+        tracing::info!("Found {} restricted posts", ids.len());
+
+        let (mut accessible, mut restricted, mut failed) = (0, 0, 0);
+        for id in ids {
+            if crate::interrupt::is_interrupted() {
+                break;
+            }
+            let detail = crate::retry::with_retries(
+                crate::retry::RetryPolicy::global(),
+                format!("post {}", id),
+                || crate::data::fanbox::fetch_post(session, id),
+            )
+            .await;
+            let mut detail = match detail {
+                Ok(detail) => detail,
+                Err(e) if self.skip_failed => {
+                    tracing::error!("Failed to resync post {}: {:?}", id, e);
+                    failed += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if detail.body.is_none() {
+                tracing::info!(
+                    "Post {} - {} is still restricted, skipping",
+                    id,
+                    detail.post.title
+                );
+                restricted += 1;
+                continue;
+            }
+            store_post(db, &mut detail, self.body_storage.storage()).await?;
+            accessible += 1;
+        }
+
+        tracing::info!(
+            "{} posts became accessible, {} are still restricted, {} failed",
+            accessible,
+            restricted,
+            failed
+        );
+        if failed == 0 {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("{} post(s) failed to resync", failed))
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct FanboxPruneArgs {
+    /// Only prune posts from this creator
+    #[arg(short, long)]
+    creator: Option<String>,
+
+    /// Only prune this post
+    #[arg(short, long, conflicts_with = "creator")]
+    post: Option<u64>,
+
+    /// Use the stored bodies instead of refetching the posts.
+    ///
+    /// Only rich bodies list their images and files, posts in other formats or stored without a body are skipped.
+    #[arg(long)]
+    offline: bool,
+
+    /// Only print the images and files that would be deleted
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl FanboxPruneArgs {
+    pub async fn run(
+        &self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+    ) -> anyhow::Result<()> {
+        let ids = match self.post {
+            Some(post) => vec![post],
+            None => db.query_fanbox_post_ids(self.creator.as_deref()).await?,
+        };
+
+        let mut pruned = 0;
+        let mut errored = 0;
+        for id in ids {
+            match self.prune_post(session, db, id).await {
+                Ok(cnt) => pruned += cnt,
+                Err(e) => {
+                    tracing::error!("Failed to prune post {}: {:?}", id, e);
+                    errored += 1;
+                }
+            }
+        }
+
+        tracing::info!(
+            "{} {} images and files",
+            if self.dry_run {
+                "Would prune"
+            } else {
+                "Pruned"
+            },
+            pruned
+        );
+        if errored == 0 {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("{} post(s) failed to prune", errored))
+        }
+    }
+
+    /// Returns the number of pruned images and files
+    async fn prune_post(
+        &self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+        id: u64,
+    ) -> anyhow::Result<usize> {
+        let (images, files) = if self.offline {
+            let Some(body) = db.query_fanbox_post_rich_body(id).await? else {
+                tracing::debug!(
+                    "Skipping post {}: no stored rich body, or stored as plain text",
+                    id
+                );
+                return Ok(0);
+            };
+            let blocks: Vec<crate::data::fanbox::FetchPostBlock> = serde_json::from_str(&body)?;
+            crate::data::fanbox::FetchPostBlock::referenced_ids(&blocks)
+        } else {
+            let mut detail = crate::retry::with_retries(
+                crate::retry::RetryPolicy::global(),
+                format!("post {}", id),
+                || crate::data::fanbox::fetch_post(session, id),
+            )
+            .await?;
+            let Some(ref mut body) = detail.body else {
+                tracing::debug!("Skipping post {}: body is inaccessible", id);
+                return Ok(0);
+            };
+            let images = body.images().map(|(_, i)| i.id.clone()).collect();
+            let files = body.files().map(|(_, f)| f.id.clone()).collect();
+            (images, files)
+        };
+
+        let pruned_images = db.prune_fanbox_images(id, &images, self.dry_run).await?;
+        let pruned_files = db.prune_fanbox_files(id, &files, self.dry_run).await?;
+        let prompt = if self.dry_run {
+            "Would prune"
+        } else {
+            "Pruned"
+        };
+        for image in &pruned_images {
+            tracing::info!("{} post {}: image {}", prompt, id, image);
+        }
+        for file in &pruned_files {
+            tracing::info!("{} post {}: file {}", prompt, id, file);
+        }
+        Ok(pruned_images.len() + pruned_files.len())
+    }
+}
+
 #[derive(Subcommand)]
 pub enum FanboxCmd {
     /// Synchronize a specific post, posts from Fanbox creator, or all supported creators
@@ -425,6 +1298,15 @@ pub enum FanboxCmd {
 
     /// Attachment query
     Attachment(FanboxAttachmentArgs),
+
+    /// Post query
+    Query(FanboxQueryArgs),
+
+    /// Delete images and files that are neither downloaded nor referenced by the latest post bodies
+    Prune(FanboxPruneArgs),
+
+    /// Sync again the posts whose body was inaccessible, e.g. after upgrading a supporting plan
+    ResyncRestricted(FanboxResyncRestrictedArgs),
 }
 
 impl Fanbox {
@@ -437,36 +1319,169 @@ impl Fanbox {
             FanboxCmd::Sync(sync) => sync.run(session, db).await?,
             FanboxCmd::Download(dwn) => dwn.run(session, db).await?,
             FanboxCmd::Attachment(file) => file.run(session, db).await?,
+            FanboxCmd::Query(query) => query.run(db).await?,
+            FanboxCmd::Prune(prune) => prune.run(session, db).await?,
+            FanboxCmd::ResyncRestricted(resync) => resync.run(session, db).await?,
         }
         Ok(())
     }
 }
 
+/// Signed CDN URLs are rejected with 403 (or 410) once they expire
+fn is_expired_url(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<DownloadError>(),
+        Some(DownloadError::Http { status, .. })
+            if matches!(*status, wreq::StatusCode::FORBIDDEN | wreq::StatusCode::GONE)
+    )
+}
+
+/// Fetch the post of an image or file again, and store the fresh URLs of all its images and files
+async fn refresh_post_urls(
+    session: &crate::config::Session,
+    db: &crate::db::Database,
+    ty: FanboxAttachmentType,
+    id: &str,
+) -> anyhow::Result<()> {
+    let post_id = match ty {
+        FanboxAttachmentType::File => db
+            .query_fanbox_file_download_spec(id)
+            .await?
+            .map(|spec| spec.post_id as u64),
+        FanboxAttachmentType::Image => db
+            .query_fanbox_image_download_spec(id)
+            .await?
+            .map(|spec| spec.post_id as u64),
+    }
+    .ok_or_else(|| DownloadError::NotInDatabase(id.to_owned()))?;
+
+    let mut detail = crate::retry::with_retries(
+        crate::retry::RetryPolicy::global(),
+        format!("post {}", post_id),
+        || crate::data::fanbox::fetch_post(session, post_id),
+    )
+    .await?;
+    let Some(ref mut body) = detail.body else {
+        return Err(anyhow::anyhow!(
+            "Post {} is no longer accessible, cannot refresh the URL of {}",
+            post_id,
+            id
+        ));
+    };
+
+    let mut refreshed = 0;
+    for (_, file) in body.files() {
+        if db.update_fanbox_file_url(&file.id, &file.url).await? {
+            refreshed += 1;
+        }
+    }
+    for (_, image) in body.images() {
+        if db
+            .update_fanbox_image_url(&image.id, &image.original_url)
+            .await?
+        {
+            refreshed += 1;
+        }
+    }
+    tracing::info!("Refreshed {} URL(s) of post {}", refreshed, post_id);
+    Ok(())
+}
+
 /// Return (url, filename)
+///
+/// `template` replaces the default `<post_id>_<idx>_<id>[_<name>].<ext>`, see `fanbox download --output-template`.
 pub async fn get_download_spec(
     db: &crate::db::Database,
     ty: FanboxAttachmentType,
     id: &str,
+    by_creator: bool,
+    template: Option<&str>,
 ) -> anyhow::Result<(String, String)> {
-    match ty {
+    let (url, default_name, values) = match ty {
         FanboxAttachmentType::File => {
             let spec = db
                 .query_fanbox_file_download_spec(id)
                 .await?
-                .ok_or_else(|| anyhow::anyhow!("File {} not found in database", id))?;
+                .ok_or_else(|| DownloadError::NotInDatabase(format!("File {}", id)))?;
             let filename: String = format!(
                 "{}_{}_{}_{}.{}",
                 spec.post_id, spec.idx, id, spec.name, spec.ext
             );
-            Ok((spec.url, filename))
+            let values = OutputTemplateValues {
+                post_id: spec.post_id,
+                idx: spec.idx,
+                name: Some(spec.name),
+                ext: spec.ext,
+                title: spec.post_title,
+                creator_id: spec.creator_id,
+                creator: spec.creator_dir,
+            };
+            (spec.url, filename, values)
         }
         FanboxAttachmentType::Image => {
             let spec = db
                 .query_fanbox_image_download_spec(id)
                 .await?
-                .ok_or_else(|| anyhow::anyhow!("Image {} not found in database", id))?;
+                .ok_or_else(|| DownloadError::NotInDatabase(format!("Image {}", id)))?;
             let filename = format!("{}_{}_{}.{}", spec.post_id, spec.idx, id, spec.ext);
-            Ok((spec.url, filename))
+            let values = OutputTemplateValues {
+                post_id: spec.post_id,
+                idx: spec.idx,
+                name: None,
+                ext: spec.ext,
+                title: spec.post_title,
+                creator_id: spec.creator_id,
+                creator: spec.creator_dir,
+            };
+            (spec.url, filename, values)
         }
+    };
+    let filename = match template {
+        Some(template) => values
+            .render(template, id)?
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Failed to convert path to UTF-8"))?
+            .to_owned(),
+        None => default_name,
+    };
+    Ok((url, with_creator_dir(filename, &values.creator, by_creator)))
+}
+
+/// Values of the placeholders in `fanbox download --output-template`
+#[derive(Default)]
+struct OutputTemplateValues {
+    post_id: i64,
+    idx: i64,
+    name: Option<String>,
+    ext: String,
+    title: String,
+    creator_id: String,
+    /// `<alias> (<creator_id>)`, or creator ID if not aliased
+    creator: String,
+}
+
+impl OutputTemplateValues {
+    fn render(&self, template: &str, id: &str) -> anyhow::Result<std::path::PathBuf> {
+        crate::util::render_path_template(template, |placeholder| {
+            Some(match placeholder {
+                "post_id" => Some(self.post_id.to_string()),
+                "idx" => Some(self.idx.to_string()),
+                "id" => Some(id.to_owned()),
+                "name" => self.name.clone(),
+                "ext" => Some(self.ext.clone()),
+                "title" => Some(self.title.clone()),
+                "creator_id" => Some(self.creator_id.clone()),
+                "creator" => Some(self.creator.clone()),
+                _ => return None,
+            })
+        })
+    }
+}
+
+fn with_creator_dir(filename: String, creator_dir: &str, by_creator: bool) -> String {
+    if by_creator {
+        format!("{}/{}", creator_dir, filename)
+    } else {
+        filename
     }
 }
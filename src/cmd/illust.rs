@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use clap::Args;
 
-use crate::util::DownloadIdSrc;
+use crate::{data::pixiv::IllustError, util::DownloadIdSrc};
 
 #[derive(Args)]
 pub struct Illust {
@@ -10,6 +10,16 @@ pub struct Illust {
     /// ID of the illustration
     id: DownloadIdSrc<u64>,
 
+    /// Instead of given IDs, sync all stored illustrations that were never synced by ID, newest first
+    ///
+    /// These are usually synced from bookmarks, and lack their description, series and stats. Masked and unlisted illustrations are left out.
+    #[arg(long, group = "DownloadIdSrc")]
+    only_missing_detail: bool,
+
+    /// Sync at most this many illustrations with `--only-missing-detail`
+    #[arg(long, conflicts_with_all = ["id", "list"])]
+    limit: Option<u64>,
+
     /// Dry run, only fech and print the info
     #[arg(long)]
     dry_run: bool,
@@ -25,25 +35,46 @@ impl Illust {
         session: &crate::config::Session,
         db: &crate::db::Database,
     ) -> anyhow::Result<()> {
-        let mut errored = 0;
-        for id in self.id.read()? {
+        let ids = if self.only_missing_detail {
+            let ids = db.query_illusts_missing_detail(self.limit).await?;
+            tracing::info!("Found {} illustrations without details", ids.len());
+            Box::new(ids.into_iter().map(Ok))
+        } else {
+            self.id.read()?
+        };
+
+        let (mut errored, mut restricted) = (0, 0);
+        for id in ids {
+            if crate::interrupt::is_interrupted() {
+                break;
+            }
             let id = id?;
             let ret = self.sync_single(session, db, id).await;
-            if ret.is_err() {
+            if let Err(e) = ret {
                 if self.abort_on_fail {
-                    return ret;
-                } else {
-                    tracing::error!("Failed to sync illust {}: {:?}", id, ret.err());
-                    errored += 1;
+                    return Err(e);
+                }
+                match e.downcast_ref::<IllustError>() {
+                    Some(e @ IllustError::AgeRestricted { .. }) => {
+                        tracing::warn!("{}", e);
+                        restricted += 1;
+                    }
+                    _ => tracing::error!("Failed to sync illust {}: {:?}", id, e),
                 }
-            } else {
+                errored += 1;
             }
         }
 
         if errored == 0 {
             Ok(())
+        } else if restricted > 0 {
+            Err(anyhow::anyhow!(
+                "{} illust(s) failed to sync, {} of them age-restricted",
+                errored,
+                restricted
+            ))
         } else {
-            return Err(anyhow::anyhow!("{} illust(s) failed to sync", errored));
+            Err(anyhow::anyhow!("{} illust(s) failed to sync", errored))
         }
     }
 
@@ -53,17 +84,17 @@ impl Illust {
         db: &crate::db::Database,
         id: u64,
     ) -> anyhow::Result<()> {
-        let illust = crate::data::pixiv::get_illust(session, id).await?;
+        let illust = crate::retry::with_retries(
+            crate::retry::RetryPolicy::global(),
+            format!("illust {}", id),
+            || crate::data::pixiv::get_illust(session, id),
+        )
+        .await?;
 
         if !self.dry_run {
             let mut tag_map_ctx: HashMap<String, u64> = HashMap::new();
             let update_result = db.update_illust(&illust, &mut tag_map_ctx).await?;
-            let update_prompt = match update_result {
-                crate::db::IllustUpdateResult::Inserted => "INSERTED",
-                crate::db::IllustUpdateResult::BookmarkIDChanged => "BMIDCHANGED",
-                crate::db::IllustUpdateResult::Updated => "UPDATED",
-                crate::db::IllustUpdateResult::Skipped => "SKIPPED",
-            };
+            let update_prompt = update_result.label();
             tracing::info!(
                 "Synced {}: [{}] {}",
                 illust.id,
@@ -1,9 +1,13 @@
 pub mod bookmarks;
+pub mod convert;
 pub mod database;
 pub mod download;
 pub mod fanbox;
 pub mod illust;
+pub mod novels;
 pub mod query;
+pub mod ranking;
+pub mod user;
 
 use clap::Subcommand;
 
@@ -12,16 +16,29 @@ pub enum Command {
     /// Sync bookmarks into database
     Bookmarks(bookmarks::Bookmarks),
 
+    /// Sync novel bookmarks into database
+    Novels(novels::Novels),
+
     /// Sync individual illustration by ID
     Illust(illust::Illust),
 
+    /// Sync all illustrations by a user
+    User(user::User),
+
+    /// Sync illustrations from a daily, weekly or monthly ranking
+    Ranking(ranking::Ranking),
+
     /// Download individual illustration by ID
     Download(download::Download),
 
+    /// Convert downloaded ugoira into animations
+    Convert(convert::Convert),
+
     /// Fanbox fetching
     Fanbox(fanbox::Fanbox),
 
     /// Query local database
+    #[command(args_conflicts_with_subcommands = true)]
     Query(query::Query),
 
     /// Database management
@@ -29,6 +46,14 @@ pub enum Command {
 }
 
 impl Command {
+    /// Parse arguments that are passed on to another command, e.g. `download --from-query`
+    pub fn parse_nested(&mut self) -> Result<(), clap::Error> {
+        match self {
+            Command::Download(cmd) => cmd.parse_from_query(),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn run(self, session: &crate::config::Session, dburl: &str) -> anyhow::Result<()> {
         if let Command::Database(cmd) = self {
             return cmd.run(session, dburl).await;
@@ -36,13 +61,18 @@ impl Command {
 
         let db = crate::db::Database::load(dburl).await?;
 
-        match self {
+        let ret = match self {
             Command::Bookmarks(cmd) => cmd.run(session, &db).await,
+            Command::Novels(cmd) => cmd.run(session, &db).await,
             Command::Illust(cmd) => cmd.run(session, &db).await,
+            Command::User(cmd) => cmd.run(session, &db).await,
+            Command::Ranking(cmd) => cmd.run(session, &db).await,
             Command::Download(cmd) => cmd.run(session, &db).await,
+            Command::Convert(cmd) => cmd.run(session, &db).await,
             Command::Fanbox(cmd) => cmd.run(session, &db).await,
             Command::Query(cmd) => cmd.run(&db).await,
             Command::Database(_) => unreachable!(),
-        }
+        };
+        crate::db::close_after(db, ret).await
     }
 }
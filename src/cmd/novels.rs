@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use futures::{StreamExt, pin_mut};
+
+use crate::{db::NovelUpdateResult, util::TerminationCondition};
+
+#[derive(Args)]
+pub struct Novels {
+    #[arg(short, long)]
+    /// Bookmark tag
+    tag: Option<String>,
+
+    #[arg(long, default_value = "0")]
+    /// Initial offset
+    offset: usize,
+
+    #[arg(long)]
+    /// Maximum number of fetched novels
+    max_cnt: Option<usize>,
+
+    #[arg(short, long)]
+    /// Fetch private bookmarks
+    private: bool,
+
+    #[arg(alias="term", long, value_enum, default_value_t = TerminationCondition::UntilEnd)]
+    /// Termination condition (alias: --term)
+    termination: TerminationCondition,
+
+    #[arg(long)]
+    /// Only sync metadata, without fetching the text of novels
+    ///
+    /// Novels without text get it on the next sync without this flag.
+    skip_content: bool,
+}
+
+impl Novels {
+    pub async fn run(
+        self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+    ) -> anyhow::Result<()> {
+        let novels = crate::data::pixiv::get_novel_bookmarks(
+            session,
+            self.tag.as_deref(),
+            self.offset,
+            self.private,
+        )
+        .await;
+        pin_mut!(novels);
+        let mut tag_map_ctx: HashMap<String, u64> = HashMap::new();
+        let mut cnt = 0;
+        while let Some(novel) = novels.next().await {
+            if crate::interrupt::is_interrupted() {
+                break;
+            }
+            let novel = novel?;
+            let update_result = db.update_novel(&novel, &mut tag_map_ctx).await?;
+            let update_prompt = match update_result {
+                NovelUpdateResult::Inserted => "INSERTED",
+                NovelUpdateResult::Updated => "UPDATED",
+                NovelUpdateResult::Unchanged => "UNCHANGED",
+                NovelUpdateResult::Skipped => "SKIPPED",
+            };
+            tracing::info!(
+                "Queried novel {}: [{}] {}",
+                novel.id,
+                update_prompt,
+                novel.display_title()
+            );
+
+            if !self.skip_content
+                && update_result != NovelUpdateResult::Skipped
+                && db.novel_missing_content(novel.id).await?
+            {
+                let content = crate::retry::with_retries(
+                    crate::retry::RetryPolicy::global(),
+                    format!("novel {}", novel.id),
+                    || crate::data::pixiv::get_novel_content(session, novel.id),
+                )
+                .await?;
+                db.set_novel_content(novel.id, &content).await?;
+                tracing::info!(
+                    "Fetched text of novel {} ({} characters)",
+                    novel.id,
+                    content.chars().count()
+                );
+            }
+
+            if self.termination == TerminationCondition::OnHit
+                && matches!(
+                    update_result,
+                    NovelUpdateResult::Unchanged | NovelUpdateResult::Skipped
+                )
+            {
+                tracing::info!(
+                    "Encountered an already existing novel, which is unchanged. Terminating."
+                );
+                break;
+            }
+
+            cnt += 1;
+            if let Some(max_count) = self.max_cnt
+                && cnt >= max_count
+            {
+                tracing::info!(
+                    "Reached the maximum number of fetched novels ({}). Terminating.",
+                    max_count
+                );
+                break;
+            }
+        }
+        Ok(())
+    }
+}
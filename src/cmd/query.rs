@@ -1,6 +1,14 @@
 use clap::Args;
 
-use crate::{data::pixiv::IllustState, util::db_row_to_json};
+use crate::{
+    data::pixiv::{IllustState, IllustType, XRestrict},
+    db::QueryParam,
+    util::{RowsFormat, parse_cli_datetime, parse_cli_datetime_end, print_query_rows, print_sql},
+};
+
+/// Number of files an illustration is expected to have once fully downloaded.
+/// An ugoira is stored as a single zip at page 0, regardless of its reported page count.
+const EXPECTED_FILE_COUNT: &str = "(CASE WHEN illust_type = 2 THEN 1 ELSE page_count END)";
 
 #[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
 pub enum QueryDownloadState {
@@ -30,11 +38,29 @@ pub enum QueryOrder {
     /// Order by illustration ID, descending
     IdDesc,
 
-    /// Order by bookmark ID, ascending
+    /// Order by bookmark ID, ascending (oldest bookmark first)
+    ///
+    /// Bookmark IDs are shared by public and private bookmarks. Illustrations without a bookmark come last.
     BookmarkIdAsc,
 
-    /// Order by bookmark ID, descending
+    /// Order by bookmark ID, descending (most recently bookmarked first)
+    ///
+    /// Illustrations without a bookmark come last.
     BookmarkIdDesc,
+
+    /// Order by series, then by position within the series (reading order)
+    SeriesOrder,
+
+    /// Order by the number of bookmarks on pixiv, descending
+    ///
+    /// Only known for illustrations synced by ID (`illust`, `user` or `ranking`), others come last.
+    BookmarkCountDesc,
+
+    /// Order by the number of likes on pixiv, descending. Same caveats as `bookmark-count-desc`.
+    LikeCountDesc,
+
+    /// Order by the number of views on pixiv, descending. Same caveats as `bookmark-count-desc`.
+    ViewCountDesc,
 }
 
 #[derive(clap::ValueEnum, Clone, Copy)]
@@ -49,11 +75,30 @@ pub enum Format {
     ID,
 
     /// Output in JSON
-    JSON,
+    Json,
+}
+
+impl From<Format> for RowsFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Count | Format::FileCount => RowsFormat::Count,
+            Format::ID => RowsFormat::ID,
+            Format::Json => RowsFormat::Json,
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum QueryCommand {
+    /// List authors with the number of their illustrations in the database
+    Authors(QueryAuthors),
 }
 
 #[derive(Args)]
 pub struct Query {
+    #[command(subcommand)]
+    command: Option<QueryCommand>,
+
     /// Illustration ID
     #[arg(short, long)]
     id: Option<u64>,
@@ -63,21 +108,75 @@ pub struct Query {
     state: Option<IllustState>,
 
     /// Download state
+    ///
+    /// Compares the number of downloaded pages against the page count. An ugoira counts as a single page, its zip.
     #[arg(short, long, value_enum)]
     download_state: Option<QueryDownloadState>,
 
+    /// Illustration type
+    #[arg(long, value_enum)]
+    illust_type: Option<IllustType>,
+
+    /// Age restriction
+    #[arg(long, value_enum)]
+    x_restrict: Option<XRestrict>,
+
     /// Tag, can appear multiple times to specify multiple tags (AND)
     #[arg(short, long)]
     tag: Vec<String>,
 
+    /// Also match `--tag` against the romaji and translations of tags, case-insensitively
+    ///
+    /// Translations are only known for illustrations synced by ID.
+    #[arg(long, requires = "tag")]
+    tag_translations: bool,
+
     /// Bookmark tag, can appear multiple times to specify multiple tags (AND)
     #[arg(short, long)]
     bookmark_tag: Vec<String>,
 
+    /// Only illustrations whose title or description contains this text, case-insensitively
+    ///
+    /// Uses the full-text index for 3 or more characters. Descriptions are only known for illustrations synced by ID.
+    #[arg(long)]
+    search: Option<String>,
+
     /// Author ID
     #[arg(short, long)]
     author_id: Option<u64>,
 
+    /// Series ID, only known for illustrations synced by ID
+    #[arg(long)]
+    series: Option<u64>,
+
+    /// Only illustrations created at or after this time.
+    ///
+    /// Accepts RFC 3339 (e.g. `2024-01-31T12:00:00+09:00`), or `YYYY-MM-DD HH:MM:SS` / `YYYY-MM-DD` in UTC.
+    #[arg(long, value_parser = parse_cli_datetime)]
+    created_after: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only illustrations created at or before this time. Same format as `--created-after`, a date alone includes that whole day.
+    #[arg(long, value_parser = parse_cli_datetime_end)]
+    created_before: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only illustrations updated at or after this time. Same format as `--created-after`.
+    #[arg(long, value_parser = parse_cli_datetime)]
+    updated_after: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only illustrations updated at or before this time. Same format as `--created-before`.
+    #[arg(long, value_parser = parse_cli_datetime_end)]
+    updated_before: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only illustrations with an image downloaded at or after this time. Same format as `--created-after`.
+    #[arg(long, value_parser = parse_cli_datetime)]
+    downloaded_after: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Only illustrations with an image downloaded at or before this time. Same format as `--created-before`.
+    ///
+    /// Combined with `--downloaded-after`, the same image needs to be within both bounds.
+    #[arg(long, value_parser = parse_cli_datetime_end)]
+    downloaded_before: Option<chrono::DateTime<chrono::Utc>>,
+
     /// Ordering
     #[arg(short, long, value_enum, default_value_t = QueryOrder::IdAsc)]
     order: QueryOrder,
@@ -86,6 +185,14 @@ pub struct Query {
     #[arg(short, long)]
     limit: Option<usize>,
 
+    /// Skip this many results first. Unlimited if `--limit` is not given.
+    #[arg(long)]
+    offset: Option<usize>,
+
+    /// Return the N-th page of `--limit` results, starting from 1
+    #[arg(long, requires = "limit", conflicts_with = "offset", value_parser = clap::value_parser!(u64).range(1..))]
+    page: Option<u64>,
+
     /// Output format
     #[arg(short, long, value_enum, default_value_t = Format::ID)]
     format: Format,
@@ -101,25 +208,80 @@ pub struct Query {
 
 impl Query {
     pub async fn run(self, db: &crate::db::Database) -> anyhow::Result<()> {
+        if let Some(QueryCommand::Authors(cmd)) = self.command {
+            return cmd.run(db).await;
+        }
+
+        let (sql, params) = self.build_sql(self.format)?;
+        if self.print_sql {
+            print_sql(&sql, &params);
+        }
+
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let result = db.query_raw(&sql, &params).await?;
+        print_query_rows::<u64>(result, self.format.into())?;
+        Ok(())
+    }
+
+    /// IDs of the matching illustrations, ignoring `--format` and `--dry-run`
+    pub async fn query_ids(&self, db: &crate::db::Database) -> anyhow::Result<Vec<u64>> {
+        if self.command.is_some() {
+            return Err(anyhow::anyhow!(
+                "Subcommands of query don't list illustrations"
+            ));
+        }
+
+        let (sql, params) = self.build_sql(Format::ID)?;
+        if self.print_sql {
+            print_sql(&sql, &params);
+        }
+
+        use sqlx::Row;
+        db.query_raw(&sql, &params)
+            .await?
+            .into_iter()
+            .map(|row| Ok(row.try_get::<u64, _>("id")?))
+            .collect()
+    }
+
+    fn build_sql(&self, format: Format) -> anyhow::Result<(String, Vec<QueryParam>)> {
         // You know what, let's concat SQL
+        // User-provided values are still bound as parameters, in the order of their placeholders
 
+        let file_count = format!("SUM({}) as count", EXPECTED_FILE_COUNT);
         let mut sql = format!(
             "SELECT {} FROM illusts",
-            match self.format {
+            match format {
                 Format::Count => "COUNT(*) as count",
-                Format::FileCount => "SUM(page_count) as count",
+                Format::FileCount => &file_count,
                 Format::ID => "id",
-                Format::JSON => "*",
+                Format::Json => "*",
             }
         );
 
         let mut wheres = Vec::new();
+        let mut params = Vec::new();
         if let Some(id) = self.id {
-            wheres.push(format!("id = {}", id));
+            wheres.push("id = ?".to_string());
+            params.push(QueryParam::Int(id as i64));
         }
 
         if let Some(state) = self.state {
-            wheres.push(format!("illust_state = {}", state as u8));
+            wheres.push("illust_state = ?".to_string());
+            params.push(QueryParam::Int(state as i64));
+        }
+
+        if let Some(illust_type) = self.illust_type {
+            wheres.push("illust_type = ?".to_string());
+            params.push(QueryParam::Int(illust_type as i64));
+        }
+
+        if let Some(x_restrict) = self.x_restrict {
+            wheres.push("x_restrict = ?".to_string());
+            params.push(QueryParam::Int(x_restrict as i64));
         }
 
         if let Some(download_state) = self.download_state {
@@ -135,17 +297,18 @@ impl Query {
 
             wheres.push(format!(
                 r#"
-                  page_count {} (
+                  {} {} (
                     SELECT COUNT(DISTINCT page) FROM images
                     WHERE
                       illust_id = illusts.id
+                      AND variant = 0 -- Thumbnails don't count as downloaded
                       {}
                   )
                 "#,
+                EXPECTED_FILE_COUNT,
                 match download_state {
                     QueryDownloadState::Full => "<=",
-                    QueryDownloadState::Missing
-                    | QueryDownloadState::Outdated => ">",
+                    QueryDownloadState::Missing | QueryDownloadState::Outdated => ">",
                     QueryDownloadState::Extra => "<",
                     QueryDownloadState::Exact => "=",
                 },
@@ -156,53 +319,125 @@ impl Query {
             ));
         }
 
-        if self.tag.len() > 0 {
+        if self.tag_translations {
+            for tag in &self.tag {
+                wheres.push(
+                    r#"EXISTS (
+                  SELECT 1 FROM illust_tags it JOIN tags t ON t.id = it.tag_id
+                  WHERE it.illust_id = illusts.id
+                  AND (
+                    t.tag = ? OR t.romaji = ? COLLATE NOCASE
+                    OR EXISTS (SELECT 1 FROM tag_translations tt WHERE tt.tag_id = t.id AND tt.translation = ? COLLATE NOCASE)
+                  )
+                )"#
+                    .to_string(),
+                );
+                for _ in 0..3 {
+                    params.push(QueryParam::Text(tag.clone()));
+                }
+            }
+        } else if !self.tag.is_empty() {
             // Query the tags table, and asserts that not linked tags do not exist
-            wheres.push(format!(
+            let tags = serde_json::to_string(&self.tag)?;
+            wheres.push(
                 r#"NOT EXISTS (
                   SELECT id FROM tags
-                  WHERE tag IN (SELECT json_each.value FROM json_each('{}'))
+                  WHERE tag IN (SELECT json_each.value FROM json_each(?))
                   AND id NOT IN (
                     SELECT tag_id FROM illust_tags WHERE illust_id = illusts.id
                   )
-                )"#,
-                serde_json::to_string(&self.tag)?
-            ));
+                )"#
+                .to_string(),
+            );
+            params.push(QueryParam::Text(tags.clone()));
 
             // Additional constraints that all tags must exists
-            wheres.push(format!(
-                "(SELECT COUNT(*) FROM tags WHERE tag IN (SELECT json_each.value FROM json_each('{}'))) = {}",
-                serde_json::to_string(&self.tag)?,
-                self.tag.len()
-            ));
+            wheres.push(
+                "(SELECT COUNT(*) FROM tags WHERE tag IN (SELECT json_each.value FROM json_each(?))) = ?"
+                    .to_string(),
+            );
+            params.push(QueryParam::Text(tags));
+            params.push(QueryParam::Int(self.tag.len() as i64));
         }
 
-        if self.bookmark_tag.len() > 0 {
+        if !self.bookmark_tag.is_empty() {
             // Query the tags table, and asserts that not linked tags do not exist
-            wheres.push(format!(
+            let tags = serde_json::to_string(&self.bookmark_tag)?;
+            wheres.push(
                 r#"NOT EXISTS (
                   SELECT id FROM tags
-                  WHERE tag IN (SELECT json_each.value FROM json_each('{}'))
+                  WHERE tag IN (SELECT json_each.value FROM json_each(?))
                   AND id NOT IN (
                     SELECT tag_id FROM illust_bookmark_tags WHERE illust_id = illusts.id
                   )
-                )"#,
-                serde_json::to_string(&self.bookmark_tag)?
-            ));
+                )"#
+                .to_string(),
+            );
+            params.push(QueryParam::Text(tags.clone()));
 
             // Additional constraints that all tags must exists
-            wheres.push(format!(
-                "(SELECT COUNT(*) FROM tags WHERE tag IN (SELECT json_each.value FROM json_each('{}'))) = {}",
-                serde_json::to_string(&self.tag)?,
-                self.tag.len()
+            wheres.push(
+                "(SELECT COUNT(*) FROM tags WHERE tag IN (SELECT json_each.value FROM json_each(?))) = ?"
+                    .to_string(),
+            );
+            params.push(QueryParam::Text(tags));
+            params.push(QueryParam::Int(self.bookmark_tag.len() as i64));
+        }
+
+        if let Some(ref search) = self.search {
+            wheres.push(crate::util::search_condition(
+                "illusts",
+                &["title", "content_desc"],
+                search,
+                &mut params,
             ));
         }
 
         if let Some(author_id) = self.author_id {
-            wheres.push(format!("author_id = {}", author_id));
+            wheres.push("author_id = ?".to_string());
+            params.push(QueryParam::Int(author_id as i64));
+        }
+
+        if let Some(series) = self.series {
+            wheres.push("series_id = ?".to_string());
+            params.push(QueryParam::Int(series as i64));
+        }
+
+        // Stored dates are already in UTC
+        for (col, op, bound) in [
+            ("create_date", ">=", self.created_after),
+            ("create_date", "<=", self.created_before),
+            ("update_date", ">=", self.updated_after),
+            ("update_date", "<=", self.updated_before),
+        ] {
+            if let Some(bound) = bound {
+                wheres.push(format!("{} {} datetime(?)", col, op));
+                params.push(QueryParam::Text(
+                    bound.format("%Y-%m-%d %H:%M:%S").to_string(),
+                ));
+            }
+        }
+
+        if self.downloaded_after.is_some() || self.downloaded_before.is_some() {
+            let mut preds = vec!["illust_id = illusts.id".to_string()];
+            for (op, bound) in [
+                (">=", self.downloaded_after),
+                ("<=", self.downloaded_before),
+            ] {
+                if let Some(bound) = bound {
+                    preds.push(format!("download_date {} datetime(?)", op));
+                    params.push(QueryParam::Text(
+                        bound.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    ));
+                }
+            }
+            wheres.push(format!(
+                "EXISTS (SELECT 1 FROM images WHERE {})",
+                preds.join(" AND ")
+            ));
         }
 
-        if wheres.len() > 0 {
+        if !wheres.is_empty() {
             sql.push_str(" WHERE ");
             sql.push_str(&wheres.join(" AND "));
         }
@@ -211,51 +446,304 @@ impl Query {
         match self.order {
             QueryOrder::IdAsc => sql.push_str("id ASC"),
             QueryOrder::IdDesc => sql.push_str("id DESC"),
-            QueryOrder::BookmarkIdAsc => sql.push_str("bookmark_id ASC"),
-            QueryOrder::BookmarkIdDesc => sql.push_str("bookmark_id DESC"),
+            // Break ties between unbookmarked illusts by ID, so that the order is stable
+            QueryOrder::BookmarkIdAsc => sql.push_str("bookmark_id ASC NULLS LAST, id ASC"),
+            QueryOrder::BookmarkIdDesc => sql.push_str("bookmark_id DESC NULLS LAST, id DESC"),
+            QueryOrder::SeriesOrder => sql.push_str("series_id ASC, series_order ASC, id ASC"),
+            QueryOrder::BookmarkCountDesc => {
+                sql.push_str("bookmark_count DESC NULLS LAST, id DESC")
+            }
+            QueryOrder::LikeCountDesc => sql.push_str("like_count DESC NULLS LAST, id DESC"),
+            QueryOrder::ViewCountDesc => sql.push_str("view_count DESC NULLS LAST, id DESC"),
+        }
+
+        let too_large = || anyhow::anyhow!("--limit, --offset or --page is too large");
+        let limit = self
+            .limit
+            .map(|limit| i64::try_from(limit).map_err(|_| too_large()))
+            .transpose()?;
+        let offset = match (self.page, limit) {
+            (Some(page), Some(limit)) => Some(
+                i64::try_from(page - 1)
+                    .ok()
+                    .and_then(|page| page.checked_mul(limit))
+                    .ok_or_else(too_large)?,
+            ),
+            _ => self
+                .offset
+                .map(|offset| i64::try_from(offset).map_err(|_| too_large()))
+                .transpose()?,
+        };
+        if limit.is_some() || offset.is_some() {
+            // SQLite only accepts OFFSET after LIMIT, where -1 means no limit
+            sql.push_str(" LIMIT ?");
+            params.push(QueryParam::Int(limit.unwrap_or(-1)));
+        }
+        if let Some(offset) = offset {
+            sql.push_str(" OFFSET ?");
+            params.push(QueryParam::Int(offset));
+        }
+
+        Ok((sql, params))
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum AuthorOrder {
+    /// Most illustrations first
+    CountDesc,
+
+    /// Order by author ID, ascending
+    IdAsc,
+
+    /// Order by name, ascending
+    NameAsc,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum AuthorFormat {
+    /// Count only
+    Count,
+
+    /// ID only
+    ID,
+
+    /// Tab-separated ID, illustration count, account and name
+    Tsv,
+
+    /// Output in JSON
+    Json,
+}
+
+#[derive(Args)]
+pub struct QueryAuthors {
+    /// Only authors whose name or account contains this, case-insensitively (ASCII only)
+    #[arg(short, long)]
+    name: Option<String>,
+
+    /// Only authors with at least this many illustrations
+    #[arg(long)]
+    min_count: Option<u64>,
+
+    /// Ordering
+    #[arg(short, long, value_enum, default_value_t = AuthorOrder::CountDesc)]
+    order: AuthorOrder,
+
+    /// Limit
+    #[arg(short, long)]
+    limit: Option<usize>,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = AuthorFormat::Tsv)]
+    format: AuthorFormat,
+
+    /// Print SQL query
+    #[arg(long)]
+    print_sql: bool,
+
+    /// Dry run
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl QueryAuthors {
+    pub async fn run(self, db: &crate::db::Database) -> anyhow::Result<()> {
+        let mut sql =
+            r#"SELECT authors.id, authors.name, authors.account, COUNT(illusts.id) as illust_count
+            FROM authors LEFT JOIN illusts ON illusts.author_id = authors.id"#
+                .to_string();
+        let mut params = Vec::new();
+
+        if let Some(ref name) = self.name {
+            let pattern = crate::util::like_contains_pattern(name);
+            sql.push_str(
+                r#" WHERE authors.name LIKE ? ESCAPE '\' OR authors.account LIKE ? ESCAPE '\'"#,
+            );
+            params.push(QueryParam::Text(pattern.clone()));
+            params.push(QueryParam::Text(pattern));
+        }
+
+        sql.push_str(" GROUP BY authors.id");
+        if let Some(min_count) = self.min_count {
+            sql.push_str(" HAVING illust_count >= ?");
+            params.push(QueryParam::Int(min_count as i64));
+        }
+
+        sql.push_str(" ORDER BY ");
+        match self.order {
+            AuthorOrder::CountDesc => sql.push_str("illust_count DESC, authors.id ASC"),
+            AuthorOrder::IdAsc => sql.push_str("authors.id ASC"),
+            AuthorOrder::NameAsc => sql.push_str("authors.name ASC, authors.id ASC"),
         }
 
         if let Some(limit) = self.limit {
-            sql.push_str(&format!(" LIMIT {}", limit));
+            sql.push_str(" LIMIT ?");
+            params.push(QueryParam::Int(limit as i64));
+        }
+
+        if let AuthorFormat::Count = self.format {
+            sql = format!("SELECT COUNT(*) as count FROM ({})", sql);
         }
 
         if self.print_sql {
-            println!("{}", sql);
+            print_sql(&sql, &params);
         }
 
         if self.dry_run {
             return Ok(());
         }
 
-        let result = db.query_raw(&sql).await?;
+        let result = db.query_raw(&sql, &params).await?;
         use sqlx::Row;
 
-        match self.format {
-            Format::Count | Format::FileCount => {
-                let row = result.into_iter().next().unwrap();
-                let count: i64 = row.try_get("count")?;
-                println!("{}", count);
-            }
-            Format::ID => {
+        let format = match self.format {
+            AuthorFormat::Count => RowsFormat::Count,
+            AuthorFormat::ID => RowsFormat::ID,
+            AuthorFormat::Json => RowsFormat::Json,
+            AuthorFormat::Tsv => {
                 for row in result {
                     let id: u64 = row.try_get("id")?;
-                    println!("{}", id);
+                    let count: i64 = row.try_get("illust_count")?;
+                    let account: Option<String> = row.try_get("account")?;
+                    let name: String = row.try_get("name")?;
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        id,
+                        count,
+                        account.as_deref().unwrap_or(""),
+                        name
+                    );
                 }
+                return Ok(());
             }
-            Format::JSON => {
-                println!("[");
-                let mut first = true;
-                for row in result {
-                    if !first {
-                        println!(",");
-                    }
-                    first = false;
-                    let json = db_row_to_json(row)?;
-                    println!("{}", serde_json::to_string(&json)?);
-                }
-                println!("]");
-            }
+        };
+        print_query_rows::<u64>(result, format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::data::pixiv::ImageVariant;
+    use crate::db::tests::{bookmarked, illust, memory_db};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        query: Query,
+    }
+
+    async fn query_ids(db: &crate::db::Database, args: &[&str]) -> Vec<u64> {
+        let cli =
+            Cli::try_parse_from(std::iter::once("query").chain(args.iter().copied())).unwrap();
+        cli.query.query_ids(db).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn bookmark_tags_only() {
+        let db = memory_db().await;
+        let mut tag_map_ctx = HashMap::new();
+        for illust in [
+            bookmarked(1, 10, false, &["a", "b"]),
+            bookmarked(2, 20, false, &["a"]),
+            bookmarked(3, 30, true, &[]),
+        ] {
+            db.update_illust(&illust, &mut tag_map_ctx).await.unwrap();
+        }
+
+        assert_eq!(query_ids(&db, &["-b", "a"]).await, [1, 2]);
+        assert_eq!(query_ids(&db, &["-b", "a", "-b", "b"]).await, [1]);
+        // Illustration tags are not bookmark tags
+        assert_eq!(query_ids(&db, &["-b", "tag"]).await, [] as [u64; 0]);
+        assert_eq!(query_ids(&db, &["-b", "missing"]).await, [] as [u64; 0]);
+    }
+
+    #[tokio::test]
+    async fn ugoira_counts_as_one_file() {
+        let db = memory_db().await;
+        let mut tag_map_ctx = HashMap::new();
+        for illust in [
+            illust(1, IllustType::Illustration, 2),
+            illust(2, IllustType::Manga, 2),
+            illust(3, IllustType::Ugoira, 3),
+            illust(4, IllustType::Ugoira, 1),
+        ] {
+            db.update_illust(&illust, &mut tag_map_ctx).await.unwrap();
+        }
+        for (illust_id, page, variant) in [
+            (1, 0, ImageVariant::Original),
+            // Thumbnails don't count as downloaded
+            (1, 1, ImageVariant::Small),
+            (2, 0, ImageVariant::Original),
+            (2, 1, ImageVariant::Original),
+            // The zip of an ugoira is always page 0
+            (3, 0, ImageVariant::Original),
+        ] {
+            let path = format!("{}_p{}_{}", illust_id, page, variant as u8);
+            db.insert_image(illust_id, page, "", &path, 1, 1, None, variant, "")
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(query_ids(&db, &["-d", "missing"]).await, [1, 4]);
+        assert_eq!(query_ids(&db, &["-d", "full"]).await, [2, 3]);
+        assert_eq!(query_ids(&db, &["-d", "exact"]).await, [2, 3]);
+        assert_eq!(query_ids(&db, &["--illust-type", "ugoira"]).await, [3, 4]);
+
+        use sqlx::Row;
+        let cli = Cli::try_parse_from(["query", "--illust-type", "ugoira"]).unwrap();
+        let (sql, params) = cli.query.build_sql(Format::FileCount).unwrap();
+        let rows = db.query_raw(&sql, &params).await.unwrap();
+        assert_eq!(rows[0].try_get::<i64, _>("count").unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn bookmark_order_mixes_visibility() {
+        let db = memory_db().await;
+        let mut tag_map_ctx = HashMap::new();
+        for illust in [
+            bookmarked(1, 40, true, &[]),
+            bookmarked(2, 10, false, &[]),
+            illust(3, IllustType::Illustration, 1),
+            bookmarked(4, 30, false, &[]),
+            bookmarked(5, 20, true, &[]),
+            illust(6, IllustType::Illustration, 1),
+        ] {
+            db.update_illust(&illust, &mut tag_map_ctx).await.unwrap();
+        }
+
+        assert_eq!(
+            query_ids(&db, &["-o", "bookmark-id-desc"]).await,
+            [1, 4, 5, 2, 6, 3]
+        );
+        assert_eq!(
+            query_ids(&db, &["-o", "bookmark-id-asc"]).await,
+            [2, 5, 4, 1, 3, 6]
+        );
+        assert_eq!(
+            query_ids(&db, &["-o", "bookmark-id-desc", "-l", "2"]).await,
+            [1, 4]
+        );
+    }
+
+    #[test]
+    fn page_overflow() {
+        let cli = Cli::try_parse_from(["query", "-l", "2", "--page", "3"]).unwrap();
+        let (sql, _) = cli.query.build_sql(Format::ID).unwrap();
+        assert!(sql.ends_with(" LIMIT ? OFFSET ?"), "{}", sql);
+
+        let large = u64::MAX.to_string();
+        for args in [
+            ["-l", "1000", "--page", large.as_str()],
+            ["-l", large.as_str(), "--page", "1"],
+            ["-l", "1", "--offset", large.as_str()],
+        ] {
+            let cli = Cli::try_parse_from(std::iter::once("query").chain(args)).unwrap();
+            assert!(cli.query.build_sql(Format::ID).is_err(), "{:?}", args);
         }
-        Ok(())
     }
 }
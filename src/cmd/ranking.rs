@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use clap::Args;
+
+use crate::data::pixiv::RankingMode;
+
+#[derive(Args)]
+pub struct Ranking {
+    #[arg(short, long, value_enum, default_value_t = RankingMode::Daily)]
+    /// Ranking period
+    mode: RankingMode,
+
+    #[arg(short, long)]
+    /// Fetch the ranking that ended on this day (YYYY-MM-DD), instead of the latest one
+    date: Option<chrono::NaiveDate>,
+
+    #[arg(long)]
+    /// Maximum number of fetched illustrations, starting from the top rank
+    max_cnt: Option<usize>,
+
+    /// Abort if failed
+    #[arg(long)]
+    abort_on_fail: bool,
+}
+
+impl Ranking {
+    pub async fn run(
+        self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+    ) -> anyhow::Result<()> {
+        let mut entries = crate::data::pixiv::get_ranking(session, self.mode, self.date).await?;
+        tracing::info!("Ranking has {} works", entries.len());
+        if let Some(max_cnt) = self.max_cnt {
+            entries.truncate(max_cnt);
+        }
+
+        // Ranking entries lack most metadata, including the bookmark state, so each work is synced by ID
+        let mut tag_map_ctx: HashMap<String, u64> = HashMap::new();
+        let mut errored = 0;
+        for entry in entries {
+            if crate::interrupt::is_interrupted() {
+                break;
+            }
+            let id = entry.illust_id;
+            let illust = match crate::retry::with_retries(
+                crate::retry::RetryPolicy::global(),
+                format!("illust {}", id),
+                || crate::data::pixiv::get_illust(session, id),
+            )
+            .await
+            {
+                Ok(illust) => illust,
+                Err(e) if !self.abort_on_fail => {
+                    tracing::error!("Failed to fetch illust {} ({}): {:?}", id, entry.title, e);
+                    errored += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let update_result = db.update_illust(&illust, &mut tag_map_ctx).await?;
+            let update_prompt = update_result.label();
+            tracing::info!(
+                "Synced #{} {}: [{}] {}",
+                entry.rank,
+                illust.id,
+                update_prompt,
+                illust.data.display_title()
+            );
+        }
+
+        if errored == 0 {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("{} illust(s) failed to sync", errored))
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use clap::Args;
+
+use crate::util::{TerminationCondition, parse_cli_duration};
+
+#[derive(Args)]
+pub struct User {
+    /// ID of the user
+    id: u64,
+
+    #[arg(long)]
+    /// Maximum number of fetched illustrations
+    max_cnt: Option<usize>,
+
+    #[arg(alias="term", long, value_enum, default_value_t = TerminationCondition::UntilEnd)]
+    /// Termination condition (alias: --term)
+    ///
+    /// Works are fetched newest first, so `on-hit` stops at the first one already in the database.
+    termination: TerminationCondition,
+
+    #[arg(long, alias = "timeout-total", value_parser = parse_cli_duration)]
+    /// Stop after this much time has elapsed, e.g. `30m` or `1h` (alias: --timeout-total)
+    ///
+    /// Checked between illustrations, so the one being fetched is always stored.
+    max_duration: Option<std::time::Duration>,
+
+    /// Abort if failed
+    #[arg(long)]
+    abort_on_fail: bool,
+}
+
+impl User {
+    pub async fn run(
+        self,
+        session: &crate::config::Session,
+        db: &crate::db::Database,
+    ) -> anyhow::Result<()> {
+        let ids = crate::retry::with_retries(
+            crate::retry::RetryPolicy::global(),
+            format!("works of user {}", self.id),
+            || crate::data::pixiv::get_user_works(session, self.id),
+        )
+        .await?;
+        tracing::info!("User {} has {} works", self.id, ids.len());
+
+        let deadline = crate::util::deadline_after(self.max_duration);
+        let mut tag_map_ctx: HashMap<String, u64> = HashMap::new();
+        let mut cnt = 0;
+        let mut errored = 0;
+        for id in ids {
+            if crate::interrupt::is_interrupted() {
+                break;
+            }
+            let illust = match crate::retry::with_retries(
+                crate::retry::RetryPolicy::global(),
+                format!("illust {}", id),
+                || crate::data::pixiv::get_illust(session, id),
+            )
+            .await
+            {
+                Ok(illust) => illust,
+                Err(e) if !self.abort_on_fail => {
+                    tracing::error!("Failed to fetch illust {}: {:?}", id, e);
+                    errored += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let update_result = db.update_illust(&illust, &mut tag_map_ctx).await?;
+            let update_prompt = update_result.label();
+            tracing::info!(
+                "Synced {}: [{}] {}",
+                illust.id,
+                update_prompt,
+                illust.data.display_title()
+            );
+
+            if matches!(
+                update_result,
+                crate::db::IllustUpdateResult::Unchanged | crate::db::IllustUpdateResult::Skipped
+            ) && self.termination == TerminationCondition::OnHit
+            {
+                tracing::info!("Encountered an already existing illustration. Terminating.");
+                break;
+            }
+
+            cnt += 1;
+            if let Some(max_count) = self.max_cnt
+                && cnt >= max_count
+            {
+                tracing::info!(
+                    "Reached the maximum number of fetched illustrations ({}). Terminating.",
+                    max_count
+                );
+                break;
+            }
+
+            if let Some(deadline) = deadline
+                && tokio::time::Instant::now() >= deadline
+            {
+                tracing::info!(
+                    "Reached the maximum duration after {} illustrations (last: {}). Terminating.",
+                    cnt,
+                    illust.id
+                );
+                break;
+            }
+        }
+
+        if errored == 0 {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("{} illust(s) failed to sync", errored))
+        }
+    }
+}
@@ -1,3 +1,126 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Settings read from the config file
+///
+/// Every field is optional, and is overridden by the corresponding flag or environment variable.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub pixiv_cookie: Option<String>,
+    pub fanbox_cookie: Option<String>,
+    pub fanbox_header_full: Option<String>,
+    pub fanbox_header_full_file: Option<PathBuf>,
+
+    pub pixiv_base_dir: Option<PathBuf>,
+    pub pixiv_thumb_dir: Option<PathBuf>,
+    pub fanbox_base_dir: Option<PathBuf>,
+
+    pub database_url: Option<String>,
+    pub fetch_delay: Option<i64>,
+    pub fetch_delay_var: Option<i64>,
+    pub http_retries: Option<u32>,
+    pub retries: Option<usize>,
+    pub retry_backoff: Option<u64>,
+    pub download_retries: Option<u32>,
+    pub proxy: Option<String>,
+    pub user_agent: Option<String>,
+    pub emulation: Option<Emulation>,
+
+    /// Named sets of account settings, selected with `--profile`
+    #[serde(default)]
+    pub profiles: std::collections::HashMap<String, ProfileConfig>,
+}
+
+/// Account settings of a profile, in a `[profiles.<name>]` table
+///
+/// Set fields replace the top-level ones of the config file when the profile is selected.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub pixiv_cookie: Option<String>,
+    pub fanbox_cookie: Option<String>,
+    pub fanbox_header_full: Option<String>,
+    pub fanbox_header_full_file: Option<PathBuf>,
+
+    pub pixiv_base_dir: Option<PathBuf>,
+    pub pixiv_thumb_dir: Option<PathBuf>,
+    pub fanbox_base_dir: Option<PathBuf>,
+
+    pub database_url: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// `$XDG_CONFIG_HOME/pixivdwn`, falling back to `~/.config/pixivdwn`
+pub fn config_dir() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|e| !e.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("pixivdwn"))
+}
+
+impl ConfigFile {
+    /// `config.toml` in the config directory
+    fn default_path() -> Option<PathBuf> {
+        Some(config_dir()?.join("config.toml"))
+    }
+
+    /// Load the config file from the given path, or the default location
+    ///
+    /// An explicitly given file must exist, while a missing default file is treated as empty.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "Failed to read config file {}: {}",
+                    path.display(),
+                    e
+                ));
+            }
+        };
+        tracing::debug!("Loaded config file {}", path.display());
+        toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse config file {}: {}", path.display(), e))
+    }
+
+    /// Replace the top-level settings with the ones set in the given profile
+    pub fn apply_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        let profile = self
+            .profiles
+            .remove(name)
+            .ok_or_else(|| anyhow::anyhow!("Profile {} not found in the config file", name))?;
+        tracing::debug!("Using profile {}", name);
+
+        // Header strings and files override each other, so a profile setting either replaces both
+        if profile.fanbox_header_full.is_some() || profile.fanbox_header_full_file.is_some() {
+            self.fanbox_header_full = profile.fanbox_header_full;
+            self.fanbox_header_full_file = profile.fanbox_header_full_file;
+        }
+        self.pixiv_cookie = profile.pixiv_cookie.or(self.pixiv_cookie.take());
+        self.fanbox_cookie = profile.fanbox_cookie.or(self.fanbox_cookie.take());
+        self.pixiv_base_dir = profile.pixiv_base_dir.or(self.pixiv_base_dir.take());
+        self.pixiv_thumb_dir = profile.pixiv_thumb_dir.or(self.pixiv_thumb_dir.take());
+        self.fanbox_base_dir = profile.fanbox_base_dir.or(self.fanbox_base_dir.take());
+        self.database_url = profile.database_url.or(self.database_url.take());
+        self.user_agent = profile.user_agent.or(self.user_agent.take());
+        Ok(())
+    }
+}
+
 pub struct UIDSession {
     pub uid: u64,
     pub cookie: String,
@@ -21,22 +144,57 @@ impl TryFrom<&str> for UIDSession {
     }
 }
 
+/// Browser emulated by all pixiv and fanbox requests, down to the TLS and HTTP/2 fingerprint
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Emulation {
+    Chrome131,
+    Chrome136,
+    #[default]
+    Chrome140,
+    Edge134,
+    Firefox142,
+    Safari26,
+}
+
+impl From<Emulation> for wreq_util::Emulation {
+    fn from(value: Emulation) -> Self {
+        match value {
+            Emulation::Chrome131 => wreq_util::Emulation::Chrome131,
+            Emulation::Chrome136 => wreq_util::Emulation::Chrome136,
+            Emulation::Chrome140 => wreq_util::Emulation::Chrome140,
+            Emulation::Edge134 => wreq_util::Emulation::Edge134,
+            Emulation::Firefox142 => wreq_util::Emulation::Firefox142,
+            Emulation::Safari26 => wreq_util::Emulation::Safari26,
+        }
+    }
+}
+
 pub struct Session {
     pub pixiv: Option<UIDSession>,
     pub fanbox: Option<UIDSession>,
     pub fanbox_header_full: Option<Vec<(String, String)>>,
 
     pub pixiv_base_dir: Option<std::path::PathBuf>,
+    pub pixiv_thumb_dir: Option<std::path::PathBuf>,
     pub fanbox_base_dir: Option<std::path::PathBuf>,
+
+    /// Overrides the User-Agent of the emulated browser
+    pub user_agent: Option<String>,
+    pub emulation: Emulation,
 }
 
 impl Session {
+    #[expect(clippy::too_many_arguments)]
     pub fn new(
         pixiv_cookie: Option<String>,
         fanbox_cookie: Option<String>,
         fanbox_header_full: Option<String>,
         pixiv_base_dir: Option<std::path::PathBuf>,
+        pixiv_thumb_dir: Option<std::path::PathBuf>,
         fanbox_base_dir: Option<std::path::PathBuf>,
+        user_agent: Option<String>,
+        emulation: Emulation,
     ) -> anyhow::Result<Self> {
         let pixiv = pixiv_cookie
             .map(|e| UIDSession::try_from(e.as_str()))
@@ -51,6 +209,8 @@ impl Session {
                 for row in e
                     .trim()
                     .split("\n")
+                    // Headers saved from browsers may come with CRLF
+                    .map(|e| e.trim_end_matches('\r'))
                     .filter(|e| {
                         !(e.starts_with("GET")
                             || e.starts_with("Host: ")
@@ -78,16 +238,35 @@ impl Session {
             fanbox,
             fanbox_header_full,
             pixiv_base_dir,
+            pixiv_thumb_dir,
             fanbox_base_dir,
+            user_agent,
+            emulation,
         })
     }
 
+    /// Make the request look like it comes from the emulated browser, with the User-Agent override if set
+    pub fn fingerprint(&self, req: wreq::RequestBuilder) -> wreq::RequestBuilder {
+        let req = req.emulation(wreq_util::Emulation::from(self.emulation));
+        match self.user_agent {
+            Some(ref user_agent) => req.header("User-Agent", user_agent),
+            None => req,
+        }
+    }
+
     pub fn get_pixiv_base_dir(&self) -> anyhow::Result<&std::path::PathBuf> {
         self.pixiv_base_dir
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("Pixiv base directory is not set."))
     }
 
+    pub fn get_pixiv_thumb_dir(&self) -> anyhow::Result<std::path::PathBuf> {
+        if let Some(ref dir) = self.pixiv_thumb_dir {
+            return Ok(dir.clone());
+        }
+        Ok(self.get_pixiv_base_dir()?.join("thumbs"))
+    }
+
     pub fn get_fanbox_base_dir(&self) -> anyhow::Result<&std::path::PathBuf> {
         self.fanbox_base_dir
             .as_ref()
@@ -1,4 +1,7 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::atomic::AtomicBool,
+};
 
 use async_stream::try_stream;
 use serde::{Deserialize, Serialize};
@@ -22,8 +25,7 @@ pub struct LinkedPixivUser {
 pub struct FetchPostCover {
     #[expect(unused)]
     r#type: String,
-    #[expect(unused)]
-    url: String,
+    pub url: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -35,7 +37,6 @@ pub struct FetchPost {
     pub fee_required: u64,
     pub published_datetime: chrono::DateTime<chrono::FixedOffset>,
     pub updated_datetime: chrono::DateTime<chrono::FixedOffset>,
-    #[expect(unused)]
     pub tags: Vec<String>,
 
     #[expect(unused)]
@@ -49,12 +50,9 @@ pub struct FetchPost {
 
     pub is_restricted: bool,
 
-    #[expect(unused)]
     pub user: Option<LinkedPixivUser>,
     pub creator_id: String,
-    #[expect(unused)]
     pub has_adult_content: bool,
-    #[expect(unused)]
     pub cover: Option<FetchPostCover>,
     #[expect(unused)]
     pub excerpt: String,
@@ -97,6 +95,22 @@ pub enum FetchPostBlock {
     },
 }
 
+impl FetchPostBlock {
+    /// IDs of (images, files) referenced by stored rich body blocks
+    pub fn referenced_ids(blocks: &[FetchPostBlock]) -> (Vec<String>, Vec<String>) {
+        let mut images = Vec::new();
+        let mut files = Vec::new();
+        for block in blocks {
+            match block {
+                FetchPostBlock::Image { image_id } => images.push(image_id.clone()),
+                FetchPostBlock::File { file_id } => files.push(file_id.clone()),
+                _ => {}
+            }
+        }
+        (images, files)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FetchPostImage {
@@ -119,6 +133,84 @@ pub struct FetchPostFile {
     pub url: String,
 }
 
+/// An external link embedded in a rich post body
+#[derive(Debug)]
+pub struct FetchPostUrlEmbed {
+    pub id: String,
+    /// e.g. `default`, `html`, `html.card`, `fanbox.post` or `fanbox.creator`
+    pub r#type: String,
+    /// Resolved target of the embed, None if the type is unknown
+    pub url: Option<String>,
+}
+
+impl FetchPostUrlEmbed {
+    fn from_content(id: &str, content: &serde_json::Value) -> Self {
+        let r#type = content
+            .get("type")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_owned();
+        let url = Self::resolve_url(&r#type, content);
+        if url.is_none() {
+            tracing::warn!(
+                "Unable to resolve url_embed {} of type {:?}: {}",
+                id,
+                r#type,
+                content
+            );
+        }
+        FetchPostUrlEmbed {
+            id: id.to_owned(),
+            r#type,
+            url,
+        }
+    }
+
+    fn resolve_url(ty: &str, content: &serde_json::Value) -> Option<String> {
+        // IDs may be either strings or numbers
+        let field = |v: &serde_json::Value, key: &str| match v.get(key)? {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        };
+
+        match ty {
+            "default" => field(content, "url"),
+            // Cards and raw HTML are iframes (e.g. YouTube, Twitter) or links, use the first target
+            "html" | "html.card" => {
+                let html = scraper::Html::parse_fragment(content.get("html")?.as_str()?);
+                let selector = scraper::Selector::parse("iframe[src], a[href], img[src]").unwrap();
+                let target = html.select(&selector).find_map(|e| {
+                    let value = e.value();
+                    value.attr("src").or_else(|| value.attr("href"))
+                })?;
+                let target = target.trim();
+                if target.starts_with("//") {
+                    Some(format!("https:{}", target))
+                } else {
+                    Some(target.to_owned())
+                }
+            }
+            "fanbox.post" => {
+                let info = content.get("postInfo")?;
+                Some(format!(
+                    "https://www.fanbox.cc/@{}/posts/{}",
+                    field(info, "creatorId")?,
+                    field(info, "id")?
+                ))
+            }
+            "fanbox.creator" => {
+                let profile = content.get("profile")?;
+                Some(format!(
+                    "https://www.fanbox.cc/@{}",
+                    field(profile, "creatorId")?
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FetchPostBodyRichRaw {
@@ -135,6 +227,7 @@ pub struct FetchPostBodyRich {
     pub blocks: Vec<FetchPostBlock>,
     pub images: Vec<(usize, FetchPostImage)>,
     pub files: Vec<(usize, FetchPostFile)>,
+    pub url_embeds: Vec<(usize, FetchPostUrlEmbed)>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -158,6 +251,15 @@ pub enum FetchPostBodyConversionError {
     },
 }
 
+/// Whether extra unmapped items in rich bodies are errors, see [`set_strict_json`]
+static STRICT_JSON: AtomicBool = AtomicBool::new(false);
+
+/// If set, rich bodies with unmapped images, files or embeds fail to parse instead of
+/// having the extra items ignored. Useful for detecting schema changes.
+pub fn set_strict_json(strict: bool) {
+    STRICT_JSON.store(strict, std::sync::atomic::Ordering::Relaxed);
+}
+
 impl TryFrom<FetchPostBodyRichRaw> for FetchPostBodyRich {
     type Error = FetchPostBodyConversionError;
 
@@ -165,6 +267,7 @@ impl TryFrom<FetchPostBodyRichRaw> for FetchPostBodyRich {
         tracing::debug!("Converting rich body: {:#?}", raw);
         let mut images = Vec::new();
         let mut files = Vec::new();
+        let mut url_embeds = Vec::new();
 
         for (idx, block) in raw.blocks.iter_mut().enumerate() {
             match block {
@@ -195,23 +298,35 @@ impl TryFrom<FetchPostBodyRichRaw> for FetchPostBodyRich {
                         FetchPostBodyConversionError::UnmatchedUrlEmbedId(url_embed_id.clone())
                     })?;
                     assert!(content.is_none());
+                    url_embeds.push((idx, FetchPostUrlEmbed::from_content(url_embed_id, &inner)));
                     *content = Some(inner);
                 }
                 _ => {}
             }
         }
 
-        let extra_embed = raw.embed_map.len() != 0;
-        let extra_url_embed = raw.url_embed_map.len() != 0;
-        let extra_image = raw.image_map.len() != 0;
-        let extra_file = raw.file_map.len() != 0;
+        let extra_embed = !raw.embed_map.is_empty();
+        let extra_url_embed = !raw.url_embed_map.is_empty();
+        let extra_image = !raw.image_map.is_empty();
+        let extra_file = !raw.file_map.is_empty();
         if extra_embed || extra_url_embed || extra_image || extra_file {
-            return Err(FetchPostBodyConversionError::Extra {
+            let err = FetchPostBodyConversionError::Extra {
                 embed: extra_embed,
                 url_embed: extra_url_embed,
                 image: extra_image,
                 file: extra_file,
-            });
+            };
+            if STRICT_JSON.load(std::sync::atomic::Ordering::Relaxed) {
+                return Err(err);
+            }
+            tracing::warn!(
+                "{}, ignoring. image: {:?} file: {:?} embed: {:?} url_embed: {:?}",
+                err,
+                raw.image_map.keys().collect::<Vec<_>>(),
+                raw.file_map.keys().collect::<Vec<_>>(),
+                raw.embed_map.keys().collect::<Vec<_>>(),
+                raw.url_embed_map.keys().collect::<Vec<_>>()
+            );
         }
 
         tracing::debug!(
@@ -224,6 +339,7 @@ impl TryFrom<FetchPostBodyRichRaw> for FetchPostBodyRich {
             blocks: raw.blocks,
             images,
             files,
+            url_embeds,
         })
     }
 }
@@ -285,7 +401,7 @@ impl FetchPostBodyLegacy {
             }
         }
 
-        return None;
+        None
     }
 
     fn parse(&mut self) {
@@ -445,6 +561,15 @@ impl FetchPostBody {
         }
     }
 
+    /// External links embedded in the body. Only rich bodies have them.
+    pub fn url_embeds(&self) -> impl Iterator<Item = (usize, &FetchPostUrlEmbed)> {
+        let url_embeds = match self {
+            FetchPostBody::Rich(rich) => rich.url_embeds.as_slice(),
+            FetchPostBody::Simple(_) | FetchPostBody::Legacy(_) => &[],
+        };
+        url_embeds.iter().map(|(idx, embed)| (*idx, embed))
+    }
+
     pub fn text_repr(&self) -> anyhow::Result<String> {
         let txt = match self {
             FetchPostBody::Rich(rich) => serde_json::to_string(&rich.blocks)?,
@@ -454,6 +579,26 @@ impl FetchPostBody {
         Ok(txt)
     }
 
+    /// Plain text for searching, i.e. paragraphs and headers of rich bodies, one per line
+    pub fn plain_text(&self) -> String {
+        match self {
+            FetchPostBody::Rich(rich) => {
+                let texts: Vec<&str> =
+                    rich.blocks
+                        .iter()
+                        .filter_map(|b| match b {
+                            FetchPostBlock::Paragraph { text }
+                            | FetchPostBlock::Header { text } => Some(text.as_str()),
+                            _ => None,
+                        })
+                        .collect();
+                texts.join("\n")
+            }
+            FetchPostBody::Simple(simple) => simple.text.clone(),
+            FetchPostBody::Legacy(legacy) => legacy.html.clone(),
+        }
+    }
+
     pub fn is_rich(&self) -> bool {
         matches!(self, FetchPostBody::Rich(_))
     }
@@ -467,11 +612,14 @@ pub struct FetchPostDetail {
     pub body: Option<FetchPostBody>,
 }
 
+#[derive(Clone, Copy)]
 pub struct FanboxRequest<'a>(pub &'a Session);
 
 impl<'a> RequestArgumenter for FanboxRequest<'a> {
     fn argument(self, req: wreq::RequestBuilder) -> anyhow::Result<wreq::RequestBuilder> {
+        let req = self.0.fingerprint(req);
         if let Some(ref full) = self.0.fanbox_header_full {
+            // Copied from the browser, so they take precedence, User-Agent included
             let mut updated = req;
             for (hdr, val) in full.iter() {
                 updated = updated.header(hdr, val);
@@ -487,8 +635,7 @@ impl<'a> RequestArgumenter for FanboxRequest<'a> {
 
         let updated = updated
             .header("Origin", "https://www.fanbox.cc")
-            .header("Referer", "https://www.fanbox.cc/")
-            .emulation(wreq_util::Emulation::Chrome140);
+            .header("Referer", "https://www.fanbox.cc/");
         Ok(updated)
     }
 }
@@ -529,26 +676,63 @@ pub async fn get_author_paginates(
     json.into_body()
 }
 
+/// Make sure a page URL returned by `post.paginateCreator` lists posts of the same creator, before sending the session to it
+fn check_paginate_url(url: &str, author_id: &str) -> anyhow::Result<()> {
+    let creator = url
+        .strip_prefix("https://api.fanbox.cc/post.listCreator?")
+        .and_then(|query| {
+            query
+                .split('&')
+                .find_map(|param| param.strip_prefix("creatorId="))
+        });
+    if creator != Some(author_id) {
+        return Err(anyhow::anyhow!(
+            "Unexpected page URL for creator {}: {}, expected https://api.fanbox.cc/post.listCreator?creatorId={}&...",
+            author_id,
+            url,
+            author_id
+        ));
+    }
+    Ok(())
+}
+
 pub fn fetch_author_posts(
     session: &Session,
     author_id: &str,
     skip_pages: usize,
 ) -> impl futures::Stream<Item = anyhow::Result<FetchPost>> {
     try_stream! {
-        let paginates = get_author_paginates(session, author_id).await?;
+        let paginates = crate::retry::with_retries(
+            crate::retry::RetryPolicy::global(),
+            format!("pages of creator {}", author_id),
+            || get_author_paginates(session, author_id),
+        ).await?;
+        if paginates.is_empty() {
+            tracing::info!("Creator {} has no posts", author_id);
+        }
+        for url in &paginates {
+            check_paginate_url(url, author_id)?;
+        }
+
         for (page, url) in paginates.iter().enumerate() {
             if page < skip_pages {
                 tracing::info!("Skipping page {}/{}", page + 1, paginates.len());
                 continue;
             }
 
-            // FIXME: assert url format
             tracing::info!("Fetching page {}/{}", page + 1, paginates.len());
 
-            let posts: Response<Vec<FetchPost>> = crate::fetch::fetch(|client| {
-                Ok(client.get(url).prepare_with(FanboxRequest(session))?.build()?)
-            }).await?;
-            for post in posts.into_body()? {
+            let posts = crate::retry::with_retries(
+                crate::retry::RetryPolicy::global(),
+                format!("page {} of creator {}", page + 1, author_id),
+                || async {
+                    let posts: Response<Vec<FetchPost>> = crate::fetch::fetch(|client| {
+                        Ok(client.get(url).prepare_with(FanboxRequest(session))?.build()?)
+                    }).await?;
+                    posts.into_body()
+                },
+            ).await?;
+            for post in posts {
                 yield post;
             }
         }
@@ -599,3 +783,35 @@ pub async fn fetch_supporting_list(session: &Session) -> anyhow::Result<Vec<Supp
     .await?;
     json.into_body()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paginate_url_creator() {
+        let ok =
+            "https://api.fanbox.cc/post.listCreator?creatorId=abc&maxPublishedDatetime=2024-01-01";
+        assert!(check_paginate_url(ok, "abc").is_ok());
+        assert!(
+            check_paginate_url(
+                "https://api.fanbox.cc/post.listCreator?limit=10&creatorId=abc",
+                "abc"
+            )
+            .is_ok()
+        );
+
+        for url in [
+            // Another creator, or one whose ID only starts with the expected one
+            "https://api.fanbox.cc/post.listCreator?creatorId=other",
+            "https://api.fanbox.cc/post.listCreator?creatorId=abcd",
+            "https://api.fanbox.cc/post.listCreator?xcreatorId=abc",
+            // Another host or endpoint
+            "https://evil.example/post.listCreator?creatorId=abc",
+            "https://api.fanbox.cc/post.info?creatorId=abc",
+            "http://api.fanbox.cc/post.listCreator?creatorId=abc",
+        ] {
+            assert!(check_paginate_url(url, "abc").is_err(), "{}", url);
+        }
+    }
+}
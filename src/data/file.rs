@@ -1,47 +1,310 @@
-use std::path::Path;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
 
 use crate::data::{RequestArgumenter, RequestExt};
 use futures::StreamExt;
 use sha2::{Digest, Sha256};
 use tempfile::NamedTempFile;
 
+static DOWNLOAD_RETRIES: AtomicU32 = AtomicU32::new(0);
+static DOWNLOAD_RETRY_BACKOFF_MS: AtomicU64 = AtomicU64::new(5000);
+
+/// How many times a failed file download is restarted, and the initial backoff before that (ms), doubled after each retry
+pub fn update_download_retry_settings(retries: u32, backoff_ms: u64) {
+    DOWNLOAD_RETRIES.store(retries, Ordering::Relaxed);
+    DOWNLOAD_RETRY_BACKOFF_MS.store(backoff_ms, Ordering::Relaxed);
+}
+
+static OVERLAP_TRANSFERS: AtomicBool = AtomicBool::new(false);
+
+/// Whether file transfers may overlap, for concurrent downloads
+///
+/// Otherwise the global delay is held for the whole transfer, and counts from its end, so one file is downloaded at a time.
+/// When overlapping, it's only held until the response headers arrive, pacing the requests but not the transfers.
+pub fn update_overlap_transfers(overlap: bool) {
+    OVERLAP_TRANSFERS.store(overlap, Ordering::Relaxed);
+}
+
+// 0 means unlimited
+static LIMIT_RATE: AtomicU64 = AtomicU64::new(0);
+// When the bandwidth used so far by all downloads is paid off
+static RATE_DEADLINE: std::sync::Mutex<Option<tokio::time::Instant>> = std::sync::Mutex::new(None);
+
+/// Cap the total bandwidth of all file downloads, in bytes per second
+pub fn update_limit_rate_settings(bytes_per_sec: Option<u64>) {
+    LIMIT_RATE.store(bytes_per_sec.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Wait until receiving `len` more bytes keeps all downloads within `--limit-rate`
+///
+/// The budget is shared, so concurrent downloads split the bandwidth between them.
+async fn throttle(len: usize) {
+    let rate = LIMIT_RATE.load(Ordering::Relaxed);
+    if rate == 0 {
+        return;
+    }
+    let cost = std::time::Duration::from_secs_f64(len as f64 / rate as f64);
+    let deadline = {
+        let mut deadline = RATE_DEADLINE.lock().unwrap();
+        let now = tokio::time::Instant::now();
+        // Idle time doesn't accumulate into a burst
+        let next = deadline.filter(|d| *d > now).unwrap_or(now) + cost;
+        *deadline = Some(next);
+        next
+    };
+    tokio::time::sleep_until(deadline).await;
+}
+
+/// What a download is expected to contain, checked against its `Content-Type` before anything is written
+#[derive(Clone, Copy, Debug)]
+pub enum ExpectedContent {
+    /// Any `image/*`
+    Image,
+
+    /// An ugoira, usually a zip archive of its frames, but newer ones may be a single animated image
+    Ugoira,
+
+    /// Anything, e.g. fanbox attachments or when the check is turned off
+    Any,
+}
+
+impl ExpectedContent {
+    fn accepts(self, mime: &str) -> bool {
+        match self {
+            ExpectedContent::Image => mime.starts_with("image/"),
+            ExpectedContent::Ugoira => {
+                matches!(mime, "application/zip" | "application/x-zip-compressed")
+                    || mime.starts_with("image/")
+            }
+            ExpectedContent::Any => true,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            ExpectedContent::Image => "an image",
+            ExpectedContent::Ugoira => "a zip archive or an animated image",
+            ExpectedContent::Any => "anything",
+        }
+    }
+}
+
+/// Progress of a transfer, reported once before the body is read and after each received chunk
+#[derive(Clone, Copy, Debug)]
+pub struct DownloadProgress {
+    /// Bytes received so far
+    pub downloaded: u64,
+
+    /// Total size from `Content-Length`, if known
+    pub total: Option<u64>,
+}
+
+/// Progress callback that draws an `indicatif` bar if `show` is set, and does nothing otherwise
+///
+/// Within a batch, the bar is drawn below the batch bar and cleared once the file completes, and received bytes count towards the batch.
+pub fn progress_bar(
+    show: bool,
+    batch: Option<&BatchProgress>,
+) -> impl FnMut(DownloadProgress) + '_ {
+    let mut bar: Option<indicatif::ProgressBar> = None;
+    let mut last = 0;
+    move |progress| {
+        if let Some(batch) = batch {
+            batch.add_bytes(progress.downloaded.saturating_sub(last));
+            last = progress.downloaded;
+        }
+        if !show {
+            return;
+        }
+        let bar = bar.get_or_insert_with(|| {
+            let bar = if let Some(size) = progress.total {
+                indicatif::ProgressBar::new(size)
+            } else {
+                indicatif::ProgressBar::new_spinner()
+            };
+            bar.set_style(indicatif::ProgressStyle::with_template(
+                "ETA {eta_precise} {elapsed_precise} | {wide_bar} {percent}% | {binary_bytes}/{binary_total_bytes} [{binary_bytes_per_sec}]"
+            ).unwrap().progress_chars("##-"));
+            match batch {
+                Some(batch) => batch
+                    .multi
+                    .add(bar.with_finish(indicatif::ProgressFinish::AndClear)),
+                // Also leave the bar in place if the transfer ends without reaching the total
+                None => bar.with_finish(indicatif::ProgressFinish::AndLeave),
+            }
+        });
+        bar.set_position(progress.downloaded);
+        if progress.total == Some(progress.downloaded) {
+            if batch.is_some() {
+                bar.finish_and_clear();
+            } else {
+                bar.finish();
+            }
+        }
+    }
+}
+
+/// Aggregate progress of a batch of downloads, with the number of completed items and the total received bytes
+pub struct BatchProgress {
+    multi: indicatif::MultiProgress,
+    bar: indicatif::ProgressBar,
+    bytes: std::sync::atomic::AtomicU64,
+}
+
+impl BatchProgress {
+    /// Start drawing the batch bar, for `len` items if known.
+    /// Returns None if `show` is not set, or if the batch is known to hold at most one item.
+    pub fn new(show: bool, len: Option<u64>) -> Option<Self> {
+        if !show || len.is_some_and(|len| len <= 1) {
+            return None;
+        }
+        let multi = indicatif::MultiProgress::new();
+        let bar = match len {
+            Some(len) => indicatif::ProgressBar::new(len).with_style(
+                indicatif::ProgressStyle::with_template(
+                    "Batch {pos}/{len} {elapsed_precise} | {wide_bar} | {msg}",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            ),
+            None => indicatif::ProgressBar::new_spinner().with_style(
+                indicatif::ProgressStyle::with_template(
+                    "Batch {pos} done {elapsed_precise} | {spinner} | {msg}",
+                )
+                .unwrap(),
+            ),
+        };
+        let bar = multi.add(bar);
+        bar.set_message("0 B");
+        Some(BatchProgress {
+            multi,
+            bar,
+            bytes: std::sync::atomic::AtomicU64::new(0),
+        })
+    }
+
+    fn add_bytes(&self, delta: u64) {
+        let bytes = self
+            .bytes
+            .fetch_add(delta, std::sync::atomic::Ordering::Relaxed)
+            + delta;
+        self.bar
+            .set_message(indicatif::HumanBytes(bytes).to_string());
+    }
+
+    /// Mark one item of the batch as done, successful or not
+    pub fn inc(&self) {
+        self.bar.inc(1);
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish();
+    }
+}
+
+/// Why a single download failed
+///
+/// Returned wrapped in `anyhow::Error`, use `downcast_ref` to match on it. Network errors are passed through as-is.
+#[derive(thiserror::Error, Debug)]
+pub enum DownloadError {
+    #[error("Illustration {0} not found in database. Please run `pixivdwn illust {0}` first.")]
+    IllustNotInDatabase(u64),
+    #[error("{0} not found in database")]
+    NotInDatabase(String),
+    #[error("Failed to download {url}: HTTP {status}")]
+    Http {
+        status: wreq::StatusCode,
+        url: String,
+    },
+    #[error(
+        "Unexpected Content-Type {content_type} from {url}, expected {expected}. Use --no-mime-check to download anyway"
+    )]
+    UnexpectedContent {
+        content_type: String,
+        url: String,
+        expected: &'static str,
+    },
+    #[error("IO error: {0}")]
+    Io(#[source] std::io::Error),
+}
+
+/// All failures of a batch download that didn't abort on the first one, in the order they occurred
+#[derive(Debug)]
+pub struct DownloadFailures {
+    pub failures: Vec<(String, anyhow::Error)>,
+}
+
+impl std::fmt::Display for DownloadFailures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} errors occurred during download:",
+            self.failures.len()
+        )?;
+        for (id, e) in &self.failures {
+            write!(f, "\n  {}: {}", id, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DownloadFailures {}
+
 pub async fn download<W: std::io::Write, R: RequestArgumenter>(
     req_arg: R,
     url: &str,
+    expected: ExpectedContent,
     mut dst: W,
-    show_progress: bool,
+    mut on_progress: impl FnMut(DownloadProgress),
 ) -> anyhow::Result<(usize, [u8; 32])> {
-    let fetch_ctx = crate::fetch::FetchCtxGuard::begin().await;
-    let client = fetch_ctx.client();
-
-    let req = client.get(url).prepare_with(req_arg)?.build()?;
-
-    let resp = client.execute(req).await?;
+    let mut fetch_ctx = Some(crate::fetch::FetchCtxGuard::begin().await);
+    let resp = {
+        let client = fetch_ctx.as_ref().unwrap().client();
+        let req = client.get(url).prepare_with(req_arg)?.build()?;
+        client.execute(req).await?
+    };
+    if OVERLAP_TRANSFERS.load(Ordering::Relaxed) {
+        drop(fetch_ctx.take());
+    }
     let status = resp.status();
 
     if !status.is_success() {
-        anyhow::bail!("Failed to download: HTTP {}", status);
+        return Err(DownloadError::Http {
+            status,
+            url: url.to_owned(),
+        }
+        .into());
+    }
+
+    // Error pages are sometimes served with 200, don't save them as the content
+    if !matches!(expected, ExpectedContent::Any) {
+        let content_type = resp
+            .headers()
+            .get(wreq::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok());
+        let mime = content_type
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase());
+        if !mime.as_deref().is_some_and(|mime| expected.accepts(mime)) {
+            return Err(DownloadError::UnexpectedContent {
+                content_type: content_type.unwrap_or("(none)").to_owned(),
+                url: url.to_owned(),
+                expected: expected.describe(),
+            }
+            .into());
+        }
     }
 
-    let size = resp.headers().get("Content-Length").and_then(|e| {
+    let total = resp.headers().get("Content-Length").and_then(|e| {
         let s = e.to_str().ok()?;
         s.parse().ok()
     });
-    let mut bar = if !show_progress {
-        None
-    } else {
-        let bar = if let Some(size) = size {
-            indicatif::ProgressBar::new(size)
-        } else {
-            indicatif::ProgressBar::new_spinner()
-        };
-        bar.set_style(indicatif::ProgressStyle::with_template(
-            "ETA {eta_precise} {elapsed_precise} | {wide_bar} {percent}% | {binary_bytes}/{binary_total_bytes} [{binary_bytes_per_sec}]"
-        ).unwrap().progress_chars("##-"));
-        Some(bar)
-    };
-
-    // FIXME: check MIME
+    on_progress(DownloadProgress {
+        downloaded: 0,
+        total,
+    });
 
     let mut stream = resp.bytes_stream();
     let mut total_length = 0;
@@ -50,29 +313,78 @@ pub async fn download<W: std::io::Write, R: RequestArgumenter>(
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
         digest.update(&chunk);
-        dst.write_all(&chunk)?;
+        dst.write_all(&chunk).map_err(DownloadError::Io)?;
         total_length += chunk.len();
-        if let Some(ref mut bar) = bar {
-            bar.inc(chunk.len() as u64);
-        }
-    }
-
-    if let Some(bar) = bar {
-        bar.finish();
+        on_progress(DownloadProgress {
+            downloaded: total_length as u64,
+            total,
+        });
+        throttle(chunk.len()).await;
     }
 
     Ok((total_length, digest.finalize().into()))
 }
 
-pub async fn download_to_tmp<R: RequestArgumenter>(
+/// Whether a failed download may succeed when tried again
+///
+/// Network errors, HTTP 429 and 5xx are transient. Other statuses, a wrong `Content-Type` or local IO errors are not.
+fn is_retryable(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<DownloadError>() {
+        Some(DownloadError::Http { status, .. }) => {
+            *status == wreq::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+        }
+        Some(_) => false,
+        None => true,
+    }
+}
+
+/// Download into a temporary file in `base_dir`, retrying transient failures as set by `--download-retries`
+///
+/// Each retry restarts the transfer from the beginning in a fresh temporary file.
+pub async fn download_to_tmp<R: RequestArgumenter + Clone>(
     req_arg: R,
     base_dir: &Path,
     url: &str,
-    show_progress: bool,
+    expected: ExpectedContent,
+    mut on_progress: impl FnMut(DownloadProgress),
 ) -> anyhow::Result<(NamedTempFile, usize, [u8; 32])> {
-    let mut tmp_file = NamedTempFile::with_prefix_in("pixivdwn_", base_dir)?;
-    let mut buffered_file = std::io::BufWriter::new(tmp_file.as_file_mut());
-    let (file_len, digest) = download(req_arg, url, &mut buffered_file, show_progress).await?;
-    drop(buffered_file);
-    Ok((tmp_file, file_len, digest))
+    let retries = DOWNLOAD_RETRIES.load(Ordering::Relaxed);
+    let mut backoff = DOWNLOAD_RETRY_BACKOFF_MS.load(Ordering::Relaxed);
+    let mut tries = 0;
+    loop {
+        let mut tmp_file =
+            NamedTempFile::with_prefix_in("pixivdwn_", base_dir).map_err(DownloadError::Io)?;
+        let mut buffered_file = std::io::BufWriter::new(tmp_file.as_file_mut());
+        let result = download(
+            req_arg.clone(),
+            url,
+            expected,
+            &mut buffered_file,
+            &mut on_progress,
+        )
+        .await;
+        let result = result.and_then(|ret| {
+            buffered_file
+                .into_inner()
+                .map_err(|e| DownloadError::Io(e.into_error()))?;
+            Ok(ret)
+        });
+        match result {
+            Ok((file_len, digest)) => return Ok((tmp_file, file_len, digest)),
+            Err(e) if tries < retries && is_retryable(&e) => {
+                tries += 1;
+                tracing::warn!(
+                    "Failed to download {}: {}, retrying in {}ms ({}/{})",
+                    url,
+                    e,
+                    backoff,
+                    tries,
+                    retries
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(backoff)).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
 }
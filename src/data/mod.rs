@@ -19,6 +19,35 @@ where
     de_str_to_u64(deserializer).map(Some)
 }
 
+/// Like `de_str_to_u64_opt`, but also accepts null
+fn de_nullable_str_to_u64_opt<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<&str> = Deserialize::deserialize(deserializer)?;
+    s.map(|s| s.parse::<u64>().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// SQLite stores booleans as 0 / 1, so exported rows may have either form
+fn de_bool_or_int_opt<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrInt {
+        Bool(bool),
+        Int(i64),
+    }
+
+    Ok(match Option::<BoolOrInt>::deserialize(deserializer)? {
+        None => None,
+        Some(BoolOrInt::Bool(b)) => Some(b),
+        Some(BoolOrInt::Int(i)) => Some(i != 0),
+    })
+}
+
 fn de_str_or_u64_to_u64<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
     D: serde::Deserializer<'de>,
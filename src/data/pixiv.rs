@@ -9,7 +9,7 @@ use crate::{
     data::{RequestArgumenter, RequestExt},
 };
 
-#[derive(Deserialize_repr, sqlx::Type, Debug, Clone, Copy)]
+#[derive(Deserialize_repr, clap::ValueEnum, sqlx::Type, Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum IllustType {
     Illustration = 0,
@@ -17,7 +17,7 @@ pub enum IllustType {
     Ugoira = 2,
 }
 
-#[derive(Deserialize_repr, sqlx::Type, Debug, Clone, Copy)]
+#[derive(Deserialize_repr, clap::ValueEnum, sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum XRestrict {
     Public = 0,
@@ -53,9 +53,7 @@ pub struct DetailedTag {
     pub user_id: Option<u64>,
     #[expect(unused)]
     pub user_name: Option<String>,
-    #[expect(unused)]
     pub romaji: Option<String>,
-    #[expect(unused)]
     pub translation: Option<HashMap<String, String>>,
 }
 
@@ -82,6 +80,14 @@ impl Tags {
         };
         ret
     }
+
+    /// Tags with romaji and translations, only available from detailed fetches
+    pub fn details(&self) -> &[DetailedTag] {
+        match self {
+            Tags::Brief(_) => &[],
+            Tags::Detailed { tags, .. } => tags,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -104,7 +110,7 @@ pub struct FetchWorkBrief {
     user_name: String,
     user_account: Option<String>,
 
-    bookmark_data: BookmarkData,
+    bookmark_data: Option<BookmarkData>, // null if not bookmarked
     create_date: chrono::DateTime<chrono::FixedOffset>,
     #[serde(alias = "uploadDate")] // Detail field
     update_date: chrono::DateTime<chrono::FixedOffset>,
@@ -121,18 +127,18 @@ pub struct FetchWorkBrief {
     ai_type: AIType,
 }
 
-impl Into<Illust> for FetchWorkBrief {
-    fn into(self) -> Illust {
+impl From<FetchWorkBrief> for Illust {
+    fn from(val: FetchWorkBrief) -> Self {
         assert!(
-            !self.is_unlisted || !self.is_masked,
+            !val.is_unlisted || !val.is_masked,
             "self cannot be both unlisted and masked"
         );
 
-        let state = if self.is_unlisted {
-            tracing::warn!("Unlisted self {:?}", self);
+        let state = if val.is_unlisted {
+            tracing::warn!("Unlisted self {:?}", val);
             IllustState::Unlisted
-        } else if self.is_masked {
-            tracing::warn!("Masked self {}", self.id);
+        } else if val.is_masked {
+            tracing::warn!("Masked self {}", val.id);
             IllustState::Masked
         } else {
             IllustState::Normal
@@ -140,33 +146,33 @@ impl Into<Illust> for FetchWorkBrief {
 
         let data = if let IllustState::Normal = state {
             IllustData::Simple(IllustDataSimple {
-                title: self.title,
-                tags: self.tags,
+                title: val.title,
+                tags: val.tags,
                 author: Illustrator {
-                    id: self.user_id,
-                    name: self.user_name,
-                    account: self.user_account,
+                    id: val.user_id,
+                    name: val.user_name,
+                    account: val.user_account,
                 },
-                create_date: self.create_date,
-                update_date: self.update_date,
-                x_restrict: self.x_restrict,
-                ai_type: self.ai_type,
+                create_date: val.create_date,
+                update_date: val.update_date,
+                x_restrict: val.x_restrict,
+                ai_type: val.ai_type,
 
-                illust_type: self.illust_type,
-                page_count: self.page_count,
+                illust_type: val.illust_type,
+                page_count: val.page_count,
             })
         } else {
             IllustData::Unknown
         };
 
         Illust {
-            id: self.id,
+            id: val.id,
             data,
             state,
-            bookmark: Some(IllustBookmarkState {
-                id: self.bookmark_data.id,
+            bookmark: val.bookmark_data.map(|b| IllustBookmarkState {
+                id: b.id,
                 tags: IllustBookmarkTags::Unknown,
-                private: self.bookmark_data.private,
+                private: b.private,
             }),
         }
     }
@@ -179,9 +185,7 @@ pub struct PageUrls {
     pub mini: String,
     #[expect(unused)]
     pub thumb: Option<String>,
-    #[expect(unused)]
     pub small: String,
-    #[expect(unused)]
     pub regular: String,
 
     pub original: String,
@@ -194,6 +198,14 @@ pub struct Page {
     pub height: u64,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FetchSeriesNav {
+    #[serde(deserialize_with = "super::de_str_to_u64")]
+    pub series_id: u64,
+    pub order: u64,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FetchWorkDetail {
@@ -206,15 +218,11 @@ pub struct FetchWorkDetail {
     #[serde(flatten)]
     pub brief: FetchWorkBrief,
 
-    #[expect(unused)]
     pub bookmark_count: u64,
-    #[expect(unused)]
     pub like_count: u64,
-    #[expect(unused)]
     pub comment_count: u64,
     #[expect(unused)]
     pub response_count: u64,
-    #[expect(unused)]
     pub view_count: u64,
 
     #[expect(unused)]
@@ -222,22 +230,35 @@ pub struct FetchWorkDetail {
 
     pub is_howto: bool,
     pub is_original: bool,
+
+    #[serde(default)]
+    pub series_nav_data: Option<FetchSeriesNav>,
 }
 
-impl Into<Illust> for FetchWorkDetail {
-    fn into(self) -> Illust {
-        let mut illust: Illust = self.brief.into();
+impl From<FetchWorkDetail> for Illust {
+    fn from(val: FetchWorkDetail) -> Self {
+        let mut illust: Illust = val.brief.into();
         match illust.data {
             IllustData::Unknown => {}
             IllustData::Simple(brief) => {
-                assert_eq!(self.illust_comment, self.description);
-                assert_eq!(self.illust_id, illust.id);
-                assert_eq!(self.illust_title, brief.title);
+                assert_eq!(val.illust_comment, val.description);
+                assert_eq!(val.illust_id, illust.id);
+                assert_eq!(val.illust_title, brief.title);
 
                 let extra = IllustDataDetail {
-                    desc: self.description,
-                    is_howto: self.is_howto,
-                    is_original: self.is_original,
+                    desc: val.description,
+                    is_howto: val.is_howto,
+                    is_original: val.is_original,
+                    series: val.series_nav_data.map(|s| IllustSeries {
+                        id: s.series_id,
+                        order: s.order,
+                    }),
+                    stats: Some(IllustStats {
+                        bookmark_count: val.bookmark_count,
+                        like_count: val.like_count,
+                        comment_count: val.comment_count,
+                        view_count: val.view_count,
+                    }),
                 };
                 illust.data = IllustData::Detailed(brief, extra);
             }
@@ -287,11 +308,128 @@ where
     deserializer.deserialize_any(BookmarkTagsVisitor)
 }
 
+/// Deserialize a work id set, which is either a map from ids to null, or an empty ARRAY
+fn de_work_ids<'de, D>(deserializer: D) -> Result<Vec<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct WorkIdsVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for WorkIdsVisitor {
+        type Value = Vec<u64>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("map of numbers to anything or an empty array")
+        }
+
+        fn visit_map<M>(self, mut access: M) -> Result<Self::Value, M::Error>
+        where
+            M: serde::de::MapAccess<'de>,
+        {
+            let mut ids = Vec::new();
+            while let Some((key, _)) = access.next_entry::<&str, IgnoredAny>()? {
+                let key = key.parse::<u64>().map_err(serde::de::Error::custom)?;
+                ids.push(key);
+            }
+            Ok(ids)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            if seq.next_element::<IgnoredAny>()?.is_some() {
+                return Err(serde::de::Error::custom("Expected empty array"));
+            }
+            Ok(Vec::new())
+        }
+    }
+
+    deserializer.deserialize_any(WorkIdsVisitor)
+}
+
 #[derive(Deserialize)]
+pub struct UserWorks {
+    #[serde(deserialize_with = "de_work_ids")]
+    pub illusts: Vec<u64>,
+    #[serde(deserialize_with = "de_work_ids")]
+    pub manga: Vec<u64>,
+}
+
+#[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct Bookmarks {
+pub struct FetchNovelBrief {
+    #[serde(deserialize_with = "super::de_str_or_u64_to_u64")]
+    id: u64,
+    title: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    x_restrict: XRestrict,
+
+    #[serde(deserialize_with = "super::de_str_or_u64_to_u64")]
+    user_id: u64,
+    user_name: String,
+
+    text_count: u64,
+    #[serde(default)]
+    description: String,
+    #[serde(deserialize_with = "super::de_nullable_str_to_u64_opt", default)]
+    series_id: Option<u64>,
+
+    bookmark_data: Option<BookmarkData>, // null if not bookmarked
+    create_date: chrono::DateTime<chrono::FixedOffset>,
+    update_date: chrono::DateTime<chrono::FixedOffset>,
+
+    #[serde(default)]
+    is_masked: bool,
+    ai_type: AIType,
+}
+
+impl From<FetchNovelBrief> for Novel {
+    fn from(val: FetchNovelBrief) -> Self {
+        let data = if val.is_masked {
+            tracing::warn!("Masked novel {}", val.id);
+            None
+        } else {
+            Some(NovelData {
+                title: val.title,
+                tags: val.tags,
+                author: Illustrator {
+                    id: val.user_id,
+                    name: val.user_name,
+                    account: None,
+                },
+                description: val.description,
+                text_count: val.text_count,
+                series_id: val.series_id,
+                create_date: val.create_date,
+                update_date: val.update_date,
+                x_restrict: val.x_restrict,
+                ai_type: val.ai_type,
+            })
+        };
+
+        Novel {
+            id: val.id,
+            data,
+            bookmark: val.bookmark_data.map(|b| NovelBookmarkState {
+                id: b.id,
+                private: b.private,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FetchNovelDetail {
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bookmarks<W = FetchWorkBrief> {
     pub total: usize,
-    pub works: Vec<FetchWorkBrief>,
+    pub works: Vec<W>,
     #[serde(deserialize_with = "de_bookmark_tags")]
     pub bookmark_tags: HashMap<u64, Vec<String>>,
 }
@@ -311,6 +449,50 @@ impl Bookmarks {
     }
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum RankingMode {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl RankingMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            RankingMode::Daily => "daily",
+            RankingMode::Weekly => "weekly",
+            RankingMode::Monthly => "monthly",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RankingEntry {
+    pub illust_id: u64,
+    pub rank: u64,
+    pub title: String,
+}
+
+/// `next` is the next page number, or `false` on the last page
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RankingNext {
+    Page(u64),
+    End(#[expect(unused)] bool),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RankingResponse {
+    Page {
+        contents: Vec<RankingEntry>,
+        next: RankingNext,
+    },
+    Errored {
+        error: String,
+    },
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct UgoiraFrame {
     pub file: String,
@@ -385,6 +567,10 @@ impl<T> Response<T> {
     }
 }
 
+/// Authenticates requests to pixiv, including file downloads from its CDN
+///
+/// `i.pximg.net` rejects requests without a pixiv `Referer` with 403, so it's always sent.
+#[derive(Clone, Copy)]
 pub struct PixivRequest<'a>(pub &'a Session);
 
 impl RequestArgumenter for PixivRequest<'_> {
@@ -394,29 +580,31 @@ impl RequestArgumenter for PixivRequest<'_> {
             .pixiv
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("Pixiv session is required"))?;
-        Ok(req
+        Ok(self
+            .0
+            .fingerprint(req)
             .header("Cookie", format!("PHPSESSID={};", pixiv_session.cookie))
-            .header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/58.0.3029.110 Safari/537.36")
-            .header("Referer", "https://www.pixiv.net/")
-        )
+            .header("Referer", "https://www.pixiv.net/"))
     }
 }
 
-async fn get_bookmarks_page(
+/// Fetch a page of bookmarked works, where `kind` is the path segment, i.e. `illusts` or `novels`
+async fn get_bookmarks_page<W: serde::de::DeserializeOwned>(
     session: &Session,
+    kind: &str,
     tag: Option<&str>,
     hidden: bool,
     offset: usize,
     limit: usize,
-) -> anyhow::Result<Bookmarks> {
+) -> anyhow::Result<Bookmarks<W>> {
     let pixiv_session = session
         .pixiv
         .as_ref()
         .ok_or_else(|| anyhow::anyhow!("Pixiv session is required"))?;
 
     let url = format!(
-        "https://www.pixiv.net/ajax/user/{}/illusts/bookmarks",
-        pixiv_session.uid,
+        "https://www.pixiv.net/ajax/user/{}/{}/bookmarks",
+        pixiv_session.uid, kind,
     );
 
     let req = |client: &wreq::Client| {
@@ -432,13 +620,13 @@ async fn get_bookmarks_page(
             ])
             .build()?)
     };
-    let json: Response<Bookmarks> = crate::fetch::fetch(req).await?;
+    let json: Response<Bookmarks<W>> = crate::fetch::fetch(req).await?;
     json.into_body()
 }
 
 // Parsed data
 
-#[derive(clap::ValueEnum, sqlx::Type, Debug, Clone, Copy)]
+#[derive(Deserialize_repr, clap::ValueEnum, sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum IllustState {
     Normal = 0,
@@ -446,6 +634,31 @@ pub enum IllustState {
     Masked = 2,
 }
 
+/// Which rendition of a page is stored in an image row
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ImageVariant {
+    Original = 0,
+    Small = 1,
+    Regular = 2,
+}
+
+impl ImageVariant {
+    pub fn is_thumbnail(self) -> bool {
+        !matches!(self, ImageVariant::Original)
+    }
+}
+
+impl PageUrls {
+    pub fn get(&self, variant: ImageVariant) -> &str {
+        match variant {
+            ImageVariant::Original => &self.original,
+            ImageVariant::Small => &self.small,
+            ImageVariant::Regular => &self.regular,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Illustrator {
     pub id: u64,
@@ -467,11 +680,28 @@ pub struct IllustDataSimple {
     pub page_count: u64,
 }
 
+#[derive(Debug)]
+pub struct IllustSeries {
+    pub id: u64,
+    pub order: u64, // 1-based position within the series
+}
+
+#[derive(Debug)]
+pub struct IllustStats {
+    pub bookmark_count: u64,
+    pub like_count: u64,
+    pub comment_count: u64,
+    pub view_count: u64,
+}
+
 #[derive(Debug)]
 pub struct IllustDataDetail {
     pub desc: String,
     pub is_howto: bool,
     pub is_original: bool,
+    pub series: Option<IllustSeries>,
+    /// Unknown for imported illustrations
+    pub stats: Option<IllustStats>,
 }
 
 #[derive(Debug)]
@@ -525,30 +755,206 @@ pub struct Illust {
     pub bookmark: Option<IllustBookmarkState>,
 }
 
-pub async fn get_bookmarks(
-    session: &Session,
-    tag: Option<&str>,
+#[derive(Debug)]
+pub struct NovelData {
+    pub title: String,
+    pub tags: Vec<String>,
+    pub author: Illustrator,
+    pub description: String,
+    pub text_count: u64,
+    pub series_id: Option<u64>,
+    pub create_date: chrono::DateTime<chrono::FixedOffset>,
+    pub update_date: chrono::DateTime<chrono::FixedOffset>,
+    pub x_restrict: XRestrict,
+    pub ai_type: AIType,
+}
+
+#[derive(Debug)]
+pub struct NovelBookmarkState {
+    pub id: u64,
+    pub private: bool,
+}
+
+#[derive(Debug)]
+pub struct Novel {
+    pub id: u64,
+    pub data: Option<NovelData>, // None if masked
+    pub bookmark: Option<NovelBookmarkState>,
+}
+
+impl Novel {
+    pub fn display_title(&self) -> &str {
+        self.data
+            .as_ref()
+            .map(|d| d.title.as_str())
+            .unwrap_or("(unknown)")
+    }
+}
+
+/// An illustration as written by `database export -f jsonl`
+///
+/// Columns that `update_illust` doesn't write (fetch timestamps, `corrupted`) are ignored.
+#[derive(Deserialize)]
+pub struct ExportedIllust {
+    id: u64,
+    illust_state: IllustState,
+
+    title: Option<String>,
+    author_id: Option<u64>,
+    author_name: Option<String>,
+    author_account: Option<String>,
+    illust_type: Option<IllustType>,
+    page_count: Option<u64>,
+    create_date: Option<String>,
+    update_date: Option<String>,
+    x_restrict: Option<XRestrict>,
+    ai_type: Option<AIType>,
+    #[serde(default)]
+    tags: Vec<String>,
+
+    bookmark_id: Option<u64>,
+    #[serde(default, deserialize_with = "super::de_bool_or_int_opt")]
+    bookmark_private: Option<bool>,
+    bookmark_tags: Option<Vec<String>>,
+
+    series_id: Option<u64>,
+    series_order: Option<u64>,
+    content_desc: Option<String>,
+    #[serde(default, deserialize_with = "super::de_bool_or_int_opt")]
+    content_is_howto: Option<bool>,
+    #[serde(default, deserialize_with = "super::de_bool_or_int_opt")]
+    content_is_original: Option<bool>,
+}
+
+impl TryFrom<ExportedIllust> for Illust {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ExportedIllust) -> Result<Self, Self::Error> {
+        fn required<T>(field: Option<T>, name: &str) -> anyhow::Result<T> {
+            field.ok_or_else(|| anyhow::anyhow!("missing field `{}`", name))
+        }
+        fn date(
+            field: Option<String>,
+            name: &str,
+        ) -> anyhow::Result<chrono::DateTime<chrono::FixedOffset>> {
+            let field = required(field, name)?;
+            let date = crate::util::parse_cli_datetime(&field)
+                .map_err(|e| anyhow::anyhow!("field `{}`: {}", name, e))?;
+            Ok(date.fixed_offset())
+        }
+
+        // Masked illusts have no data at all, which shows up as a missing title
+        let data = match value.title {
+            None => IllustData::Unknown,
+            Some(title) => {
+                let simple = IllustDataSimple {
+                    title,
+                    tags: Tags::Brief(value.tags),
+                    author: Illustrator {
+                        id: required(value.author_id, "author_id")?,
+                        name: required(value.author_name, "author_name")?,
+                        account: value.author_account,
+                    },
+                    create_date: date(value.create_date, "create_date")?,
+                    update_date: date(value.update_date, "update_date")?,
+                    x_restrict: required(value.x_restrict, "x_restrict")?,
+                    ai_type: required(value.ai_type, "ai_type")?,
+                    illust_type: required(value.illust_type, "illust_type")?,
+                    page_count: required(value.page_count, "page_count")?,
+                };
+                match value.content_desc {
+                    None => IllustData::Simple(simple),
+                    Some(desc) => {
+                        let series = match (value.series_id, value.series_order) {
+                            (Some(id), Some(order)) => Some(IllustSeries { id, order }),
+                            _ => None,
+                        };
+                        let detail = IllustDataDetail {
+                            desc,
+                            is_howto: required(value.content_is_howto, "content_is_howto")?,
+                            is_original: required(
+                                value.content_is_original,
+                                "content_is_original",
+                            )?,
+                            series,
+                            stats: None,
+                        };
+                        IllustData::Detailed(simple, detail)
+                    }
+                }
+            }
+        };
+
+        let bookmark = match value.bookmark_id {
+            None => None,
+            Some(id) => Some(IllustBookmarkState {
+                id,
+                private: required(value.bookmark_private, "bookmark_private")?,
+                tags: match value.bookmark_tags {
+                    Some(tags) => IllustBookmarkTags::Known(tags),
+                    None => IllustBookmarkTags::Unknown,
+                },
+            }),
+        };
+
+        Ok(Illust {
+            id: value.id,
+            data,
+            state: value.illust_state,
+            bookmark,
+        })
+    }
+}
+
+/// Stream pages of bookmarked works until the end of the list, see `get_bookmarks_page` for `kind`
+fn get_bookmark_pages<'a, W: serde::de::DeserializeOwned + 'a>(
+    session: &'a Session,
+    kind: &'a str,
+    tag: Option<&'a str>,
     mut offset: usize,
     hidden: bool,
-) -> impl futures::Stream<Item = anyhow::Result<Illust>> {
-    const LIMIT: usize = 48;
+    limit: usize,
+) -> impl futures::Stream<Item = anyhow::Result<Bookmarks<W>>> + 'a {
+    // Pixiv sometimes returns an empty page mid-pagination, which usually goes away on retry
+    const EMPTY_BATCH_RETRIES: usize = 3;
 
     try_stream! {
+        let mut empty_batches = 0;
         loop {
-            let batch = get_bookmarks_page(session, tag, hidden, offset, LIMIT).await?;
+            let batch: Bookmarks<W> = crate::retry::with_retries(
+                crate::retry::RetryPolicy::global(),
+                format!("{} bookmarks at offset {}", kind, offset),
+                || get_bookmarks_page(session, kind, tag, hidden, offset, limit),
+            )
+            .await?;
             let total = batch.total;
             let batch_size = batch.works.len();
 
-            for illust in batch.into_illusts() {
-                yield illust;
-            }
+            yield batch;
 
             offset += batch_size;
             if offset < total && batch_size == 0 {
-                tracing::warn!("Empty batch before reaching end of bookmark list.");
+                if empty_batches == EMPTY_BATCH_RETRIES {
+                    Err(anyhow::anyhow!(
+                        "Got empty batches at offset {} of {} bookmarks after {} retries, the sync is incomplete",
+                        offset,
+                        total,
+                        EMPTY_BATCH_RETRIES
+                    ))?;
+                }
+                empty_batches += 1;
+                tracing::warn!(
+                    "Empty batch at offset {} before reaching end of bookmark list ({}), retrying ({}/{})",
+                    offset,
+                    total,
+                    empty_batches,
+                    EMPTY_BATCH_RETRIES
+                );
+                continue;
             }
+            empty_batches = 0;
 
-            if offset >= total || batch_size == 0 {
+            if offset >= total {
                 break;
             }
 
@@ -557,6 +963,57 @@ pub async fn get_bookmarks(
     }
 }
 
+pub async fn get_bookmarks(
+    session: &Session,
+    tag: Option<&str>,
+    offset: usize,
+    hidden: bool,
+) -> impl futures::Stream<Item = anyhow::Result<Illust>> {
+    const LIMIT: usize = 48;
+
+    try_stream! {
+        let pages = get_bookmark_pages::<FetchWorkBrief>(session, "illusts", tag, offset, hidden, LIMIT);
+        for await batch in pages {
+            for illust in batch?.into_illusts() {
+                yield illust;
+            }
+        }
+    }
+}
+
+pub async fn get_novel_bookmarks(
+    session: &Session,
+    tag: Option<&str>,
+    offset: usize,
+    hidden: bool,
+) -> impl futures::Stream<Item = anyhow::Result<Novel>> {
+    // The novel bookmark page is limited to 24 works per request
+    const LIMIT: usize = 24;
+
+    try_stream! {
+        let pages = get_bookmark_pages::<FetchNovelBrief>(session, "novels", tag, offset, hidden, LIMIT);
+        for await batch in pages {
+            for work in batch?.works {
+                yield Novel::from(work);
+            }
+        }
+    }
+}
+
+/// Fetch the text of a novel
+pub async fn get_novel_content(session: &Session, novel_id: u64) -> anyhow::Result<String> {
+    let url = format!("https://www.pixiv.net/ajax/novel/{}", novel_id);
+
+    let req = |client: &wreq::Client| {
+        Ok(client
+            .get(&url)
+            .prepare_with(PixivRequest(session))?
+            .build()?)
+    };
+    let json: Response<FetchNovelDetail> = crate::fetch::fetch(req).await?;
+    Ok(json.into_body()?.content)
+}
+
 pub async fn get_illust(session: &Session, illust_id: u64) -> anyhow::Result<Illust> {
     let url = format!("https://www.pixiv.net/ajax/illust/{}", illust_id);
 
@@ -567,8 +1024,111 @@ pub async fn get_illust(session: &Session, illust_id: u64) -> anyhow::Result<Ill
             .build()?)
     };
     let json: Response<FetchWorkDetail> = crate::fetch::fetch(req).await?;
-    let detail = json.into_body()?;
-    Ok(detail.into())
+    match json {
+        Response::Success { body } => Ok(body.into()),
+        Response::Errored { message } => Err(IllustError::from_message(illust_id, message).into()),
+    }
+}
+
+/// Why pixiv refused to return an illustration
+#[derive(thiserror::Error, Debug)]
+pub enum IllustError {
+    #[error(
+        "Illustration {id} is age-restricted, check the pixiv cookie and whether R-18 works are enabled in the account settings: {message}"
+    )]
+    AgeRestricted { id: u64, message: String },
+    #[error("Illustration {id} was deleted or doesn't exist: {message}")]
+    NotFound { id: u64, message: String },
+    #[error("API error for illustration {id}: {message}")]
+    Api { id: u64, message: String },
+}
+
+impl IllustError {
+    /// Classify by the error message, which is in Japanese or English depending on the account language
+    fn from_message(id: u64, message: String) -> Self {
+        let lower = message.to_lowercase();
+        if ["r-18", "r18", "sensitive", "年齢", "センシティブ"]
+            .iter()
+            .any(|m| lower.contains(m))
+        {
+            IllustError::AgeRestricted { id, message }
+        } else if ["deleted", "does not exist", "削除", "存在しない"]
+            .iter()
+            .any(|m| lower.contains(m))
+        {
+            IllustError::NotFound { id, message }
+        } else {
+            IllustError::Api { id, message }
+        }
+    }
+}
+
+/// Get all entries of a ranking, highest rank first
+///
+/// `date` selects the ranking that ended on that day, otherwise the latest one.
+pub async fn get_ranking(
+    session: &Session,
+    mode: RankingMode,
+    date: Option<chrono::NaiveDate>,
+) -> anyhow::Result<Vec<RankingEntry>> {
+    let date = date.map(|d| d.format("%Y%m%d").to_string());
+    let mut entries = Vec::new();
+    let mut page = 1;
+    loop {
+        let req = |client: &wreq::Client| {
+            let mut query = vec![
+                ("mode", mode.as_str().to_owned()),
+                ("format", "json".to_owned()),
+                ("p", page.to_string()),
+            ];
+            if let Some(date) = &date {
+                query.push(("date", date.clone()));
+            }
+            Ok(client
+                .get("https://www.pixiv.net/ranking.php")
+                .prepare_with(PixivRequest(session))?
+                .query(&query)
+                .build()?)
+        };
+        let resp: RankingResponse = crate::retry::with_retries(
+            crate::retry::RetryPolicy::global(),
+            format!("{} ranking page {}", mode.as_str(), page),
+            || crate::fetch::fetch(req),
+        )
+        .await?;
+        match resp {
+            RankingResponse::Errored { error } => {
+                return Err(anyhow::anyhow!("Ranking error: {}", error));
+            }
+            RankingResponse::Page { contents, next } => {
+                entries.extend(contents);
+                match next {
+                    RankingNext::Page(next) => page = next,
+                    RankingNext::End(_) => break,
+                }
+            }
+        }
+        tracing::info!("Fetched {} ranking entries", entries.len());
+    }
+    Ok(entries)
+}
+
+/// Get ids of all illustrations and manga by a user, newest first
+pub async fn get_user_works(session: &Session, user_id: u64) -> anyhow::Result<Vec<u64>> {
+    let url = format!("https://www.pixiv.net/ajax/user/{}/profile/all", user_id);
+
+    let req = |client: &wreq::Client| {
+        Ok(client
+            .get(&url)
+            .prepare_with(PixivRequest(session))?
+            .build()?)
+    };
+    let json: Response<UserWorks> = crate::fetch::fetch(req).await?;
+    let works = json.into_body()?;
+    let mut ids = works.illusts;
+    ids.extend(works.manga);
+    ids.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(ids)
 }
 
 pub async fn get_illust_pages(session: &Session, illust_id: u64) -> anyhow::Result<Vec<Page>> {
@@ -1,15 +1,18 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use serde::Serialize;
 use sqlx::{
-    SqlitePool,
+    Connection, SqliteConnection, SqlitePool,
     migrate::{Migrate, Migrator},
-    sqlite::{SqliteConnectOptions, SqliteRow},
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow, SqliteSynchronous,
+    },
 };
 
 use crate::data::{
     fanbox,
-    pixiv::{IllustBookmarkTags, IllustState, UgoiraFrame},
+    pixiv::{IllustBookmarkTags, IllustState, ImageVariant, UgoiraFrame},
 };
 
 struct TagIterator<I: Iterator<Item = u64> + Clone>(I);
@@ -19,15 +22,40 @@ impl<I: Iterator<Item = u64> + Clone> Serialize for TagIterator<I> {
     }
 }
 
-// TODO: detect completely unchanged
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum IllustUpdateResult {
     Inserted,
     BookmarkIDChanged,
     Updated,
+    /// Already existing, and the fetched data is identical to the stored one
+    Unchanged,
     Skipped,
 }
 
+impl IllustUpdateResult {
+    /// Short label for logging
+    pub fn label(self) -> &'static str {
+        match self {
+            IllustUpdateResult::Inserted => "INSERTED",
+            IllustUpdateResult::BookmarkIDChanged => "BMIDCHANGED",
+            IllustUpdateResult::Updated => "UPDATED",
+            IllustUpdateResult::Unchanged => "UNCHANGED",
+            IllustUpdateResult::Skipped => "SKIPPED",
+        }
+    }
+}
+
+/// What to keep of a fanbox post body
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FanboxBodyStorage {
+    /// As fetched, i.e. serialized blocks for rich bodies
+    Full,
+    /// Rich bodies are replaced by their plain text, the same as `body_text`. Other bodies are kept as-is.
+    PlainText,
+    /// An empty body, still marking the post as accessible
+    Empty,
+}
+
 #[derive(PartialEq, Eq)]
 pub enum FanboxPostUpdateResult {
     Inserted,
@@ -35,17 +63,41 @@ pub enum FanboxPostUpdateResult {
     Skipped,
 }
 
+#[derive(PartialEq, Eq)]
+pub enum NovelUpdateResult {
+    Inserted,
+    Updated,
+    Unchanged,
+    Skipped,
+}
+
+/// A value bound to a `?` placeholder in a hand-built query
+#[derive(Clone)]
+pub enum QueryParam {
+    Int(i64),
+    Text(String),
+}
+
+impl std::fmt::Display for QueryParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryParam::Int(i) => write!(f, "{}", i),
+            QueryParam::Text(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
 pub struct FanboxPostStatus {
     pub updated_datetime: chrono::DateTime<chrono::Utc>,
-    pub body_null: bool,
+    pub is_restricted: bool,
 }
 
 impl FanboxPostStatus {
     pub fn needs_update(&self, new: &fanbox::FetchPost) -> bool {
-        if self.body_null && !new.is_restricted {
+        if self.is_restricted && !new.is_restricted {
             return true;
         }
-        if !self.body_null && new.is_restricted {
+        if !self.is_restricted && new.is_restricted {
             return false;
         }
 
@@ -68,6 +120,10 @@ pub struct FanboxFileDownloadSpec {
     pub post_id: i64,
     pub ext: String,
     pub idx: i64,
+    /// Creator alias, or creator ID if not aliased
+    pub creator_dir: String,
+    pub creator_id: String,
+    pub post_title: String,
 }
 
 pub struct FanboxImageDownloadSpec {
@@ -75,6 +131,83 @@ pub struct FanboxImageDownloadSpec {
     pub post_id: i64,
     pub ext: String,
     pub idx: i64,
+    /// Creator alias, or creator ID if not aliased
+    pub creator_dir: String,
+    pub creator_id: String,
+    pub post_title: String,
+}
+
+pub struct FanboxCoverDownloadSpec {
+    pub url: String,
+    /// Creator alias, or creator ID if not aliased
+    pub creator_dir: String,
+}
+
+/// Metadata of an illustration, written next to downloaded images by `download --sidecar`, and filling `download --output-template`
+#[derive(Serialize)]
+pub struct IllustSidecar {
+    pub illust_id: i64,
+    pub title: Option<String>,
+    pub author_id: Option<i64>,
+    pub author_name: Option<String>,
+    pub author_account: Option<String>,
+    /// RFC 3339, in UTC
+    pub create_date: Option<String>,
+    /// `public`, `r18` or `r18g`
+    pub x_restrict: Option<&'static str>,
+    pub tags: Vec<String>,
+}
+
+/// A downloaded original image, with the metadata to fill a view template
+pub struct ImageLinkEntry {
+    pub illust_id: i64,
+    pub page: i64,
+    pub path: String,
+    pub title: Option<String>,
+    pub author_id: Option<i64>,
+    pub author_name: Option<String>,
+    pub author_account: Option<String>,
+    pub first_tag: Option<String>,
+    pub first_bookmark_tag: Option<String>,
+}
+
+pub struct FanboxImageDimensionEntry {
+    pub id: String,
+    pub post_id: i64,
+    pub idx: i64,
+    pub path: Option<String>,
+    pub width: i64,
+    pub height: i64,
+}
+
+pub struct FanboxFileSizeEntry {
+    pub id: String,
+    pub post_id: i64,
+    pub idx: i64,
+    pub path: Option<String>,
+    pub size: i64,
+}
+
+pub struct ImageDimensionEntry {
+    pub illust_id: i64,
+    pub page: i64,
+    pub path: String,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub variant: ImageVariant,
+    pub is_ugoira: bool,
+}
+
+pub struct PruneImageEntry {
+    pub illust_id: i64,
+    pub page: i64,
+    pub path: String,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub hash: Option<String>,
+    pub variant: ImageVariant,
+    pub is_ugoira: bool,
+    pub thumbnail_path: Option<String>,
 }
 
 pub struct DownloadPathEntry<ID> {
@@ -85,11 +218,205 @@ pub struct DownloadPathEntry<ID> {
 static MIGRATOR: Migrator = sqlx::migrate!();
 pub struct Database {
     db: SqlitePool,
+    in_memory: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl From<JournalMode> for SqliteJournalMode {
+    fn from(mode: JournalMode) -> Self {
+        match mode {
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Off => SqliteJournalMode::Off,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl From<Synchronous> for SqliteSynchronous {
+    fn from(mode: Synchronous) -> Self {
+        match mode {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        }
+    }
+}
+
+/// Pragmas applied to every connection
+#[derive(Clone, Copy)]
+struct ConnectSettings {
+    journal_mode: JournalMode,
+    synchronous: Synchronous,
+    busy_timeout: std::time::Duration,
+}
+
+static CONNECT_SETTINGS: std::sync::Mutex<ConnectSettings> =
+    std::sync::Mutex::new(ConnectSettings {
+        journal_mode: JournalMode::Wal,
+        synchronous: Synchronous::Normal,
+        busy_timeout: std::time::Duration::from_secs(5),
+    });
+
+/// Set the connection pragmas, applied to databases opened afterwards
+pub fn update_connect_settings(
+    journal_mode: JournalMode,
+    synchronous: Synchronous,
+    busy_timeout_ms: u64,
+) {
+    let settings = ConnectSettings {
+        journal_mode,
+        synchronous,
+        busy_timeout: std::time::Duration::from_millis(busy_timeout_ms),
+    };
+    *CONNECT_SETTINGS.lock().unwrap() = settings;
+}
+
+/// Resolve the file path in a sqlite URL to an absolute one, so it no longer depends on the current directory
+///
+/// Accepts `sqlite://<PATH>` and `sqlite:<PATH>`, and returns the `sqlite://<ABSOLUTE PATH>` form.
+/// Query parameters such as `?mode=rwc` are kept. In-memory URLs are returned unchanged.
+pub fn canonicalize_url(url: &str) -> anyhow::Result<String> {
+    let Some(rest) = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+    else {
+        return Err(anyhow::anyhow!(
+            "Unsupported database URL {}, expected sqlite://<PATH>",
+            url
+        ));
+    };
+    let (path, params) = match rest.split_once('?') {
+        Some((path, params)) => (path, Some(params)),
+        None => (rest, None),
+    };
+    let in_memory =
+        path == ":memory:" || params.is_some_and(|p| p.split('&').any(|kv| kv == "mode=memory"));
+    if in_memory || path.is_empty() {
+        return Ok(url.to_owned());
+    }
+
+    // The path is percent-encoded, which doesn't affect whether it's absolute
+    let path = if Path::new(path).is_absolute() {
+        path.to_owned()
+    } else {
+        let cwd = std::env::current_dir()?;
+        let cwd = cwd
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Non UTF-8 working directory {}", cwd.display()))?;
+        let cwd = cwd
+            .replace('%', "%25")
+            .replace('?', "%3F")
+            .replace('#', "%23");
+        let mut rel = path;
+        while let Some(stripped) = rel.strip_prefix("./") {
+            rel = stripped;
+        }
+        format!("{}/{}", cwd.trim_end_matches(['/', '\\']), rel)
+    };
+
+    Ok(match params {
+        Some(params) => format!("sqlite://{}?{}", path, params),
+        None => format!("sqlite://{}", path),
+    })
+}
+
+fn is_in_memory(url: &str) -> bool {
+    url.contains(":memory:") || url.contains("mode=memory")
+}
+
+/// Open a connection pool to the given URL
+///
+/// In-memory databases (`sqlite::memory:`) are private to each connection, so the pool is
+/// restricted to one connection that is never recycled, keeping the whole database alive.
+async fn connect(url: &str, create_if_missing: bool) -> anyhow::Result<SqlitePool> {
+    let settings = *CONNECT_SETTINGS.lock().unwrap();
+    let opts: SqliteConnectOptions = url.parse()?;
+    let opts = opts
+        .create_if_missing(create_if_missing)
+        .journal_mode(settings.journal_mode.into())
+        .synchronous(settings.synchronous.into())
+        .busy_timeout(settings.busy_timeout);
+    let in_memory = is_in_memory(url);
+    if !in_memory && !create_if_missing && !opts.get_filename().try_exists()? {
+        tracing::warn!(
+            "Database file {} doesn't exist, did you run `pixivdwn database setup`?",
+            opts.get_filename().display()
+        );
+    }
+    let pool = if in_memory {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(opts)
+            .await?
+    } else {
+        SqlitePool::connect_with(opts).await?
+    };
+    Ok(pool)
+}
+
+async fn upsert_tags<S: AsRef<str>>(
+    conn: &mut SqliteConnection,
+    tags: &[S],
+) -> anyhow::Result<HashMap<String, u64>> {
+    let tags_str = serde_json::to_string(&tags.iter().map(AsRef::as_ref).collect::<Vec<_>>())?;
+    // `WHERE true` keeps the parser from taking ON CONFLICT as a join constraint
+    sqlx::query!(
+        "INSERT INTO tags (tag) SELECT value FROM json_each(?) WHERE true ON CONFLICT(tag) DO NOTHING",
+        tags_str
+    )
+    .execute(&mut *conn)
+    .await?;
+    let recs = sqlx::query!(
+        r#"SELECT id as "id!", tag FROM tags WHERE tag IN (SELECT value FROM json_each(?))"#,
+        tags_str
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(recs.into_iter().map(|r| (r.tag, r.id as u64)).collect())
+}
+
+/// Close the database after a command finished, whether it succeeded or not
+///
+/// The command's own error takes precedence over a failure to close.
+pub async fn close_after<T>(db: Database, ret: anyhow::Result<T>) -> anyhow::Result<T> {
+    let closed = db.close().await;
+    match ret {
+        Ok(val) => closed.map(|_| val),
+        Err(e) => {
+            if let Err(close_err) = closed {
+                tracing::warn!("Failed to close database: {}", close_err);
+            }
+            Err(e)
+        }
+    }
 }
 
 impl Database {
     pub async fn load(url: &str) -> anyhow::Result<Database> {
-        let db = SqlitePool::connect(&url).await?;
+        let db = connect(url, false).await?;
 
         let mut conn = db.acquire().await?;
         conn.ensure_migrations_table().await?;
@@ -119,30 +446,127 @@ impl Database {
             }
         }
 
-        Ok(Database { db })
+        Ok(Database {
+            db,
+            in_memory: is_in_memory(url),
+        })
     }
 
     pub async fn setup(url: &str) -> anyhow::Result<Database> {
-        let opts: SqliteConnectOptions = url.parse()?;
-        let opts = opts.create_if_missing(true);
-        let db = SqlitePool::connect_with(opts).await?;
+        let db = connect(url, true).await?;
         MIGRATOR.run(&db).await?;
-        Ok(Database { db })
+        Ok(Database {
+            db,
+            in_memory: is_in_memory(url),
+        })
     }
 
-    pub async fn get_tag_mapping<S: AsRef<str>>(&self, tag: S) -> anyhow::Result<u64> {
-        // Upsert tags one by one, guarantees atomicity
-        let tag = tag.as_ref();
-        let rec = sqlx::query!("INSERT INTO tags (tag) VALUES (?) ON CONFLICT(tag) DO UPDATE SET tag=excluded.tag RETURNING id", tag)
-            .fetch_one(&self.db)
+    /// Checkpoint the WAL and close all connections, so no `-wal` / `-shm` files are left behind
+    pub async fn close(self) -> anyhow::Result<()> {
+        // Connections released right before closing are returned to the pool in the background,
+        // and may end up idle after `close` finished. Closing again picks them up.
+        // SQLite only cleans up the files when the last connection is gone.
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(1);
+        loop {
+            self.db.close().await;
+            if self.db.size() == 0 || tokio::time::Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // Checkpoint from a lone connection, which removes the files as the last one to close
+        let opts = self.db.connect_options();
+        let mut conn = SqliteConnection::connect_with(&opts).await?;
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&mut conn)
             .await?;
-        Ok(rec.id as u64)
+        conn.close().await?;
+        Ok(())
+    }
+
+    /// Path of the database file, or None for in-memory databases
+    pub fn file_path(&self) -> Option<PathBuf> {
+        // sqlx names in-memory databases internally, e.g. `file:sqlx-in-memory-0`
+        let opts = self.db.connect_options();
+        let path = opts.get_filename();
+        (!self.in_memory && !path.as_os_str().is_empty()).then(|| path.to_owned())
+    }
+
+    /// Compact the database with `VACUUM`, or write a compacted copy to `into` with `VACUUM INTO`
+    ///
+    /// `analyze` and `optimize` run `ANALYZE` and `PRAGMA optimize` first, so a copy also carries the statistics.
+    pub async fn vacuum(
+        &self,
+        into: Option<&Path>,
+        analyze: bool,
+        optimize: bool,
+    ) -> anyhow::Result<()> {
+        // VACUUM can't run inside a transaction, and requires no other statement in progress on the connection
+        let mut conn = self.db.acquire().await?;
+        if analyze {
+            sqlx::query("ANALYZE").execute(&mut *conn).await?;
+        }
+        if optimize {
+            sqlx::query("PRAGMA optimize").execute(&mut *conn).await?;
+        }
+        match into {
+            Some(into) => {
+                let into = into
+                    .to_str()
+                    .ok_or_else(|| anyhow::anyhow!("Non UTF-8 path {}", into.display()))?;
+                sqlx::query("VACUUM INTO ?")
+                    .bind(into)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            None => {
+                sqlx::query("VACUUM").execute(&mut *conn).await?;
+                // In WAL mode, the file only shrinks once the WAL is checkpointed
+                sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Upsert tags in a single transaction, and return their IDs
+    pub async fn get_tag_mappings<S: AsRef<str>>(
+        &self,
+        tags: &[S],
+    ) -> anyhow::Result<HashMap<String, u64>> {
+        if tags.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let mut tx = self.db.begin().await?;
+        let mapping = upsert_tags(&mut tx, tags).await?;
+        tx.commit().await?;
+        Ok(mapping)
     }
 
     pub async fn update_illust(
         &self,
         illust: &crate::data::pixiv::Illust,
         tag_map_ctx: &mut HashMap<String, u64>,
+    ) -> anyhow::Result<IllustUpdateResult> {
+        self.update_illust_impl(illust, tag_map_ctx, false).await
+    }
+
+    /// Classify what `update_illust` would do, without writing anything
+    pub async fn preview_update_illust(
+        &self,
+        illust: &crate::data::pixiv::Illust,
+        tag_map_ctx: &mut HashMap<String, u64>,
+    ) -> anyhow::Result<IllustUpdateResult> {
+        self.update_illust_impl(illust, tag_map_ctx, true).await
+    }
+
+    async fn update_illust_impl(
+        &self,
+        illust: &crate::data::pixiv::Illust,
+        tag_map_ctx: &mut HashMap<String, u64>,
+        dry_run: bool,
     ) -> anyhow::Result<IllustUpdateResult> {
         // Update illust content (title, caption, etc.)
 
@@ -151,78 +575,90 @@ impl Database {
             tx: &mut sqlx::Transaction<'static, sqlx::Sqlite>,
             illust_id: u64,
             tags: impl Iterator<Item = u64> + Clone,
-        ) -> anyhow::Result<()> {
+        ) -> anyhow::Result<bool> {
             // Insert new tags
             let illust_id = illust_id as i64;
             let tags_str = serde_json::to_string(&TagIterator(tags.clone()))?;
+            let mut rows_affected = 0;
             for tag in tags {
                 let tag = tag as i64;
-                sqlx::query!(
+                rows_affected += sqlx::query!(
                     "INSERT OR IGNORE INTO illust_tags (illust_id, tag_id) VALUES (?, ?)",
                     illust_id,
                     tag
                 )
                 .execute(&mut **tx)
-                .await?;
+                .await?
+                .rows_affected();
             }
             // Delete tags that are not in the new set
-            sqlx::query!("DELETE FROM illust_tags WHERE illust_id = ? AND tag_id NOT IN (SELECT json_each.value FROM json_each(?))", illust_id, tags_str)
+            rows_affected += sqlx::query!("DELETE FROM illust_tags WHERE illust_id = ? AND tag_id NOT IN (SELECT json_each.value FROM json_each(?))", illust_id, tags_str)
                 .execute(&mut **tx)
-                .await?;
-            Ok(())
+                .await?
+                .rows_affected();
+            Ok(rows_affected > 0)
         }
 
         async fn tag_illust_bookmark(
             tx: &mut sqlx::Transaction<'static, sqlx::Sqlite>,
             illust_id: u64,
             tags: impl Iterator<Item = u64> + Clone,
-        ) -> anyhow::Result<()> {
+        ) -> anyhow::Result<bool> {
             // Insert new tags
             let illust_id = illust_id as i64;
             let tags_str = serde_json::to_string(&TagIterator(tags.clone()))?;
+            let mut rows_affected = 0;
             for tag in tags {
                 let tag = tag as i64;
-                sqlx::query!(
+                rows_affected += sqlx::query!(
                     "INSERT OR IGNORE INTO illust_bookmark_tags (illust_id, tag_id) VALUES (?, ?)",
                     illust_id,
                     tag
                 )
                 .execute(&mut **tx)
-                .await?;
+                .await?
+                .rows_affected();
             }
             // Delete tags that are not in the new set
-            sqlx::query!("DELETE FROM illust_bookmark_tags WHERE illust_id = ? AND tag_id NOT IN (SELECT json_each.value FROM json_each(?))", illust_id, tags_str)
+            rows_affected += sqlx::query!("DELETE FROM illust_bookmark_tags WHERE illust_id = ? AND tag_id NOT IN (SELECT json_each.value FROM json_each(?))", illust_id, tags_str)
                 .execute(&mut **tx)
-                .await?;
-            Ok(())
+                .await?
+                .rows_affected();
+            Ok(rows_affected > 0)
         }
 
-        // Before locking the database, upsert all tags
+        // Before locking the database, upsert all tags not seen yet
+        let mut missing_tags: Vec<&str> = Vec::new();
         if let Some(inner) = illust.data.as_simple() {
-            for t in inner.tags.tag_names() {
-                if tag_map_ctx.contains_key(t) {
-                    continue;
-                }
-                let id = self.get_tag_mapping(t).await?;
-                tag_map_ctx.insert(t.to_owned(), id);
-            }
+            missing_tags.extend(inner.tags.tag_names());
         }
-
         if let Some(inner) = illust.bookmark.as_ref()
             && let IllustBookmarkTags::Known(tags) = &inner.tags
         {
-            for t in tags.iter() {
-                if tag_map_ctx.contains_key(t.as_str()) {
-                    continue;
-                }
-                let id = self.get_tag_mapping(t).await?;
-                tag_map_ctx.insert(t.clone(), id);
-            }
+            missing_tags.extend(tags.iter().map(String::as_str));
+        }
+        missing_tags.retain(|t| !tag_map_ctx.contains_key(*t));
+        missing_tags.sort_unstable();
+        missing_tags.dedup();
+
+        if !dry_run {
+            tag_map_ctx.extend(self.get_tag_mappings(&missing_tags).await?);
         }
 
         // Start transaction
         let mut tx = self.db.begin().await?;
 
+        // In a dry run, new tags are rolled back with everything else, so their IDs must not be cached
+        let preview_tag_map;
+        let tag_map_ctx: &HashMap<String, u64> = if dry_run && !missing_tags.is_empty() {
+            let mut map = tag_map_ctx.clone();
+            map.extend(upsert_tags(&mut tx, &missing_tags).await?);
+            preview_tag_map = map;
+            &preview_tag_map
+        } else {
+            tag_map_ctx
+        };
+
         // Update author first s.t. foreign key is satisfied
         if let Some(inner) = &illust.data.as_simple() {
             let author_id = inner.author.id as i64;
@@ -261,7 +697,8 @@ impl Database {
         // If exists and previously masked, insert anyway
         // If exists and previously unlisted, only update if new state is not masked
         // If exists and previously normal, only update if the new state is normal
-        // Returns whether the illust was "new", in the sense that it was inserted or the bookmark id changed
+        // Returns whether the illust was "new", in the sense that it was inserted or the bookmark id changed,
+        // and whether anything changed at all
 
         let orig = sqlx::query!(
             r#"SELECT bookmark_id, illust_state as "illust_state: IllustState" FROM illusts WHERE id = ?"#,
@@ -282,6 +719,7 @@ impl Database {
         let fetched_illust_type = fetched_data.map(|d| d.illust_type);
         let fetched_page_count = fetched_data.map(|d| d.page_count as i64);
 
+        let mut changed = true;
         let update_type: IllustUpdateResult = if let Some(orig) = &orig {
             // Set last_fetch no matter what
             sqlx::query!(
@@ -303,6 +741,37 @@ impl Database {
                 return Ok(IllustUpdateResult::Skipped);
             }
 
+            // Compare the same way values are stored, so dates are normalized
+            changed = sqlx::query_scalar!(
+                r#"SELECT NOT (
+                    title IS ?
+                    AND author_id IS ?
+                    AND create_date IS datetime(?, 'utc')
+                    AND update_date IS datetime(?, 'utc')
+                    AND x_restrict IS ?
+                    AND ai_type IS ?
+                    AND illust_state IS ?
+                    AND bookmark_id IS ?
+                    AND bookmark_private IS ?
+                    AND illust_type IS ?
+                    AND page_count IS ?
+                ) as "changed!: bool" FROM illusts WHERE id = ?"#,
+                fetched_title,
+                fetched_author_id,
+                fetched_create_date,
+                fetched_update_date,
+                fetched_x_restrict,
+                fetched_ai_type,
+                illust.state,
+                illust_bookmark_id,
+                illust_bookmark_private,
+                fetched_illust_type,
+                fetched_page_count,
+                illust_id,
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
             // Do update
             sqlx::query!(
                 r#"UPDATE illusts SET
@@ -388,16 +857,52 @@ impl Database {
 
         if let Some(detail) = illust.data.as_detail() {
             // Update details
+            let series_id = detail.series.as_ref().map(|s| s.id as i64);
+            let series_order = detail.series.as_ref().map(|s| s.order as i64);
+            // Stats change all the time, so they don't count as a change, and are kept if unknown
+            let bookmark_count = detail.stats.as_ref().map(|s| s.bookmark_count as i64);
+            let like_count = detail.stats.as_ref().map(|s| s.like_count as i64);
+            let comment_count = detail.stats.as_ref().map(|s| s.comment_count as i64);
+            let view_count = detail.stats.as_ref().map(|s| s.view_count as i64);
+            changed |= sqlx::query_scalar!(
+                r#"SELECT NOT (
+                    content_desc IS ?
+                    AND content_is_howto IS ?
+                    AND content_is_original IS ?
+                    AND series_id IS ?
+                    AND series_order IS ?
+                ) as "changed!: bool" FROM illusts WHERE id = ?"#,
+                detail.desc,
+                detail.is_howto,
+                detail.is_original,
+                series_id,
+                series_order,
+                illust_id,
+            )
+            .fetch_one(&mut *tx)
+            .await?;
             let rows_affected = sqlx::query!(
                 r#"UPDATE illusts SET
                     content_desc=?,
                     content_is_howto=?,
                     content_is_original=?,
+                    series_id=?,
+                    series_order=?,
+                    bookmark_count=COALESCE(?, bookmark_count),
+                    like_count=COALESCE(?, like_count),
+                    comment_count=COALESCE(?, comment_count),
+                    view_count=COALESCE(?, view_count),
                     last_successful_content_fetch = last_fetch
                 WHERE id = ?"#,
                 detail.desc,
                 detail.is_howto,
                 detail.is_original,
+                series_id,
+                series_order,
+                bookmark_count,
+                like_count,
+                comment_count,
+                view_count,
                 illust_id,
             )
             .execute(&mut *tx)
@@ -406,27 +911,61 @@ impl Database {
             assert_eq!(rows_affected, 1);
         }
 
-        // TODO: add tag details
         if let Some(inner) = illust.data.as_simple() {
             let tags: Vec<_> = inner.tags.tag_names().map(str::to_owned).collect();
             let tags_iterator = tags.iter().map(|t| *tag_map_ctx.get(t).unwrap());
-            tag_illust(&mut tx, illust.id, tags_iterator).await?;
+            changed |= tag_illust(&mut tx, illust.id, tags_iterator).await?;
+
+            for detail in inner.tags.details() {
+                let tag_id = *tag_map_ctx.get(detail.tag.as_str()).unwrap() as i64;
+                if let Some(romaji) = &detail.romaji {
+                    sqlx::query!("UPDATE tags SET romaji = ? WHERE id = ?", romaji, tag_id)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                for (lang, translation) in detail.translation.iter().flatten() {
+                    sqlx::query!(
+                        r#"INSERT INTO tag_translations (tag_id, lang, translation) VALUES (?, ?, ?)
+                        ON CONFLICT(tag_id, lang) DO UPDATE SET translation = excluded.translation"#,
+                        tag_id,
+                        lang,
+                        translation,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
         }
 
         // Update bookmark tags
-        // FIXME: unbookmarked, should remove tags
-        if let Some(inner) = illust.bookmark.as_ref()
-            && let IllustBookmarkTags::Known(tags) = &inner.tags
-        {
-            let bookmark_tags_iterator = tags.iter().map(|t| *tag_map_ctx.get(t.as_str()).unwrap());
-            tag_illust_bookmark(&mut tx, illust.id, bookmark_tags_iterator).await?;
+        match illust.bookmark.as_ref() {
+            Some(inner) => {
+                if let IllustBookmarkTags::Known(tags) = &inner.tags {
+                    let bookmark_tags_iterator =
+                        tags.iter().map(|t| *tag_map_ctx.get(t.as_str()).unwrap());
+                    changed |=
+                        tag_illust_bookmark(&mut tx, illust.id, bookmark_tags_iterator).await?;
+                }
+            }
+            // Not bookmarked (anymore), like the NULL bookmark columns above
+            None => {
+                changed |= tag_illust_bookmark(&mut tx, illust.id, std::iter::empty()).await?;
+            }
         }
 
-        tx.commit().await?;
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
 
+        if update_type == IllustUpdateResult::Updated && !changed {
+            return Ok(IllustUpdateResult::Unchanged);
+        }
         Ok(update_type)
     }
 
+    #[expect(clippy::too_many_arguments)]
     pub async fn insert_image(
         &self,
         illust: u64,
@@ -436,6 +975,8 @@ impl Database {
         width: u64,
         height: u64,
         ugoira_frames: Option<&Vec<UgoiraFrame>>,
+        variant: ImageVariant,
+        hash: &str,
     ) -> anyhow::Result<()> {
         let illust = illust as i64;
         let page = page as i64;
@@ -446,8 +987,8 @@ impl Database {
             .transpose()?;
 
         sqlx::query!(
-            r#"INSERT INTO images (illust_id, page, url, path, download_date, verified_date, width, height, ugoira_frames)
-            VALUES (?, ?, ?, ?, datetime('now', 'utc'), datetime('now', 'utc'), ?, ?, ?)
+            r#"INSERT INTO images (illust_id, page, url, path, download_date, verified_date, width, height, ugoira_frames, variant, hash)
+            VALUES (?, ?, ?, ?, datetime('now', 'utc'), datetime('now', 'utc'), ?, ?, ?, ?, ?)
             "#,
             illust,
             page,
@@ -456,6 +997,8 @@ impl Database {
             width,
             height,
             ugoira_frames,
+            variant,
+            hash,
         )
         .execute(&self.db)
         .await?;
@@ -485,6 +1028,52 @@ impl Database {
         }
     }
 
+    pub async fn set_illust_corrupted(
+        &self,
+        illust_id: u64,
+        corrupted: bool,
+    ) -> anyhow::Result<()> {
+        let illust_id = illust_id as i64;
+        sqlx::query!(
+            "UPDATE illusts SET corrupted = ? WHERE id = ?",
+            corrupted,
+            illust_id
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Record the generated thumbnail of an image, relative to the pixiv base directory
+    pub async fn update_image_thumbnail(
+        &self,
+        path: &str,
+        thumbnail_path: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let ret = sqlx::query!(
+            r#"UPDATE images SET thumbnail_path = ? WHERE path = ?"#,
+            thumbnail_path,
+            path,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// Paths of downloaded original images to generate thumbnails for, excluding ugoira zips
+    pub async fn query_thumbnail_candidates(&self) -> anyhow::Result<Vec<String>> {
+        let recs = sqlx::query!(
+            r#"SELECT path FROM images WHERE variant = ? AND ugoira_frames IS NULL ORDER BY illust_id, page"#,
+            ImageVariant::Original,
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| r.path)
+        .collect();
+        Ok(recs)
+    }
+
     pub async fn update_image_path_refresh(&self, path: &str) -> anyhow::Result<bool> {
         let ret = sqlx::query!(
             r#"UPDATE images SET verified_date = datetime('now', 'utc') WHERE path = ?"#,
@@ -509,10 +1098,53 @@ impl Database {
         Ok(rec.map(|r| r.illust_type))
     }
 
+    pub async fn get_illust_sidecar(
+        &self,
+        illust_id: u64,
+    ) -> anyhow::Result<Option<IllustSidecar>> {
+        let illust_id = illust_id as i64;
+        let rec = sqlx::query!(
+            r#"SELECT
+                il.id, il.title, il.author_id, a.name as "author_name?", a.account as "author_account?",
+                strftime('%Y-%m-%dT%H:%M:%SZ', il.create_date) as "create_date?: String",
+                il.x_restrict as "x_restrict?: crate::data::pixiv::XRestrict",
+                (
+                    SELECT json_group_array(tag) FROM (
+                        SELECT t.tag FROM illust_tags it JOIN tags t ON t.id = it.tag_id
+                        WHERE it.illust_id = il.id ORDER BY it.rowid
+                    )
+                ) as "tags!: String"
+            FROM illusts il
+            LEFT JOIN authors a ON a.id = il.author_id
+            WHERE il.id = ?"#,
+            illust_id,
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        let Some(rec) = rec else {
+            return Ok(None);
+        };
+        Ok(Some(IllustSidecar {
+            illust_id: rec.id,
+            title: rec.title,
+            author_id: rec.author_id,
+            author_name: rec.author_name,
+            author_account: rec.author_account,
+            create_date: rec.create_date,
+            x_restrict: rec.x_restrict.map(|x| match x {
+                crate::data::pixiv::XRestrict::Public => "public",
+                crate::data::pixiv::XRestrict::R18 => "r18",
+                crate::data::pixiv::XRestrict::R18G => "r18g",
+            }),
+            tags: serde_json::from_str(&rec.tags)?,
+        }))
+    }
+
     pub async fn get_image_existing_for(
         &self,
         illust_id: u64,
-    ) -> anyhow::Result<impl Iterator<Item = (usize, String)> + '_> {
+        variant: ImageVariant,
+    ) -> anyhow::Result<impl Iterator<Item = (usize, String, Option<String>)> + '_> {
         let illust_id = illust_id as i64;
         // FIXME: only get the latest record per page
         let recs = sqlx::query!(
@@ -520,66 +1152,313 @@ impl Database {
               SELECT
                 page,
                 path,
+                hash,
                 MAX(download_date) as "download_date: String"
               FROM images
-              WHERE illust_id = ?
+              WHERE illust_id = ? AND variant = ?
               GROUP BY page
               ORDER BY page ASC"#,
             illust_id,
+            variant,
         )
         .fetch_all(&self.db)
         .await?;
-        Ok(recs.into_iter().map(|r| (r.page as usize, r.path)))
+        Ok(recs.into_iter().map(|r| (r.page as usize, r.path, r.hash)))
     }
 
-    pub async fn query_raw(&self, sql: &str) -> anyhow::Result<Vec<SqliteRow>> {
-        let result = sqlx::query(sql).fetch_all(&self.db).await?;
+    /// Latest downloaded ugoira zip of an illust, with its frames
+    pub async fn get_ugoira_for(
+        &self,
+        illust_id: u64,
+    ) -> anyhow::Result<Option<(String, Vec<UgoiraFrame>)>> {
+        let illust_id = illust_id as i64;
+        let rec = sqlx::query!(
+            r#"SELECT path, ugoira_frames as "ugoira_frames!"
+              FROM images
+              WHERE illust_id = ? AND variant = 0 AND ugoira_frames IS NOT NULL
+              ORDER BY download_date DESC
+              LIMIT 1"#,
+            illust_id,
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        rec.map(|r| Ok((r.path, serde_json::from_str(&r.ugoira_frames)?)))
+            .transpose()
+    }
+
+    pub async fn query_raw(
+        &self,
+        sql: &str,
+        params: &[QueryParam],
+    ) -> anyhow::Result<Vec<SqliteRow>> {
+        let mut query = sqlx::query(sql);
+        for param in params {
+            query = match param {
+                QueryParam::Int(i) => query.bind(*i),
+                QueryParam::Text(s) => query.bind(s.as_str()),
+            };
+        }
+        let result = query.fetch_all(&self.db).await?;
         Ok(result)
     }
 
-    pub async fn update_fanbox_post(
+    /// Like `query_raw` without parameters, but yields rows as they're read instead of collecting them
+    pub fn query_raw_stream<'a>(
+        &'a self,
+        sql: &'a str,
+    ) -> impl futures::Stream<Item = anyhow::Result<SqliteRow>> + 'a {
+        use futures::TryStreamExt;
+        sqlx::query(sql).fetch(&self.db).map_err(Into::into)
+    }
+
+    /// Upsert a novel's metadata. Masked novels are skipped, since they carry no data at all.
+    ///
+    /// When the novel itself was updated on pixiv, the stored text is dropped so it gets fetched again.
+    pub async fn update_novel(
         &self,
-        detail: &crate::data::fanbox::FetchPostDetail,
-    ) -> anyhow::Result<FanboxPostUpdateResult> {
-        let post = &detail.post;
+        novel: &crate::data::pixiv::Novel,
+        tag_map_ctx: &mut HashMap<String, u64>,
+    ) -> anyhow::Result<NovelUpdateResult> {
+        let Some(data) = &novel.data else {
+            return Ok(NovelUpdateResult::Skipped);
+        };
 
-        let post_id = post.id as i64;
-        let creator_id = &post.creator_id;
-        let title = &post.title;
-        let body = detail.body.as_ref().map(|e| e.text_repr()).transpose()?;
-        let is_body_rich = detail.body.as_ref().map(|e| e.is_rich());
-        let fee = post.fee_required as i64;
-        let published_datetime = post.published_datetime;
-        let updated_datetime = post.updated_datetime;
+        let mut missing_tags: Vec<&str> = data.tags.iter().map(String::as_str).collect();
+        missing_tags.retain(|t| !tag_map_ctx.contains_key(*t));
+        missing_tags.sort_unstable();
+        missing_tags.dedup();
+        tag_map_ctx.extend(self.get_tag_mappings(&missing_tags).await?);
+        let tags: Vec<u64> = data.tags.iter().map(|t| tag_map_ctx[t]).collect();
+
+        let novel_id = novel.id as i64;
+        let author_id = data.author.id as i64;
+        let x_restrict = data.x_restrict as i64;
+        let ai_type = data.ai_type as i64;
+        let text_count = data.text_count as i64;
+        let series_id = data.series_id.map(|id| id as i64);
+        let create_date = data.create_date;
+        let update_date = data.update_date;
+        let bookmark_id = novel.bookmark.as_ref().map(|b| b.id as i64);
+        let bookmark_private = novel.bookmark.as_ref().map(|b| b.private);
 
-        let orig = self.query_fanbox_post_status(post.id).await?;
-        if let Some(orig) = orig {
-            if !orig.needs_update(&post) {
-                return Ok(FanboxPostUpdateResult::Skipped);
-            }
+        let mut tx = self.db.begin().await?;
 
-            sqlx::query!(
-                r#"UPDATE fanbox_posts SET
-                    creator_id=?,
-                    title=?,
-                    body=?,
-                    is_body_rich=?,
-                    fee=?,
-                    published_datetime=datetime(?, 'utc'),
-                    updated_datetime=datetime(?, 'utc'),
-                    fetched_at=datetime('now', 'utc')
-                WHERE id = ?"#,
-                creator_id,
-                title,
-                body,
+        // Update author first s.t. foreign key is satisfied
+        sqlx::query!(
+            r#"
+            INSERT INTO authors (id, name)
+            VALUES (?, ?)
+            ON CONFLICT(id) DO UPDATE SET
+                name=excluded.name
+            "#,
+            author_id,
+            data.author.name,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let orig = sqlx::query!(
+            r#"SELECT
+                update_date = datetime(?, 'utc') AS "same_update: bool",
+                bookmark_id IS ? AND bookmark_private IS ? AS "same_bookmark: bool"
+            FROM novels WHERE id = ?"#,
+            update_date,
+            bookmark_id,
+            bookmark_private,
+            novel_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let result = match orig {
+            Some(orig) if orig.same_update && orig.same_bookmark => NovelUpdateResult::Unchanged,
+            Some(orig) => {
+                let stale_content = !orig.same_update;
+                sqlx::query!(
+                    r#"UPDATE novels SET
+                        title=?,
+                        author_id=?,
+                        description=?,
+                        x_restrict=?,
+                        ai_type=?,
+                        text_count=?,
+                        series_id=?,
+                        create_date=datetime(?, 'utc'),
+                        update_date=datetime(?, 'utc'),
+                        content=CASE WHEN ? THEN NULL ELSE content END,
+                        content_fetched_at=CASE WHEN ? THEN NULL ELSE content_fetched_at END,
+                        bookmark_id=?,
+                        bookmark_private=?,
+                        fetched_at=datetime('now', 'utc')
+                    WHERE id = ?"#,
+                    data.title,
+                    author_id,
+                    data.description,
+                    x_restrict,
+                    ai_type,
+                    text_count,
+                    series_id,
+                    create_date,
+                    update_date,
+                    stale_content,
+                    stale_content,
+                    bookmark_id,
+                    bookmark_private,
+                    novel_id,
+                )
+                .execute(&mut *tx)
+                .await?;
+                NovelUpdateResult::Updated
+            }
+            None => {
+                sqlx::query!(
+                    r#"INSERT INTO novels (
+                        id,
+                        title,
+                        author_id,
+                        description,
+                        x_restrict,
+                        ai_type,
+                        text_count,
+                        series_id,
+                        create_date,
+                        update_date,
+                        bookmark_id,
+                        bookmark_private,
+                        fetched_at
+                    ) VALUES (
+                        ?, ?, ?, ?, ?, ?, ?, ?, datetime(?, 'utc'), datetime(?, 'utc'), ?, ?, datetime('now', 'utc')
+                    )"#,
+                    novel_id,
+                    data.title,
+                    author_id,
+                    data.description,
+                    x_restrict,
+                    ai_type,
+                    text_count,
+                    series_id,
+                    create_date,
+                    update_date,
+                    bookmark_id,
+                    bookmark_private,
+                )
+                .execute(&mut *tx)
+                .await?;
+                NovelUpdateResult::Inserted
+            }
+        };
+
+        let tags_str = serde_json::to_string(&tags)?;
+        for tag in tags {
+            let tag = tag as i64;
+            sqlx::query!(
+                "INSERT OR IGNORE INTO novel_tags (novel_id, tag_id) VALUES (?, ?)",
+                novel_id,
+                tag
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        // Delete tags that are not in the new set
+        sqlx::query!("DELETE FROM novel_tags WHERE novel_id = ? AND tag_id NOT IN (SELECT json_each.value FROM json_each(?))", novel_id, tags_str)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    /// Whether a stored novel has no text yet
+    pub async fn novel_missing_content(&self, novel_id: u64) -> anyhow::Result<bool> {
+        let novel_id = novel_id as i64;
+        let missing = sqlx::query_scalar!(
+            r#"SELECT content IS NULL AS "missing!: bool" FROM novels WHERE id = ?"#,
+            novel_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(missing.unwrap_or(false))
+    }
+
+    pub async fn set_novel_content(&self, novel_id: u64, content: &str) -> anyhow::Result<()> {
+        let novel_id = novel_id as i64;
+        sqlx::query!(
+            "UPDATE novels SET content = ?, content_fetched_at = datetime('now', 'utc') WHERE id = ?",
+            content,
+            novel_id
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn update_fanbox_post(
+        &self,
+        detail: &crate::data::fanbox::FetchPostDetail,
+        storage: FanboxBodyStorage,
+    ) -> anyhow::Result<FanboxPostUpdateResult> {
+        let post = &detail.post;
+
+        let post_id = post.id as i64;
+        let creator_id = &post.creator_id;
+        let title = &post.title;
+        // Not storing the body still needs a non-NULL value, otherwise the post would look restricted
+        let body = match (&detail.body, storage) {
+            (None, _) => None,
+            (Some(_), FanboxBodyStorage::Empty) => Some(String::new()),
+            (Some(body), FanboxBodyStorage::PlainText) => Some(body.plain_text()),
+            (Some(body), FanboxBodyStorage::Full) => Some(body.text_repr()?),
+        };
+        let body_text = match (&detail.body, storage) {
+            (None, _) => None,
+            (Some(_), FanboxBodyStorage::Empty) => Some(String::new()),
+            (Some(body), _) => Some(body.plain_text()),
+        };
+        let is_body_rich = detail.body.as_ref().map(|e| e.is_rich());
+        let is_restricted = detail.body.is_none();
+        let fee = post.fee_required as i64;
+        let published_datetime = post.published_datetime;
+        let updated_datetime = post.updated_datetime;
+        let cover_url = post.cover.as_ref().map(|c| &c.url);
+        let has_adult_content = post.has_adult_content;
+
+        let orig = self.query_fanbox_post_status(post.id).await?;
+        if let Some(orig) = orig {
+            if !orig.needs_update(post) {
+                return Ok(FanboxPostUpdateResult::Skipped);
+            }
+
+            sqlx::query!(
+                r#"UPDATE fanbox_posts SET
+                    creator_id=?,
+                    title=?,
+                    body=?,
+                    body_text=?,
+                    is_body_rich=?,
+                    is_restricted=?,
+                    fee=?,
+                    published_datetime=datetime(?, 'utc'),
+                    updated_datetime=datetime(?, 'utc'),
+                    cover_url=?,
+                    has_adult_content=?,
+                    fetched_at=datetime('now', 'utc')
+                WHERE id = ?"#,
+                creator_id,
+                title,
+                body,
+                body_text,
                 is_body_rich,
+                is_restricted,
                 fee,
                 published_datetime,
                 updated_datetime,
+                cover_url,
+                has_adult_content,
                 post_id,
             )
             .execute(&self.db)
             .await?;
+            self.tag_fanbox_post(post).await?;
             Ok(FanboxPostUpdateResult::Updated)
         } else {
             sqlx::query!(
@@ -588,35 +1467,69 @@ impl Database {
                     creator_id,
                     title,
                     body,
+                    body_text,
                     is_body_rich,
+                    is_restricted,
                     fee,
                     published_datetime,
                     updated_datetime,
+                    cover_url,
+                    has_adult_content,
                     fetched_at
                 ) VALUES (
-                    ?, ?, ?, ?, ?, ?, datetime(?, 'utc'), datetime(?, 'utc'), datetime('now', 'utc')
+                    ?, ?, ?, ?, ?, ?, ?, ?, datetime(?, 'utc'), datetime(?, 'utc'), ?, ?, datetime('now', 'utc')
                 )"#,
                 post_id,
                 creator_id,
                 title,
                 body,
+                body_text,
                 is_body_rich,
+                is_restricted,
                 fee,
                 published_datetime,
                 updated_datetime,
+                cover_url,
+                has_adult_content,
             )
             .execute(&self.db)
             .await?;
+            self.tag_fanbox_post(post).await?;
             Ok(FanboxPostUpdateResult::Inserted)
         }
     }
 
+    async fn tag_fanbox_post(&self, post: &crate::data::fanbox::FetchPost) -> anyhow::Result<()> {
+        let mapping = self.get_tag_mappings(&post.tags).await?;
+        let tags: Vec<_> = post.tags.iter().map(|t| mapping[t]).collect();
+
+        let post_id = post.id as i64;
+        let tags_str = serde_json::to_string(&tags)?;
+        let mut tx = self.db.begin().await?;
+        for tag in tags {
+            let tag = tag as i64;
+            sqlx::query!(
+                "INSERT OR IGNORE INTO fanbox_post_tags (post_id, tag_id) VALUES (?, ?)",
+                post_id,
+                tag
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        // Delete tags that are not in the new set
+        sqlx::query!("DELETE FROM fanbox_post_tags WHERE post_id = ? AND tag_id NOT IN (SELECT json_each.value FROM json_each(?))", post_id, tags_str)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
     pub async fn query_fanbox_post_status(
         &self,
         post_id: u64,
     ) -> anyhow::Result<Option<FanboxPostStatus>> {
         let post_id = post_id as i64;
-        let rec = sqlx::query_as!(FanboxPostStatus, r#"SELECT updated_datetime as "updated_datetime: chrono::DateTime<chrono::Utc>", body IS NULL as "body_null: bool" FROM fanbox_posts WHERE id = ?"#, post_id)
+        let rec = sqlx::query_as!(FanboxPostStatus, r#"SELECT updated_datetime as "updated_datetime: chrono::DateTime<chrono::Utc>", is_restricted as "is_restricted: bool" FROM fanbox_posts WHERE id = ?"#, post_id)
             .fetch_optional(&self.db)
             .await?;
         Ok(rec)
@@ -704,13 +1617,199 @@ impl Database {
         Ok(ret > 0)
     }
 
+    pub async fn add_fanbox_url_embed(
+        &self,
+        post_id: u64,
+        idx: usize,
+        embed: &crate::data::fanbox::FetchPostUrlEmbed,
+    ) -> anyhow::Result<bool> {
+        let id = &embed.id;
+        let post_id = post_id as i64;
+        let idx = idx as i64;
+        let embed_type = &embed.r#type;
+        let url = &embed.url;
+
+        let ret = sqlx::query!(
+            r#"INSERT OR IGNORE INTO fanbox_embeds (
+                id,
+                post_id,
+                idx,
+                embed_type,
+                url
+            ) VALUES (
+                ?, ?, ?, ?, ?
+            )"#,
+            id,
+            post_id,
+            idx,
+            embed_type,
+            url
+        )
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+
+        Ok(ret > 0)
+    }
+
+    /// (idx, id) of all images of a post
+    pub async fn query_fanbox_post_image_ids(
+        &self,
+        post_id: u64,
+    ) -> anyhow::Result<Vec<(i64, String)>> {
+        let post_id = post_id as i64;
+        let recs = sqlx::query!(
+            r#"SELECT idx, id as "id!" FROM fanbox_images WHERE post_id = ? ORDER BY idx ASC"#,
+            post_id
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| (r.idx, r.id))
+        .collect();
+        Ok(recs)
+    }
+
+    /// (idx, id) of all files of a post
+    pub async fn query_fanbox_post_file_ids(
+        &self,
+        post_id: u64,
+    ) -> anyhow::Result<Vec<(i64, String)>> {
+        let post_id = post_id as i64;
+        let recs = sqlx::query!(
+            r#"SELECT idx, id as "id!" FROM fanbox_files WHERE post_id = ? ORDER BY idx ASC"#,
+            post_id
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| (r.idx, r.id))
+        .collect();
+        Ok(recs)
+    }
+
+    /// IDs of all stored posts, optionally only of one creator, newest first
+    pub async fn query_fanbox_post_ids(&self, creator: Option<&str>) -> anyhow::Result<Vec<u64>> {
+        let recs = sqlx::query_scalar!(
+            r#"SELECT id as "id!" FROM fanbox_posts WHERE ? IS NULL OR creator_id = ? ORDER BY id DESC"#,
+            creator,
+            creator
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(recs.into_iter().map(|id| id as u64).collect())
+    }
+
+    /// IDs of stored posts whose body was inaccessible, optionally only of one creator, newest first
+    pub async fn query_restricted_fanbox_post_ids(
+        &self,
+        creator: Option<&str>,
+    ) -> anyhow::Result<Vec<u64>> {
+        let recs = sqlx::query_scalar!(
+            r#"SELECT id as "id!" FROM fanbox_posts WHERE is_restricted AND (? IS NULL OR creator_id = ?) ORDER BY id DESC"#,
+            creator,
+            creator
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(recs.into_iter().map(|id| id as u64).collect())
+    }
+
+    /// The stored body of a post if it's in the rich format, as serialized blocks
+    ///
+    /// Rich bodies stored as plain text are the same as their `body_text`, and are not returned.
+    pub async fn query_fanbox_post_rich_body(
+        &self,
+        post_id: u64,
+    ) -> anyhow::Result<Option<String>> {
+        let post_id = post_id as i64;
+        let body = sqlx::query_scalar!(
+            r#"SELECT body FROM fanbox_posts WHERE id = ? AND is_body_rich AND body IS NOT body_text"#,
+            post_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(body.flatten())
+    }
+
+    /// Delete images of a post that are not downloaded and not in `keep`, returning their IDs
+    ///
+    /// With `dry_run`, only returns the IDs.
+    pub async fn prune_fanbox_images(
+        &self,
+        post_id: u64,
+        keep: &[String],
+        dry_run: bool,
+    ) -> anyhow::Result<Vec<String>> {
+        let post_id = post_id as i64;
+        let keep = serde_json::to_string(keep)?;
+        let ids = if dry_run {
+            sqlx::query_scalar!(
+                r#"SELECT id as "id!" FROM fanbox_images
+                WHERE post_id = ? AND path IS NULL AND id NOT IN (SELECT json_each.value FROM json_each(?))"#,
+                post_id,
+                keep
+            )
+            .fetch_all(&self.db)
+            .await?
+        } else {
+            sqlx::query_scalar!(
+                r#"DELETE FROM fanbox_images
+                WHERE post_id = ? AND path IS NULL AND id NOT IN (SELECT json_each.value FROM json_each(?))
+                RETURNING id as "id!""#,
+                post_id,
+                keep
+            )
+            .fetch_all(&self.db)
+            .await?
+        };
+        Ok(ids)
+    }
+
+    /// Same as `prune_fanbox_images`, for files
+    pub async fn prune_fanbox_files(
+        &self,
+        post_id: u64,
+        keep: &[String],
+        dry_run: bool,
+    ) -> anyhow::Result<Vec<String>> {
+        let post_id = post_id as i64;
+        let keep = serde_json::to_string(keep)?;
+        let ids = if dry_run {
+            sqlx::query_scalar!(
+                r#"SELECT id as "id!" FROM fanbox_files
+                WHERE post_id = ? AND path IS NULL AND id NOT IN (SELECT json_each.value FROM json_each(?))"#,
+                post_id,
+                keep
+            )
+            .fetch_all(&self.db)
+            .await?
+        } else {
+            sqlx::query_scalar!(
+                r#"DELETE FROM fanbox_files
+                WHERE post_id = ? AND path IS NULL AND id NOT IN (SELECT json_each.value FROM json_each(?))
+                RETURNING id as "id!""#,
+                post_id,
+                keep
+            )
+            .fetch_all(&self.db)
+            .await?
+        };
+        Ok(ids)
+    }
+
     pub async fn query_fanbox_file_download_spec(
         &self,
         id: &str,
     ) -> anyhow::Result<Option<FanboxFileDownloadSpec>> {
         let rec = sqlx::query_as!(
             FanboxFileDownloadSpec,
-            "SELECT url, name, post_id, ext, idx FROM fanbox_files WHERE id = ?",
+            r#"SELECT f.url, f.name, f.post_id, f.ext, f.idx, COALESCE(a.alias || ' (' || p.creator_id || ')', p.creator_id) as "creator_dir!: String",
+              p.creator_id, p.title as post_title
+            FROM fanbox_files f
+            JOIN fanbox_posts p ON p.id = f.post_id
+            LEFT JOIN fanbox_creator_aliases a ON a.creator_id = p.creator_id
+            WHERE f.id = ?"#,
             id
         )
         .fetch_optional(&self.db)
@@ -724,7 +1823,12 @@ impl Database {
     ) -> anyhow::Result<Option<FanboxImageDownloadSpec>> {
         let rec = sqlx::query_as!(
             FanboxImageDownloadSpec,
-            "SELECT url, post_id, ext, idx FROM fanbox_images WHERE id = ?",
+            r#"SELECT i.url, i.post_id, i.ext, i.idx, COALESCE(a.alias || ' (' || p.creator_id || ')', p.creator_id) as "creator_dir!: String",
+              p.creator_id, p.title as post_title
+            FROM fanbox_images i
+            JOIN fanbox_posts p ON p.id = i.post_id
+            LEFT JOIN fanbox_creator_aliases a ON a.creator_id = p.creator_id
+            WHERE i.id = ?"#,
             id
         )
         .fetch_optional(&self.db)
@@ -732,11 +1836,82 @@ impl Database {
         Ok(rec)
     }
 
+    /// Cover of a post, or None if the post isn't synced or has no cover
+    pub async fn query_fanbox_cover_download_spec(
+        &self,
+        post_id: u64,
+    ) -> anyhow::Result<Option<FanboxCoverDownloadSpec>> {
+        let post_id = post_id as i64;
+        let rec = sqlx::query_as!(
+            FanboxCoverDownloadSpec,
+            r#"SELECT p.cover_url as "url!", COALESCE(a.alias || ' (' || p.creator_id || ')', p.creator_id) as "creator_dir!: String"
+            FROM fanbox_posts p
+            LEFT JOIN fanbox_creator_aliases a ON a.creator_id = p.creator_id
+            WHERE p.id = ? AND p.cover_url IS NOT NULL"#,
+            post_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(rec)
+    }
+
+    /// Set the alias of a creator, replacing the existing one
+    pub async fn set_fanbox_creator_alias(
+        &self,
+        creator_id: &str,
+        alias: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO fanbox_creator_aliases (creator_id, alias) VALUES (?, ?)
+            ON CONFLICT(creator_id) DO UPDATE SET alias = excluded.alias"#,
+            creator_id,
+            alias,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Set the alias of a creator, unless the creator already has one or the alias is taken.
+    ///
+    /// Returns if the alias is added.
+    pub async fn add_fanbox_creator_alias(
+        &self,
+        creator_id: &str,
+        alias: &str,
+    ) -> anyhow::Result<bool> {
+        let ret = sqlx::query!(
+            r#"INSERT OR IGNORE INTO fanbox_creator_aliases (creator_id, alias) VALUES (?, ?)"#,
+            creator_id,
+            alias,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// Replace the stored download URL of a file, e.g. after it expired. Returns if the file exists.
+    pub async fn update_fanbox_file_url(&self, id: &str, url: &str) -> anyhow::Result<bool> {
+        let ret = sqlx::query!("UPDATE fanbox_files SET url = ? WHERE id = ?", url, id)
+            .execute(&self.db)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// Replace the stored download URL of an image, e.g. after it expired. Returns if the image exists.
+    pub async fn update_fanbox_image_url(&self, id: &str, url: &str) -> anyhow::Result<bool> {
+        let ret = sqlx::query!("UPDATE fanbox_images SET url = ? WHERE id = ?", url, id)
+            .execute(&self.db)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
     pub async fn update_fanbox_file_download(
         &self,
         id: &str,
         path: &str,
         size: i64,
+        hash: &str,
     ) -> anyhow::Result<bool> {
         let mut tx = self.db.begin().await?;
         let orig = sqlx::query!("SELECT size FROM fanbox_files WHERE id = ?", id)
@@ -756,9 +1931,10 @@ impl Database {
         let updated_size = if orig.size != 0 { orig.size } else { size };
 
         let rows_updated = sqlx::query!(
-            "UPDATE fanbox_files SET path = ?, size = ?, downloaded_at = datetime('now', 'utc') WHERE id = ?",
+            "UPDATE fanbox_files SET path = ?, size = ?, hash = ?, downloaded_at = datetime('now', 'utc') WHERE id = ?",
             path,
             updated_size,
+            hash,
             id
         )
         .execute(&mut *tx)
@@ -769,12 +1945,30 @@ impl Database {
         Ok(rows_updated > 0)
     }
 
+    pub async fn update_fanbox_cover_download(
+        &self,
+        post_id: u64,
+        path: &str,
+    ) -> anyhow::Result<bool> {
+        let post_id = post_id as i64;
+        let rows_updated = sqlx::query!(
+            "UPDATE fanbox_posts SET cover_path = ?, cover_downloaded_at = datetime('now', 'utc') WHERE id = ?",
+            path,
+            post_id
+        )
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+        Ok(rows_updated > 0)
+    }
+
     pub async fn update_fanbox_image_download(
         &self,
         id: &str,
         path: &str,
         width: i64,
         height: i64,
+        hash: &str,
     ) -> anyhow::Result<bool> {
         let mut tx = self.db.begin().await?;
         let orig = sqlx::query!("SELECT width, height FROM fanbox_images WHERE id = ?", id)
@@ -801,10 +1995,11 @@ impl Database {
         };
 
         let rows_updated = sqlx::query!(
-            "UPDATE fanbox_images SET path = ?, width = ?, height = ?, downloaded_at = datetime('now', 'utc') WHERE id = ?",
+            "UPDATE fanbox_images SET path = ?, width = ?, height = ?, hash = ?, downloaded_at = datetime('now', 'utc') WHERE id = ?",
             path,
             updated_width,
             updated_height,
+            hash,
             id
         )
         .execute(&mut *tx)
@@ -815,16 +2010,102 @@ impl Database {
         Ok(rows_updated > 0)
     }
 
-    pub async fn query_image_paths(&self) -> anyhow::Result<Vec<DownloadPathEntry<(u64, u64)>>> {
-        let recs = sqlx::query!("SELECT illust_id, page, path FROM images")
-            .fetch_all(&self.db)
-            .await?
-            .into_iter()
-            .map(|r| DownloadPathEntry {
-                id: (r.illust_id as u64, r.page as u64),
-                path: Some(r.path),
-            })
-            .collect();
+    /// Stored dimensions of all images. Ordered by illust, so pages of the same illust are adjacent.
+    pub async fn query_image_dimensions(&self) -> anyhow::Result<Vec<ImageDimensionEntry>> {
+        let recs = sqlx::query_as!(
+            ImageDimensionEntry,
+            r#"SELECT
+                illust_id, page, path, width, height,
+                variant as "variant: ImageVariant",
+                ugoira_frames IS NOT NULL as "is_ugoira: bool"
+            FROM images
+            ORDER BY illust_id ASC, page ASC"#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(recs)
+    }
+
+    /// Images of all illustrations in the given state, with what's needed to verify them before deletion
+    pub async fn query_images_by_illust_state(
+        &self,
+        state: crate::data::pixiv::IllustState,
+    ) -> anyhow::Result<Vec<PruneImageEntry>> {
+        let recs = sqlx::query_as!(
+            PruneImageEntry,
+            r#"SELECT
+                images.illust_id, images.page, images.path, images.width, images.height, images.hash,
+                images.variant as "variant: ImageVariant",
+                images.ugoira_frames IS NOT NULL as "is_ugoira: bool",
+                images.thumbnail_path
+            FROM images JOIN illusts ON illusts.id = images.illust_id
+            WHERE illusts.illust_state = ?
+            ORDER BY images.illust_id ASC, images.page ASC"#,
+            state
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(recs)
+    }
+
+    /// IDs of normal illustrations that were never synced with their details, newest first
+    pub async fn query_illusts_missing_detail(
+        &self,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<u64>> {
+        // Negative limits are unlimited in SQLite
+        let limit = limit.map(|l| l as i64).unwrap_or(-1);
+        let recs = sqlx::query_scalar!(
+            r#"SELECT id as "id!" FROM illusts WHERE illust_state = 0 AND content_desc IS NULL ORDER BY id DESC LIMIT ?"#,
+            limit
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(recs.into_iter().map(|id| id as u64).collect())
+    }
+
+    /// Stored state of an illustration, and whether its metadata (title, author, ...) is known
+    pub async fn query_illust_state(
+        &self,
+        illust_id: u64,
+    ) -> anyhow::Result<Option<(IllustState, bool)>> {
+        let illust_id = illust_id as i64;
+        let rec = sqlx::query!(
+            r#"SELECT illust_state as "illust_state: IllustState", title IS NOT NULL as "has_data: bool" FROM illusts WHERE id = ?"#,
+            illust_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(rec.map(|r| (r.illust_state, r.has_data)))
+    }
+
+    /// Overwrite the state of an illustration, leaving all other data as-is
+    pub async fn set_illust_state(&self, illust_id: u64, state: IllustState) -> anyhow::Result<()> {
+        let illust_id = illust_id as i64;
+        sqlx::query!(
+            "UPDATE illusts SET illust_state = ? WHERE id = ?",
+            state,
+            illust_id
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn query_image_paths(
+        &self,
+    ) -> anyhow::Result<Vec<DownloadPathEntry<(u64, u64, ImageVariant)>>> {
+        let recs = sqlx::query!(
+            r#"SELECT illust_id, page, path, variant as "variant: ImageVariant" FROM images"#
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| DownloadPathEntry {
+            id: (r.illust_id as u64, r.page as u64, r.variant),
+            path: Some(r.path),
+        })
+        .collect();
         Ok(recs)
     }
 
@@ -858,6 +2139,30 @@ impl Database {
         Ok(recs)
     }
 
+    /// Stored dimensions of all fanbox images
+    pub async fn query_fanbox_image_dimensions(
+        &self,
+    ) -> anyhow::Result<Vec<FanboxImageDimensionEntry>> {
+        let recs = sqlx::query_as!(
+            FanboxImageDimensionEntry,
+            r#"SELECT id as "id!", post_id, idx, path, width, height FROM fanbox_images"#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(recs)
+    }
+
+    /// Stored sizes of all fanbox files
+    pub async fn query_fanbox_file_sizes(&self) -> anyhow::Result<Vec<FanboxFileSizeEntry>> {
+        let recs = sqlx::query_as!(
+            FanboxFileSizeEntry,
+            r#"SELECT id as "id!", post_id, idx, path, size FROM fanbox_files"#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(recs)
+    }
+
     pub async fn update_fanbox_image_path(&self, id: &str, path: &str) -> anyhow::Result<bool> {
         let rows_updated = sqlx::query!("UPDATE fanbox_images SET path = ? WHERE id = ?", path, id)
             .execute(&self.db)
@@ -873,4 +2178,361 @@ impl Database {
             .rows_affected();
         Ok(rows_updated > 0)
     }
+
+    /// Replace the directory prefix `old` of absolute pixiv image paths with `new`, returning the number of rewritten paths
+    ///
+    /// Relative paths are left untouched. With `dry_run`, the changes are rolled back.
+    pub async fn rewrite_image_path_prefix(
+        &self,
+        old: &str,
+        new: &str,
+        dry_run: bool,
+    ) -> anyhow::Result<u64> {
+        // substr is 1-based, and counts characters
+        let start = old.chars().count() as i64 + 1;
+        let old_dir = format!("{}/", old);
+        let mut tx = self.db.begin().await?;
+        let rows_updated = sqlx::query!(
+            "UPDATE images SET path = ? || substr(path, ?) WHERE substr(path, 1, ?) = ?",
+            new,
+            start,
+            start,
+            old_dir
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+        Ok(rows_updated)
+    }
+
+    /// Same as [`Self::rewrite_image_path_prefix`], for fanbox images, files and covers
+    pub async fn rewrite_fanbox_path_prefix(
+        &self,
+        old: &str,
+        new: &str,
+        dry_run: bool,
+    ) -> anyhow::Result<u64> {
+        let start = old.chars().count() as i64 + 1;
+        let old_dir = format!("{}/", old);
+        let mut tx = self.db.begin().await?;
+        let mut rows_updated = sqlx::query!(
+            "UPDATE fanbox_images SET path = ? || substr(path, ?) WHERE substr(path, 1, ?) = ?",
+            new,
+            start,
+            start,
+            old_dir
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        rows_updated += sqlx::query!(
+            "UPDATE fanbox_files SET path = ? || substr(path, ?) WHERE substr(path, 1, ?) = ?",
+            new,
+            start,
+            start,
+            old_dir
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        rows_updated += sqlx::query!(
+            "UPDATE fanbox_posts SET cover_path = ? || substr(cover_path, ?) WHERE substr(cover_path, 1, ?) = ?",
+            new,
+            start,
+            start,
+            old_dir
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            tx.commit().await?;
+        }
+        Ok(rows_updated)
+    }
+
+    /// Latest downloaded original of each page, ordered by illust and page
+    pub async fn query_image_link_entries(&self) -> anyhow::Result<Vec<ImageLinkEntry>> {
+        // Tags are ordered as pixiv lists them, which is the insertion order
+        let recs = sqlx::query_as!(
+            ImageLinkEntry,
+            r#"SELECT
+                i.illust_id, i.page, i.path, il.title, il.author_id,
+                a.name as "author_name?", a.account as "author_account?",
+                (
+                    SELECT t.tag FROM illust_tags it JOIN tags t ON t.id = it.tag_id
+                    WHERE it.illust_id = i.illust_id ORDER BY it.rowid LIMIT 1
+                ) as "first_tag?: String",
+                (
+                    SELECT t.tag FROM illust_bookmark_tags it JOIN tags t ON t.id = it.tag_id
+                    WHERE it.illust_id = i.illust_id ORDER BY it.rowid LIMIT 1
+                ) as "first_bookmark_tag?: String"
+            FROM images i
+            JOIN illusts il ON il.id = i.illust_id
+            LEFT JOIN authors a ON a.id = il.author_id
+            WHERE i.variant = 0 AND i.verified_date = (
+                SELECT MAX(verified_date) FROM images
+                WHERE illust_id = i.illust_id AND page = i.page AND variant = 0
+            )
+            ORDER BY i.illust_id ASC, i.page ASC"#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(recs)
+    }
+
+    /// Paths of pixiv images without a stored content hash
+    pub async fn query_unhashed_image_paths(&self) -> anyhow::Result<Vec<(String, ImageVariant)>> {
+        let recs = sqlx::query!(
+            r#"SELECT path, variant as "variant: ImageVariant" FROM images WHERE hash IS NULL"#
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| (r.path, r.variant))
+        .collect();
+        Ok(recs)
+    }
+
+    pub async fn query_unhashed_fanbox_image_paths(&self) -> anyhow::Result<Vec<String>> {
+        let recs = sqlx::query!(
+            r#"SELECT path as "path!" FROM fanbox_images WHERE path IS NOT NULL AND hash IS NULL"#
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| r.path)
+        .collect();
+        Ok(recs)
+    }
+
+    pub async fn query_unhashed_fanbox_file_paths(&self) -> anyhow::Result<Vec<String>> {
+        let recs = sqlx::query!(
+            r#"SELECT path as "path!" FROM fanbox_files WHERE path IS NOT NULL AND hash IS NULL"#
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| r.path)
+        .collect();
+        Ok(recs)
+    }
+
+    /// Hashes and paths of hashed pixiv images, ordered by when they were downloaded
+    pub async fn query_hashed_image_paths(
+        &self,
+    ) -> anyhow::Result<Vec<(String, String, ImageVariant)>> {
+        let recs = sqlx::query!(
+            r#"SELECT hash as "hash!", path, variant as "variant: ImageVariant" FROM images
+            WHERE hash IS NOT NULL ORDER BY download_date, rowid"#
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| (r.hash, r.path, r.variant))
+        .collect();
+        Ok(recs)
+    }
+
+    /// Hashes and paths of hashed fanbox images, ordered by when they were downloaded
+    pub async fn query_hashed_fanbox_image_paths(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let recs = sqlx::query!(
+            r#"SELECT hash as "hash!", path as "path!" FROM fanbox_images
+            WHERE path IS NOT NULL AND hash IS NOT NULL ORDER BY downloaded_at, rowid"#
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| (r.hash, r.path))
+        .collect();
+        Ok(recs)
+    }
+
+    /// Hashes and paths of hashed fanbox files, ordered by when they were downloaded
+    pub async fn query_hashed_fanbox_file_paths(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let recs = sqlx::query!(
+            r#"SELECT hash as "hash!", path as "path!" FROM fanbox_files
+            WHERE path IS NOT NULL AND hash IS NOT NULL ORDER BY downloaded_at, rowid"#
+        )
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|r| (r.hash, r.path))
+        .collect();
+        Ok(recs)
+    }
+
+    pub async fn update_image_hash(&self, path: &str, hash: &str) -> anyhow::Result<bool> {
+        let rows_updated = sqlx::query!("UPDATE images SET hash = ? WHERE path = ?", hash, path)
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+        Ok(rows_updated > 0)
+    }
+
+    pub async fn update_fanbox_image_hash(&self, path: &str, hash: &str) -> anyhow::Result<bool> {
+        let rows_updated = sqlx::query!(
+            "UPDATE fanbox_images SET hash = ? WHERE path = ?",
+            hash,
+            path
+        )
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+        Ok(rows_updated > 0)
+    }
+
+    pub async fn update_fanbox_file_hash(&self, path: &str, hash: &str) -> anyhow::Result<bool> {
+        let rows_updated = sqlx::query!(
+            "UPDATE fanbox_files SET hash = ? WHERE path = ?",
+            hash,
+            path
+        )
+        .execute(&self.db)
+        .await?
+        .rows_affected();
+        Ok(rows_updated > 0)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::data::pixiv::{
+        AIType, Illust, IllustBookmarkState, IllustData, IllustDataSimple, IllustType, Illustrator,
+        Tags, XRestrict,
+    };
+
+    /// A fresh in-memory database with all migrations applied
+    pub(crate) async fn memory_db() -> Database {
+        Database::setup("sqlite::memory:").await.unwrap()
+    }
+
+    /// A public illustration by author 1, as listed in bookmarks or search results
+    pub(crate) fn illust(id: u64, illust_type: IllustType, page_count: u64) -> Illust {
+        let date = chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00+09:00").unwrap();
+        Illust {
+            id,
+            data: IllustData::Simple(IllustDataSimple {
+                title: format!("Illust {}", id),
+                tags: Tags::Brief(vec!["tag".to_owned()]),
+                author: Illustrator {
+                    id: 1,
+                    name: "Author".to_owned(),
+                    account: None,
+                },
+                create_date: date,
+                update_date: date,
+                x_restrict: XRestrict::Public,
+                ai_type: AIType::NonAI,
+                illust_type,
+                page_count,
+            }),
+            state: IllustState::Normal,
+            bookmark: None,
+        }
+    }
+
+    /// The same as `illust`, bookmarked with the given bookmark ID and tags
+    pub(crate) fn bookmarked(id: u64, bookmark_id: u64, private: bool, tags: &[&str]) -> Illust {
+        Illust {
+            bookmark: Some(IllustBookmarkState {
+                id: bookmark_id,
+                private,
+                tags: IllustBookmarkTags::Known(tags.iter().map(|&t| t.to_owned()).collect()),
+            }),
+            ..illust(id, IllustType::Illustration, 1)
+        }
+    }
+
+    async fn illust_count(db: &Database) -> i64 {
+        use sqlx::Row;
+        let rows = db
+            .query_raw("SELECT COUNT(*) as count FROM illusts", &[])
+            .await
+            .unwrap();
+        rows[0].try_get("count").unwrap()
+    }
+
+    #[tokio::test]
+    async fn memory_db_is_migrated() {
+        let db = memory_db().await;
+        assert_eq!(db.file_path(), None);
+
+        let mut tag_map_ctx = HashMap::new();
+        let result = db
+            .update_illust(&illust(1, IllustType::Illustration, 1), &mut tag_map_ctx)
+            .await
+            .unwrap();
+        assert!(matches!(result, IllustUpdateResult::Inserted));
+        assert_eq!(illust_count(&db).await, 1);
+    }
+
+    #[tokio::test]
+    async fn memory_dbs_are_distinct() {
+        let first = memory_db().await;
+        let second = memory_db().await;
+
+        let mut tag_map_ctx = HashMap::new();
+        first
+            .update_illust(&illust(1, IllustType::Illustration, 1), &mut tag_map_ctx)
+            .await
+            .unwrap();
+        assert_eq!(illust_count(&first).await, 1);
+        assert_eq!(illust_count(&second).await, 0);
+    }
+
+    #[tokio::test]
+    async fn load_requires_setup() {
+        let err = Database::load("sqlite::memory:").await.err().unwrap();
+        assert!(
+            err.to_string().contains("pixivdwn database setup"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn canonicalize_url_forms() {
+        let cwd = std::env::current_dir().unwrap();
+        let cwd = cwd.to_str().unwrap().trim_end_matches('/');
+
+        for (url, expected) in [
+            ("sqlite:db.sqlite", format!("sqlite://{}/db.sqlite", cwd)),
+            ("sqlite:./db.sqlite", format!("sqlite://{}/db.sqlite", cwd)),
+            (
+                "sqlite://./db.sqlite?mode=rwc",
+                format!("sqlite://{}/db.sqlite?mode=rwc", cwd),
+            ),
+            (
+                "sqlite:/data/db.sqlite",
+                "sqlite:///data/db.sqlite".to_owned(),
+            ),
+            (
+                "sqlite:///data/db.sqlite",
+                "sqlite:///data/db.sqlite".to_owned(),
+            ),
+            (
+                "sqlite:///data/db.sqlite?mode=rwc",
+                "sqlite:///data/db.sqlite?mode=rwc".to_owned(),
+            ),
+        ] {
+            assert_eq!(canonicalize_url(url).unwrap(), expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn canonicalize_url_keeps_in_memory() {
+        for url in ["sqlite::memory:", "sqlite://db?mode=memory&cache=shared"] {
+            assert_eq!(canonicalize_url(url).unwrap(), url);
+        }
+        assert!(canonicalize_url("postgres://localhost/db").is_err());
+    }
 }
@@ -1,4 +1,4 @@
-use std::sync::atomic::AtomicI64;
+use std::sync::atomic::{AtomicI64, AtomicU32};
 
 use serde::de::DeserializeOwned;
 
@@ -14,6 +14,34 @@ pub fn update_delay_settings(base: i64, var: i64) {
     DELAY_RANDOM_VAR_MS.store(var, std::sync::atomic::Ordering::Relaxed);
 }
 
+static HTTP_RETRIES: AtomicU32 = AtomicU32::new(3);
+
+/// How many times an API request is retried on HTTP 429 or 5xx before giving up
+pub fn update_http_retry_settings(retries: u32) {
+    HTTP_RETRIES.store(retries, std::sync::atomic::Ordering::Relaxed);
+}
+
+static PROXY: std::sync::OnceLock<wreq::Proxy> = std::sync::OnceLock::new();
+
+/// Route all requests, including file downloads, through the given proxy
+///
+/// Supports `http://`, `https://`, `socks5://` and `socks5h://` URLs. Must be called before the first request.
+pub fn update_proxy_settings(url: &str) -> anyhow::Result<()> {
+    let scheme = url.split_once("://").map(|(scheme, _)| scheme);
+    if !matches!(scheme, Some("http" | "https" | "socks5" | "socks5h")) {
+        return Err(anyhow::anyhow!(
+            "Unsupported proxy URL {}, expected http://, https://, socks5:// or socks5h://",
+            url
+        ));
+    }
+    let proxy =
+        wreq::Proxy::all(url).map_err(|e| anyhow::anyhow!("Invalid proxy URL {}: {}", url, e))?;
+    PROXY
+        .set(proxy)
+        .map_err(|_| anyhow::anyhow!("Proxy is already set"))?;
+    Ok(())
+}
+
 pub struct FetchCtxGuard<'a> {
     guard: tokio::sync::MutexGuard<'a, Option<Ctx>>,
 }
@@ -23,7 +51,11 @@ impl<'a> FetchCtxGuard<'a> {
         let mut next = CTX.lock().await;
         match &mut *next {
             None => {
-                let client = wreq::Client::new();
+                let mut builder = wreq::Client::builder();
+                if let Some(proxy) = PROXY.get() {
+                    builder = builder.proxy(proxy.clone());
+                }
+                let client = builder.build().expect("Failed to build HTTP client");
                 *next = Some((client, tokio::time::Instant::now()));
             }
             Some((_, ddl)) => {
@@ -49,17 +81,86 @@ impl Drop for FetchCtxGuard<'_> {
     }
 }
 
-#[inline]
+/// Delay before retrying a rate-limited or failed request, or None if the response is not retryable
+///
+/// Honors `Retry-After` in seconds, otherwise backs off exponentially starting from 5s.
+fn retry_delay(resp: &wreq::Response, attempt: u32) -> Option<std::time::Duration> {
+    let status = resp.status();
+    if status != wreq::StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+        return None;
+    }
+
+    let retry_after = resp
+        .headers()
+        .get(wreq::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let secs = retry_after.unwrap_or(5 << attempt.min(6)).min(600);
+    Some(std::time::Duration::from_secs(secs))
+}
+
+/// A non-2xx API response whose body is not the expected JSON
+///
+/// Returned wrapped in `anyhow::Error`. HTTP 429 and 5xx are only returned after `--http-retries` ran out.
+#[derive(thiserror::Error, Debug)]
+#[error("HTTP {status} from {uri}: {snippet}")]
+pub struct HttpStatusError {
+    pub status: wreq::StatusCode,
+    pub uri: String,
+    pub snippet: String,
+}
+
 pub async fn fetch<T: DeserializeOwned>(
     req: impl FnOnce(&wreq::Client) -> anyhow::Result<wreq::Request>,
 ) -> anyhow::Result<T> {
-    let ctx = FetchCtxGuard::begin().await;
-
-    let client = ctx.client();
-    let req = req(client)?;
-    tracing::debug!("Fetching {}", req.uri());
-    tracing::debug!("  Headers: {:#?}", req.headers());
-    let resp = client.execute(req).await?;
-    let json = resp.json::<T>().await?;
-    Ok(json)
+    let mut ctx = FetchCtxGuard::begin().await;
+
+    let mut req = req(ctx.client())?;
+    let max_retries = HTTP_RETRIES.load(std::sync::atomic::Ordering::Relaxed);
+    let mut attempt = 0;
+    loop {
+        let uri = req.uri().clone();
+        tracing::debug!("Fetching {}", uri);
+        tracing::debug!("  Headers: {:#?}", req.headers());
+        let retry = if attempt < max_retries {
+            req.try_clone()
+        } else {
+            None
+        };
+        let resp = ctx.client().execute(req).await?;
+        let status = resp.status();
+
+        if let Some(next) = retry
+            && let Some(delay) = retry_delay(&resp, attempt)
+        {
+            attempt += 1;
+            tracing::warn!(
+                "HTTP {} from {}, retrying in {}s ({}/{})",
+                status,
+                uri,
+                delay.as_secs(),
+                attempt,
+                max_retries
+            );
+            // Release the rate-limiter while waiting, and go through it again for the retry
+            drop(ctx);
+            tokio::time::sleep(delay).await;
+            ctx = FetchCtxGuard::begin().await;
+            req = next;
+            continue;
+        }
+
+        let body = resp.text().await?;
+        return match serde_json::from_str::<T>(&body) {
+            Ok(json) => Ok(json),
+            // Pixiv reports some errors as JSON with a non-2xx status, which the caller can inspect
+            Err(_) if !status.is_success() => Err(HttpStatusError {
+                status,
+                uri: uri.to_string(),
+                snippet: body.chars().take(200).collect(),
+            }
+            .into()),
+            Err(e) => Err(e.into()),
+        };
+    }
 }
@@ -3,6 +3,8 @@ mod config;
 mod data;
 mod db;
 mod fetch;
+mod interrupt;
+mod retry;
 mod util;
 
 use std::path::PathBuf;
@@ -28,6 +30,12 @@ struct Args {
     #[arg(long, hide_short_help = true)]
     fanbox_header_full: Option<String>,
 
+    /// Read full Fanbox headers from a file, in the same format as `fanbox_header_full`
+    /// Can also be set via the FANBOX_HEADER_FULL_FILE environment variable
+    /// Ignored if `fanbox_header_full` is given, but takes precedence over FANBOX_HEADER_FULL
+    #[arg(long, hide_short_help = true)]
+    fanbox_header_full_file: Option<PathBuf>,
+
     /// Base directory to save / lookup pixiv illustrations
     ///
     /// The illustrations will be saved as `<base_dir>/<illust_id>_p<page>.<ext>`
@@ -35,6 +43,13 @@ struct Args {
     #[arg(long, hide_short_help = true)]
     pixiv_base_dir: Option<PathBuf>,
 
+    /// Directory to save / lookup pixiv thumbnails
+    ///
+    /// Defaults to `<pixiv_base_dir>/thumbs`
+    /// Can also be set via the PIXIV_THUMB_DIR environment variable
+    #[arg(long, hide_short_help = true)]
+    pixiv_thumb_dir: Option<PathBuf>,
+
     /// Base directory to save / lookup fanbox illustrations
     ///
     /// The illustrations will be saved as `<base_dir>/<post_id>_<idx>_<image_id>[_<name>].<ext>`
@@ -43,59 +58,260 @@ struct Args {
     fanbox_base_dir: Option<PathBuf>,
 
     /// Database URL, Can also be set via the DATABASE_URL environment variable
-    #[arg(long, hide_short_help = true)]
+    ///
+    /// Can be given after any subcommand, e.g. `pixivdwn query --database-url <URL>`,
+    /// to operate on a different archive for that invocation
+    #[arg(long, global = true, hide_short_help = true)]
     database_url: Option<String>,
 
-    /// Override fetch delay (ms)
-    #[arg(long, default_value_t = 2500, hide_short_help = true)]
-    fetch_delay: i64,
+    /// Override fetch delay (ms), defaults to 2500
+    #[arg(long, hide_short_help = true)]
+    fetch_delay: Option<i64>,
+
+    /// Override fetch delay random variance (ms), defaults to 500
+    #[arg(long, hide_short_help = true)]
+    fetch_delay_var: Option<i64>,
+
+    /// How many times to retry an API request on HTTP 429 or 5xx, defaults to 3
+    #[arg(long, hide_short_help = true)]
+    http_retries: Option<u32>,
 
-    /// Override fetch delay random variance (ms)
-    #[arg(long, default_value_t = 500, hide_short_help = true)]
-    fetch_delay_var: i64,
+    /// Maximum number of retries when an API request to pixiv or Fanbox fails, defaults to 0
+    ///
+    /// Can be given after any subcommand. Covers network errors and malformed responses. HTTP errors are left to `--http-retries`,
+    /// and illustrations refused by pixiv (e.g. deleted or age-restricted) are not retried.
+    #[arg(short, long, global = true, hide_short_help = true)]
+    retries: Option<usize>,
+
+    /// Initial backoff between retries (ms), doubled after each retry
+    ///
+    /// Also applies to `--download-retries`, which otherwise backs off from 5000ms.
+    #[arg(long, global = true, hide_short_help = true)]
+    retry_backoff: Option<u64>,
+
+    /// Maximum number of times to restart a file download on network errors, HTTP 429 or 5xx, defaults to 0
+    ///
+    /// Can be given after any subcommand. Each retry starts the transfer over.
+    #[arg(long, global = true, hide_short_help = true)]
+    download_retries: Option<u32>,
+
+    /// Cap the bandwidth of file downloads in bytes per second, e.g. `500K` or `2M`, unlimited by default
+    ///
+    /// Can be given after any subcommand. Shared by concurrent downloads, and independent of `--fetch-delay`.
+    #[arg(long, global = true, value_parser = util::parse_cli_rate, hide_short_help = true)]
+    limit_rate: Option<u64>,
+
+    /// Proxy for all requests, e.g. `http://127.0.0.1:8080` or `socks5h://127.0.0.1:1080`
+    /// Can also be set via the HTTPS_PROXY environment variable
+    #[arg(long, hide_short_help = true)]
+    proxy: Option<String>,
+
+    /// Override the User-Agent of all requests, which otherwise matches the emulated browser (Chrome)
+    /// Full Fanbox headers keep their own User-Agent
+    #[arg(long, hide_short_help = true)]
+    user_agent: Option<String>,
+
+    /// Browser to emulate in all requests, including its TLS fingerprint and User-Agent. Defaults to chrome140
+    #[arg(long, value_enum, hide_short_help = true)]
+    emulation: Option<config::Emulation>,
+
+    /// SQLite journal mode. WAL allows reading while a sync or download is writing.
+    #[arg(long, value_enum, default_value_t = db::JournalMode::Wal, hide_short_help = true)]
+    sqlite_journal_mode: db::JournalMode,
+
+    /// SQLite synchronous mode, trading durability on power loss for speed
+    #[arg(long, value_enum, default_value_t = db::Synchronous::Normal, hide_short_help = true)]
+    sqlite_synchronous: db::Synchronous,
+
+    /// How long to wait for a locked database before failing (ms)
+    #[arg(long, default_value_t = 5000, hide_short_help = true)]
+    sqlite_busy_timeout: u64,
+
+    /// Path to the config file
+    ///
+    /// Defaults to `~/.config/pixivdwn/config.toml`, or `$XDG_CONFIG_HOME/pixivdwn/config.toml` if set
+    /// Can also be set via the PIXIVDWN_CONFIG environment variable
+    /// Flags and environment variables take precedence over values in the config file
+    #[arg(long, hide_short_help = true)]
+    config: Option<PathBuf>,
+
+    /// Use the cookies, base directories and database of a profile defined in the config file
+    ///
+    /// Can also be set via the PIXIVDWN_PROFILE environment variable
+    /// Settings of the profile replace the top-level ones in the config file, but not flags or environment variables
+    #[arg(long, global = true, hide_short_help = true)]
+    profile: Option<String>,
+
+    /// Increase log verbosity: `-v` for debug logs, `-vv` for trace logs, `-vvv` to also include dependencies
+    /// The RUST_LOG environment variable overrides this if set
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "log_level")]
+    verbose: u8,
+
+    /// Decrease log verbosity: `-q` to only show warnings and errors, `-qq` for errors only
+    /// The RUST_LOG environment variable overrides this if set
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with_all = ["log_level", "verbose"])]
+    quiet: u8,
+
+    /// Log level for all logs, one of `error`, `warn`, `info`, `debug`, `trace` or `off` (default: info)
+    /// The RUST_LOG environment variable overrides this if set
+    #[arg(long, global = true, hide_short_help = true)]
+    log_level: Option<tracing_subscriber::filter::LevelFilter>,
+
+    /// Log output format. `json` prints one JSON object per line, for ingestion by log pipelines
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true, hide_short_help = true)]
+    log_format: LogFormat,
 
     #[command(subcommand)]
     command: cmd::Command,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv()?;
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
+    let mut args = Args::parse();
+    // Same as parse errors above, exits before anything is opened
+    args.command.parse_nested().unwrap_or_else(|e| e.exit());
+
+    let default_filter = match (args.log_level, args.verbose, args.quiet) {
+        (Some(level), _, _) => level.to_string(),
+        (None, 0, 0) => "info".to_string(),
+        (None, 0, 1) => "warn".to_string(),
+        (None, 0, _) => "error".to_string(),
+        (None, 1, _) => "info,pixivdwn=debug".to_string(),
+        (None, 2, _) => "info,pixivdwn=trace".to_string(),
+        (None, _, _) => "trace".to_string(),
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .or_else(|_| tracing_subscriber::EnvFilter::try_new(default_filter))?;
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match args.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+    interrupt::install_handler();
+
+    let config_path = args
+        .config
+        .or_else(|| std::env::var("PIXIVDWN_CONFIG").ok().map(PathBuf::from));
+    let mut config = config::ConfigFile::load(config_path.as_deref())?;
+    let profile = args
+        .profile
+        .or_else(|| std::env::var("PIXIVDWN_PROFILE").ok());
+    if let Some(profile) = profile {
+        config.apply_profile(&profile)?;
+    }
+
+    fetch::update_delay_settings(
+        args.fetch_delay.or(config.fetch_delay).unwrap_or(2500),
+        args.fetch_delay_var
+            .or(config.fetch_delay_var)
+            .unwrap_or(500),
+    );
+
+    fetch::update_http_retry_settings(args.http_retries.or(config.http_retries).unwrap_or(3));
+
+    let retry_backoff = args.retry_backoff.or(config.retry_backoff);
+    retry::update_retry_settings(args.retries.or(config.retries).unwrap_or(0), retry_backoff);
+    data::file::update_download_retry_settings(
+        args.download_retries
+            .or(config.download_retries)
+            .unwrap_or(0),
+        retry_backoff.unwrap_or(5000),
+    );
+
+    data::file::update_limit_rate_settings(args.limit_rate);
+
+    let proxy = args
+        .proxy
+        .or_else(|| std::env::var("HTTPS_PROXY").ok())
+        .or_else(|| std::env::var("https_proxy").ok())
+        .or(config.proxy);
+    if let Some(proxy) = proxy {
+        fetch::update_proxy_settings(&proxy)?;
+    }
 
-    fetch::update_delay_settings(args.fetch_delay, args.fetch_delay_var);
+    db::update_connect_settings(
+        args.sqlite_journal_mode,
+        args.sqlite_synchronous,
+        args.sqlite_busy_timeout,
+    );
 
-    let database_url = args.database_url.or_else(|| std::env::var("DATABASE_URL").ok())
-        .ok_or_else(|| anyhow::anyhow!("Please specify a database URL via --database-url or the DATABASE_URL environment variable"))?;
+    let database_url = args.database_url.or_else(|| std::env::var("DATABASE_URL").ok()).or(config.database_url)
+        .ok_or_else(|| anyhow::anyhow!("Please specify a database URL via --database-url, the DATABASE_URL environment variable, or the config file"))?;
+    let database_url = db::canonicalize_url(&database_url)?;
+    tracing::debug!("Using database {}", database_url);
 
     let pixiv_cookie = args
         .pixiv_cookie
-        .or_else(|| std::env::var("PIXIV_COOKIE").ok());
+        .or_else(|| std::env::var("PIXIV_COOKIE").ok())
+        .or(config.pixiv_cookie);
 
     let fanbox_cookie = args
         .fanbox_cookie
-        .or_else(|| std::env::var("FANBOX_COOKIE").ok());
+        .or_else(|| std::env::var("FANBOX_COOKIE").ok())
+        .or(config.fanbox_cookie);
 
-    let fanbox_header_full = args
-        .fanbox_header_full
-        .or_else(|| std::env::var("FANBOX_HEADER_FULL").ok());
+    // String > file, and arguments > environment variables > config file
+    let fanbox_header_full_file = args.fanbox_header_full_file.or_else(|| {
+        if args.fanbox_header_full.is_some() || std::env::var("FANBOX_HEADER_FULL").is_ok() {
+            None
+        } else if let Ok(path) = std::env::var("FANBOX_HEADER_FULL_FILE") {
+            Some(PathBuf::from(path))
+        } else if config.fanbox_header_full.is_some() {
+            None
+        } else {
+            config.fanbox_header_full_file
+        }
+    });
+    let fanbox_header_full = match args.fanbox_header_full {
+        Some(header) => Some(header),
+        None => match fanbox_header_full_file {
+            Some(path) => Some(std::fs::read_to_string(&path).map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to read Fanbox headers from {}: {}",
+                    path.display(),
+                    e
+                )
+            })?),
+            None => std::env::var("FANBOX_HEADER_FULL")
+                .ok()
+                .or(config.fanbox_header_full),
+        },
+    };
 
     let pixiv_base_dir = args
         .pixiv_base_dir
-        .or_else(|| std::env::var("PIXIV_BASE_DIR").ok().map(PathBuf::from));
+        .or_else(|| std::env::var("PIXIV_BASE_DIR").ok().map(PathBuf::from))
+        .or(config.pixiv_base_dir);
+    let pixiv_thumb_dir = args
+        .pixiv_thumb_dir
+        .or_else(|| std::env::var("PIXIV_THUMB_DIR").ok().map(PathBuf::from))
+        .or(config.pixiv_thumb_dir);
     let fanbox_base_dir = args
         .fanbox_base_dir
-        .or_else(|| std::env::var("FANBOX_BASE_DIR").ok().map(PathBuf::from));
+        .or_else(|| std::env::var("FANBOX_BASE_DIR").ok().map(PathBuf::from))
+        .or(config.fanbox_base_dir);
 
     let session = config::Session::new(
         pixiv_cookie,
         fanbox_cookie,
         fanbox_header_full,
         pixiv_base_dir,
+        pixiv_thumb_dir,
         fanbox_base_dir,
+        args.user_agent.or(config.user_agent),
+        args.emulation.or(config.emulation).unwrap_or_default(),
     )?;
     args.command.run(&session, &database_url).await?;
 
+    if interrupt::is_interrupted() {
+        return Err(anyhow::anyhow!("Interrupted"));
+    }
     Ok(())
 }
@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+static RETRIES: AtomicUsize = AtomicUsize::new(0);
+// 0 means no backoff
+static RETRY_BACKOFF_MS: AtomicU64 = AtomicU64::new(0);
+
+pub fn update_retry_settings(retries: usize, backoff_ms: Option<u64>) {
+    RETRIES.store(retries, Ordering::Relaxed);
+    RETRY_BACKOFF_MS.store(backoff_ms.unwrap_or(0), Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt
+    pub retries: usize,
+
+    /// Initial delay between attempts, doubled after each retry
+    pub backoff_ms: Option<u64>,
+}
+
+impl RetryPolicy {
+    /// The policy set by the global `--retries` and `--retry-backoff` flags
+    pub fn global() -> RetryPolicy {
+        let backoff_ms = RETRY_BACKOFF_MS.load(Ordering::Relaxed);
+        RetryPolicy {
+            retries: RETRIES.load(Ordering::Relaxed),
+            backoff_ms: (backoff_ms > 0).then_some(backoff_ms),
+        }
+    }
+}
+
+/// Whether retrying may help with an error, e.g. a network failure
+///
+/// Not retried are refusals by pixiv (`IllustError`), like age-restricted or deleted illustrations, and HTTP errors.
+/// Among the latter, 429 and 5xx are already retried by `fetch` itself with `--http-retries`, and retrying them again here
+/// would multiply the attempts. Other 4xx won't change by retrying.
+fn is_retryable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<crate::data::pixiv::IllustError>()
+        .is_none()
+        && e.downcast_ref::<crate::fetch::HttpStatusError>().is_none()
+}
+
+/// Run `f` until it succeeds, at most `1 + policy.retries` times
+///
+/// `what` describes the operation in logs, e.g. `post 1234`. On final failure, or on an error that retrying won't help with,
+/// the error of the last attempt is returned as-is.
+pub async fn with_retries<T, F, Fut>(
+    policy: RetryPolicy,
+    what: impl std::fmt::Display,
+    mut f: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut tries = 0;
+    let mut backoff = policy.backoff_ms;
+    loop {
+        match f().await {
+            Ok(ret) => return Ok(ret),
+            Err(e) if !is_retryable(&e) => return Err(e),
+            Err(e) => {
+                if tries == policy.retries {
+                    if policy.retries > 0 {
+                        tracing::error!(
+                            "Failed to fetch {} after {} tries",
+                            what,
+                            1 + policy.retries
+                        );
+                    }
+                    return Err(e);
+                }
+                tracing::warn!("Failed to fetch {}: {}", what, e);
+                tries += 1;
+                if let Some(ref mut b) = backoff {
+                    tracing::info!("Backing off for {}ms", *b);
+                    tokio::time::sleep(std::time::Duration::from_millis(*b)).await;
+                    *b *= 2;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn attempts(err: impl Fn() -> anyhow::Error) -> usize {
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff_ms: None,
+        };
+        let mut attempts = 0;
+        let ret: anyhow::Result<()> = with_retries(policy, "test", || {
+            attempts += 1;
+            let e = err();
+            async move { Err(e) }
+        })
+        .await;
+        assert!(ret.is_err());
+        attempts
+    }
+
+    #[tokio::test]
+    async fn skips_permanent_errors() {
+        assert_eq!(attempts(|| anyhow::anyhow!("connection reset")).await, 3);
+        let not_found = || {
+            crate::data::pixiv::IllustError::NotFound {
+                id: 1,
+                message: "deleted".to_owned(),
+            }
+            .into()
+        };
+        assert_eq!(attempts(not_found).await, 1);
+    }
+}
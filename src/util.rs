@@ -1,13 +1,14 @@
 use std::{
+    fmt::Display,
     fs::File,
-    io::{BufRead, Read},
+    io::{BufRead, Read, Write},
     path::{Path, PathBuf},
     str::FromStr,
 };
 
 use clap::Args;
 use sha2::{Digest, Sha256};
-use sqlx::{Column, Row, TypeInfo, sqlite::SqliteRow};
+use sqlx::{Column, Row, TypeInfo, ValueRef, sqlite::SqliteRow};
 
 use crate::data::RequestArgumenter;
 
@@ -53,30 +54,110 @@ pub enum DownloadResult {
         written_path: PathBuf,
         final_path: PathBuf,
         size: usize,
+        /// SHA-256 of the content, computed while downloading
+        digest: [u8; 32],
 
         old: DownloadOldResult,
     },
 
     Unchanged {
         size: usize,
+        digest: [u8; 32],
+    },
+
+    /// An unrelated file is already at the target path, and is left untouched
+    Skipped {
+        final_path: PathBuf,
     },
 }
 
-pub async fn download_then_persist<R: RequestArgumenter>(
+/// Turn a display name into a single path component. Returns None if nothing usable is left.
+pub fn sanitize_path_component(name: &str) -> Option<String> {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let sanitized = sanitized.trim();
+    if sanitized.is_empty() || sanitized.starts_with('.') {
+        None
+    } else {
+        Some(sanitized.to_owned())
+    }
+}
+
+/// Fill `{placeholder}`s of a relative path template
+///
+/// Values are sanitized with [`sanitize_path_component`], and placeholders without a usable value become `_`.
+/// Fails on unknown placeholders, and on templates that could escape the directory they are relative to.
+pub fn render_path_template(
+    template: &str,
+    lookup: impl Fn(&str) -> Option<Option<String>>,
+) -> anyhow::Result<PathBuf> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("Unclosed placeholder in template {}", template))?;
+        let name = &rest[start + 1..start + end];
+        let value = lookup(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown placeholder {{{}}} in template", name))?;
+        let value = value.as_deref().and_then(sanitize_path_component);
+        rendered.push_str(value.as_deref().unwrap_or("_"));
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+
+    let path = PathBuf::from(rendered);
+    if path
+        .components()
+        .any(|c| !matches!(c, std::path::Component::Normal(_)))
+    {
+        return Err(anyhow::anyhow!(
+            "Template {} must render into a relative path without `.` or `..`, got {}",
+            template,
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+/// Compute the SHA-256 digest of a file's content
+pub fn hash_file(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut digest = Sha256::new();
+    let mut reader = std::io::BufReader::new(&mut file);
+    std::io::copy(&mut reader, &mut digest)?;
+    Ok(digest.finalize().into())
+}
+
+#[expect(clippy::too_many_arguments)]
+pub async fn download_then_persist<R: RequestArgumenter + Clone>(
     req_arg: R,
     base_dir: &Path,
     filename: &str,
     fmt: DatabasePathFormat,
     url: &str,
+    expected: crate::data::file::ExpectedContent,
     overwrite_behavior: DownloadOverwriteBehavior,
-    show_progress: bool,
+    on_progress: impl FnMut(crate::data::file::DownloadProgress),
 ) -> anyhow::Result<DownloadResult> {
     let (tmp_file, size, digest) =
-        crate::data::file::download_to_tmp(req_arg, base_dir, url, show_progress).await?;
+        crate::data::file::download_to_tmp(req_arg, base_dir, url, expected, on_progress).await?;
 
     let mut final_path = base_dir.canonicalize()?;
     final_path.push(filename);
 
+    let overwrite = matches!(
+        overwrite_behavior,
+        DownloadOverwriteBehavior::Overwrite { .. }
+    );
+
     // Compare against old if requested
     let old = match overwrite_behavior {
         DownloadOverwriteBehavior::Compare { old } => {
@@ -87,17 +168,11 @@ pub async fn download_then_persist<R: RequestArgumenter>(
             }
 
             // Compute hash for the old file
-            let old_digest = tokio::task::block_in_place(|| -> anyhow::Result<[u8; 32]> {
-                let mut old_file = File::open(&old)?;
-                let mut old_digest = Sha256::new();
-                let mut old_reader = std::io::BufReader::new(&mut old_file);
-                std::io::copy(&mut old_reader, &mut old_digest)?;
-                Ok(old_digest.finalize().into())
-            })?;
+            let old_digest = tokio::task::block_in_place(|| hash_file(&old))?;
 
             if old_digest == digest {
                 // No change, skip writing
-                return Ok(DownloadResult::Unchanged { size });
+                return Ok(DownloadResult::Unchanged { size, digest });
             }
 
             // Now decide if old needs to move
@@ -119,19 +194,12 @@ pub async fn download_then_persist<R: RequestArgumenter>(
                 DownloadOldResult::Stale
             }
         }
+        // Overwritten files are replaced by the rename when persisting, so the target never goes missing
         DownloadOverwriteBehavior::Overwrite { old: None } => {
-            match tokio::fs::remove_file(&final_path).await {
-                Ok(_) => {
-                    tracing::info!("Removed: {}", final_path.display());
-                    DownloadOldResult::Overwritten
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    // No existing file, just write
-                    DownloadOldResult::Stale
-                }
-                Err(e) => {
-                    return Err(e.into());
-                }
+            if tokio::fs::try_exists(&final_path).await? {
+                DownloadOldResult::Overwritten
+            } else {
+                DownloadOldResult::Stale
             }
         }
         DownloadOverwriteBehavior::Overwrite { old: Some(old) } => {
@@ -142,8 +210,6 @@ pub async fn download_then_persist<R: RequestArgumenter>(
             }
 
             if final_path == old.canonicalize()? {
-                tokio::fs::remove_file(&final_path).await?;
-                tracing::info!("Removed: {}", final_path.display());
                 DownloadOldResult::Overwritten
             } else {
                 DownloadOldResult::Stale
@@ -152,14 +218,25 @@ pub async fn download_then_persist<R: RequestArgumenter>(
         DownloadOverwriteBehavior::Free => DownloadOldResult::Stale,
     };
 
-    if tokio::fs::try_exists(&final_path).await? {
-        return Err(anyhow::anyhow!(
-            "Final path already exists and not moved / overwritten: {}",
-            final_path.display()
-        ));
+    if overwrite {
+        // Renaming over an existing file is atomic on both Unix and Windows
+        if let DownloadOldResult::Overwritten = old {
+            tracing::info!("Replacing: {}", final_path.display());
+        }
+        tmp_file.persist(&final_path)?;
+    } else {
+        match tmp_file.persist_noclobber(&final_path) {
+            Ok(_) => {}
+            Err(e) if e.error.kind() == std::io::ErrorKind::AlreadyExists => {
+                tracing::warn!(
+                    "Final path already exists and not moved / overwritten, skipping: {}",
+                    final_path.display()
+                );
+                return Ok(DownloadResult::Skipped { final_path });
+            }
+            Err(e) => return Err(e.into()),
+        }
     }
-
-    tmp_file.persist(&final_path)?;
     tracing::debug!("Saved to {}", final_path.display());
 
     let written_path = match fmt {
@@ -172,11 +249,68 @@ pub async fn download_then_persist<R: RequestArgumenter>(
         written_path,
         final_path,
         size,
+        digest,
 
         old,
     })
 }
 
+/// Resolve a pixiv image path recorded in the database
+pub fn resolve_pixiv_image_path(
+    session: &crate::config::Session,
+    path: &str,
+    variant: crate::data::pixiv::ImageVariant,
+) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else if variant.is_thumbnail() {
+        Ok(session.get_pixiv_thumb_dir()?.join(path))
+    } else {
+        Ok(session.get_pixiv_base_dir()?.join(path))
+    }
+}
+
+/// Where the generated thumbnail of a downloaded pixiv image goes, relative to the pixiv base directory
+///
+/// Mirrors the image's path under `.thumbs`, e.g. `.thumbs/<author>/114514_p0.jpg`. Returns None for images outside the base directory, which can't be mirrored without colliding.
+pub fn thumbnail_rel_path(base_dir: &Path, image: &Path) -> Option<PathBuf> {
+    let canonical_base = base_dir.canonicalize().ok();
+    let rel = image
+        .strip_prefix(base_dir)
+        .ok()
+        .or_else(|| image.strip_prefix(canonical_base.as_deref()?).ok())?;
+    Some(Path::new(".thumbs").join(rel).with_extension("jpg"))
+}
+
+/// Downscale an image to fit into `size`x`size` and save it as JPEG
+///
+/// Returns false without touching anything if the thumbnail is already newer than the image.
+pub fn generate_thumbnail(image: &Path, thumbnail: &Path, size: u32) -> anyhow::Result<bool> {
+    if let Ok(thumb_meta) = std::fs::metadata(thumbnail)
+        && thumb_meta.modified()? >= std::fs::metadata(image)?.modified()?
+    {
+        return Ok(false);
+    }
+
+    let img = image::ImageReader::open(image)?
+        .with_guessed_format()?
+        .decode()?;
+    let thumb = image::DynamicImage::ImageRgb8(img.thumbnail(size, size).to_rgb8());
+
+    let dir = thumbnail
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Invalid thumbnail path: {}", thumbnail.display()))?;
+    std::fs::create_dir_all(dir)?;
+    // Written aside and renamed, so an interrupted run never leaves a truncated thumbnail that looks up to date
+    let mut tmp = tempfile::NamedTempFile::with_prefix_in("pixivdwn_", dir)?;
+    let mut writer = std::io::BufWriter::new(tmp.as_file_mut());
+    thumb.write_to(&mut writer, image::ImageFormat::Jpeg)?;
+    writer.into_inner().map_err(|e| e.into_error())?;
+    tmp.persist(thumbnail)?;
+    Ok(true)
+}
+
 #[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
 pub enum TerminationCondition {
     /// Terminate when an already existing illustration is encountered
@@ -186,8 +320,11 @@ pub enum TerminationCondition {
     UntilEnd,
 }
 
+/// Probe the dimensions of an image, reading only as much of its header as the format needs
+///
+/// The format is taken from `mime_type` if given, otherwise from the extension of `path`.
 pub fn get_image_dim(
-    mut file: impl std::io::Read,
+    file: impl std::io::BufRead + std::io::Seek,
     path: impl AsRef<Path>,
     mime_type: Option<&str>,
 ) -> anyhow::Result<(u32, u32)> {
@@ -198,15 +335,23 @@ pub fn get_image_dim(
         image::ImageFormat::from_path(path.as_ref())?
     };
 
-    let mut file_content = Vec::new();
-    file.read_to_end(&mut file_content)?;
-    let file_content = std::io::Cursor::new(file_content);
-
-    let image = image::ImageReader::with_format(file_content, image_fmt);
+    let image = image::ImageReader::with_format(file, image_fmt);
     let (width, height) = image.into_dimensions()?;
     Ok((width, height))
 }
 
+/// Whether the content starts like a zip archive, to tell ugoira packs from ugoira delivered as an animated image
+pub fn is_zip(reader: &mut impl std::io::BufRead) -> std::io::Result<bool> {
+    Ok(reader.fill_buf()?.starts_with(b"PK\x03\x04"))
+}
+
+/// Probe the dimensions of an image of unknown format, e.g. an animated ugoira
+pub fn guess_image_dim(file: impl std::io::BufRead + std::io::Seek) -> anyhow::Result<(u32, u32)> {
+    Ok(image::ImageReader::new(file)
+        .with_guessed_format()?
+        .into_dimensions()?)
+}
+
 #[derive(Args)]
 #[group(multiple = false, required = true)]
 pub struct DownloadIdSrc<U: FromStr>
@@ -214,10 +359,11 @@ where
     <U as FromStr>::Err: Into<Box<dyn std::error::Error + std::marker::Send + Sync + 'static>>,
     U: Send + Sync + Clone + 'static,
 {
-    /// IDs in argument
+    /// IDs in argument, separated by spaces or commas
+    #[arg(value_delimiter = ',')]
     pub id: Option<Vec<U>>,
 
-    /// Reading illustration IDs from a file (`-` for STDIN)
+    /// Reading illustration IDs from a file (`-` for STDIN), one per line. Can't be combined with IDs in argument.
     #[arg(short, long)]
     pub list: Option<String>,
 }
@@ -239,6 +385,88 @@ where
     }
 }
 
+/// Progress checkpoint for a batch of IDs, keyed by the hash of the whole batch and the base directory it downloads into
+///
+/// The checkpoint only records the last ID that completed successfully.
+pub struct Checkpoint {
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    /// Checkpoints are kept in `<config_dir>/checkpoints`, out of the way of downloaded files
+    pub fn new<U: Display>(base_dir: &Path, ids: &[U]) -> anyhow::Result<Self> {
+        let dir = crate::config::config_dir().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Cannot locate the config directory for checkpoints, please set XDG_CONFIG_HOME or HOME"
+            )
+        })?;
+        let mut digest = Sha256::new();
+        digest.update(base_dir.as_os_str().as_encoded_bytes());
+        digest.update(b"\n");
+        for id in ids {
+            digest.update(id.to_string().as_bytes());
+            digest.update(b"\n");
+        }
+        let path = dir.join("checkpoints").join(hex::encode(digest.finalize()));
+        Ok(Checkpoint { path })
+    }
+
+    pub fn load(&self) -> anyhow::Result<Option<String>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(content) => Ok(Some(content.trim().to_owned())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, id: impl Display) -> anyhow::Result<()> {
+        let dir = self.path.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+        // Write then rename, so that a crash never leaves a truncated checkpoint
+        let mut tmp = tempfile::NamedTempFile::with_prefix_in("pixivdwn_", dir)?;
+        writeln!(tmp, "{}", id)?;
+        tmp.persist(&self.path)?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Skip IDs up to (and including) the checkpointed one
+    pub fn resume<U: Display>(
+        &self,
+        ids: Vec<U>,
+    ) -> anyhow::Result<impl Iterator<Item = U> + use<U>> {
+        let skip = match self.load()? {
+            None => 0,
+            Some(last) => match ids.iter().position(|id| id.to_string() == last) {
+                Some(pos) => {
+                    tracing::info!(
+                        "Resuming after {} ({}/{} already completed)",
+                        last,
+                        pos + 1,
+                        ids.len()
+                    );
+                    pos + 1
+                }
+                None => {
+                    tracing::warn!(
+                        "Checkpointed ID {} not found in the list, starting over",
+                        last
+                    );
+                    0
+                }
+            },
+        };
+        Ok(ids.into_iter().skip(skip))
+    }
+}
+
 fn read_spec<T: FromStr>(
     src: &str,
 ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<T>> + 'static> {
@@ -255,6 +483,176 @@ fn read_spec<T: FromStr>(
     }))
 }
 
+/// Parse a timestamp given on the command line
+///
+/// Accepts RFC 3339 (`2024-01-31T12:00:00+09:00`), or `2024-01-31 12:00:00` / `2024-01-31` which are taken as UTC
+pub fn parse_cli_datetime(s: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.to_utc());
+    }
+    if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S") {
+        return Ok(dt.and_utc());
+    }
+    if let Ok(d) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(d.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+    Err(format!(
+        "invalid timestamp `{}`, expected RFC 3339 (2024-01-31T12:00:00+09:00), `YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DD` (UTC)",
+        s
+    ))
+}
+
+/// Parse the upper bound of a time range given on the command line, in the same formats as `parse_cli_datetime`
+///
+/// A date alone covers that whole day (in UTC), so it's taken as its last second. Stored dates have second precision.
+pub fn parse_cli_datetime_end(s: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    if let Ok(d) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        let last_second = chrono::NaiveTime::from_hms_opt(23, 59, 59).unwrap();
+        return Ok(d.and_time(last_second).and_utc());
+    }
+    parse_cli_datetime(s)
+}
+
+/// A `LIKE` pattern matching strings containing `s`, to be used with `ESCAPE '\'`
+pub fn like_contains_pattern(s: &str) -> String {
+    format!(
+        "%{}%",
+        s.replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+/// A condition matching rows of `table` whose `cols` contain `phrase`, through the full-text index `{table}_fts`
+///
+/// The trigram index can't match phrases shorter than 3 characters, those fall back to scanning with `LIKE`.
+pub fn search_condition(
+    table: &str,
+    cols: &[&str],
+    phrase: &str,
+    params: &mut Vec<crate::db::QueryParam>,
+) -> String {
+    if phrase.chars().count() < 3 {
+        let pattern = like_contains_pattern(phrase);
+        let likes: Vec<String> = cols
+            .iter()
+            .map(|col| {
+                params.push(crate::db::QueryParam::Text(pattern.clone()));
+                format!(r#"{}.{} LIKE ? ESCAPE '\'"#, table, col)
+            })
+            .collect();
+        return format!("({})", likes.join(" OR "));
+    }
+
+    // Quoted as a single FTS5 string, so the phrase is never parsed as query syntax
+    params.push(crate::db::QueryParam::Text(format!(
+        "\"{}\"",
+        phrase.replace('"', "\"\"")
+    )));
+    format!(
+        "{table}.id IN (SELECT rowid FROM {table}_fts WHERE {table}_fts MATCH ?)",
+        table = table
+    )
+}
+
+/// Print a hand-built query, followed by its bound parameters
+pub fn print_sql(sql: &str, params: &[crate::db::QueryParam]) {
+    println!("{}", sql);
+    if !params.is_empty() {
+        println!(
+            "-- params: {}",
+            params
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+}
+
+/// Parse a duration given on the command line, e.g. `90s`, `30m`, `1h30m` or `1d`. A bare number is in seconds.
+pub fn parse_cli_duration(s: &str) -> Result<std::time::Duration, String> {
+    let err = || {
+        format!(
+            "invalid duration `{}`, expected e.g. `90s`, `30m`, `1h30m` or `1d`",
+            s
+        )
+    };
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(std::time::Duration::from_secs(secs));
+    }
+
+    let mut total = 0u64;
+    let mut num = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(err()),
+        };
+        let n: u64 = num.parse().map_err(|_| err())?;
+        total = n
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(err)?;
+        num.clear();
+    }
+    if !num.is_empty() || s.is_empty() {
+        return Err(err());
+    }
+    Ok(std::time::Duration::from_secs(total))
+}
+
+/// The deadline for `--max-duration` from now
+///
+/// Durations too large to represent are treated as no deadline.
+pub fn deadline_after(duration: Option<std::time::Duration>) -> Option<tokio::time::Instant> {
+    duration.and_then(|d| tokio::time::Instant::now().checked_add(d))
+}
+
+/// Parse a transfer rate given on the command line in bytes per second, e.g. `500000`, `500K` or `2M` (K and M are 1024-based)
+pub fn parse_cli_rate(s: &str) -> Result<u64, String> {
+    let err = || {
+        format!(
+            "invalid rate `{}`, expected e.g. `500000`, `500K` or `2M`",
+            s
+        )
+    };
+    let (num, unit) = match s.strip_suffix(['k', 'K']) {
+        Some(num) => (num, 1024),
+        None => match s.strip_suffix(['m', 'M']) {
+            Some(num) => (num, 1024 * 1024),
+            None => (s, 1),
+        },
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|num| num.checked_mul(unit))
+        .filter(|rate| *rate > 0)
+        .ok_or_else(err)
+}
+
+/// Convert a single value by its actual storage class, which also works for computed columns without a declared type
+pub fn db_value_to_json(row: &SqliteRow, ordinal: usize) -> anyhow::Result<serde_json::Value> {
+    let raw = row.try_get_raw(ordinal)?;
+    if raw.is_null() {
+        return Ok(serde_json::Value::Null);
+    }
+    let ty = raw.type_info().name().to_owned();
+    Ok(match ty.as_str() {
+        "INTEGER" => row.get::<i64, _>(ordinal).into(),
+        "REAL" => row.get::<f64, _>(ordinal).into(),
+        "TEXT" => row.get::<String, _>(ordinal).into(),
+        _ => return Err(anyhow::anyhow!("Unsupported value type: {}", ty)),
+    })
+}
+
 pub fn db_row_to_json(
     row: SqliteRow,
 ) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
@@ -263,8 +661,14 @@ pub fn db_row_to_json(
         let name = col.name();
         let ordinal = col.ordinal();
         let ty = col.type_info();
+        // Declared column type doesn't tell if this specific value is NULL
+        if row.try_get_raw(ordinal)?.is_null() {
+            map.insert(name.to_string(), serde_json::Value::Null);
+            continue;
+        }
         let val: serde_json::Value = match ty.name() {
-            "NULL" => serde_json::Value::Null,
+            // Computed columns, e.g. aggregates
+            "NULL" => db_value_to_json(&row, ordinal)?,
             "INTEGER" => row.get::<i64, _>(ordinal).into(),
             "REAL" => row.get::<f64, _>(ordinal).into(),
             "TEXT" => row.get::<String, _>(ordinal).into(),
@@ -275,3 +679,158 @@ pub fn db_row_to_json(
     }
     Ok(map)
 }
+
+/// How `print_query_rows` prints the result of a query
+#[derive(Clone, Copy)]
+pub enum RowsFormat {
+    /// The `count` column of the only row
+    Count,
+
+    /// The `id` column of each row, one per line
+    ID,
+
+    /// All columns of each row, as a JSON array
+    Json,
+}
+
+/// Print the rows of a query run for `--format`. `ID` is the type of the `id` column.
+pub fn print_query_rows<ID>(rows: Vec<SqliteRow>, format: RowsFormat) -> anyhow::Result<()>
+where
+    ID: for<'r> sqlx::Decode<'r, sqlx::Sqlite> + sqlx::Type<sqlx::Sqlite> + Display,
+{
+    match format {
+        RowsFormat::Count => {
+            let row = rows
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("COUNT query returned no rows"))?;
+            let count: i64 = row.try_get("count")?;
+            println!("{}", count);
+        }
+        RowsFormat::ID => {
+            for row in rows {
+                let id: ID = row.try_get("id")?;
+                println!("{}", id);
+            }
+        }
+        RowsFormat::Json => {
+            println!("[");
+            let mut first = true;
+            for row in rows {
+                if !first {
+                    println!(",");
+                }
+                first = false;
+                let json = db_row_to_json(row)?;
+                println!("{}", serde_json::to_string(&json)?);
+            }
+            println!("]");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn cli_duration() {
+        assert_eq!(parse_cli_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_cli_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(
+            parse_cli_duration("1h30m"),
+            Ok(Duration::from_secs(90 * 60))
+        );
+        assert_eq!(
+            parse_cli_duration("1d"),
+            Ok(Duration::from_secs(24 * 60 * 60))
+        );
+        for invalid in ["", "h", "1x", "1h30", "-1s"] {
+            assert!(parse_cli_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cli_duration_overflow() {
+        assert!(parse_cli_duration("18446744073709551615d").is_err());
+        assert!(parse_cli_duration("18446744073709551615s1s").is_err());
+        // Too long to be a deadline, but not an error
+        let forever = parse_cli_duration("18446744073709551615").unwrap();
+        assert_eq!(deadline_after(Some(forever)), None);
+    }
+
+    #[test]
+    fn cli_rate() {
+        assert_eq!(parse_cli_rate("500000"), Ok(500000));
+        assert_eq!(parse_cli_rate("500K"), Ok(500 * 1024));
+        assert_eq!(parse_cli_rate("2m"), Ok(2 * 1024 * 1024));
+        for invalid in ["", "0", "0K", "K", "1.5M", "18446744073709551615M"] {
+            assert!(parse_cli_rate(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn path_component() {
+        assert_eq!(
+            sanitize_path_component(" a/b:c* ").as_deref(),
+            Some("a_b_c_")
+        );
+        assert_eq!(sanitize_path_component("..").as_deref(), None);
+        assert_eq!(sanitize_path_component(".hidden").as_deref(), None);
+        assert_eq!(sanitize_path_component("  ").as_deref(), None);
+    }
+
+    #[test]
+    fn path_template() {
+        let lookup = |name: &str| match name {
+            "author" => Some(Some("../Author/Name".to_owned())),
+            "title" => Some(Some("Title: 1".to_owned())),
+            "series" => Some(None),
+            _ => None,
+        };
+        assert_eq!(
+            render_path_template("{title}/{series}", lookup).unwrap(),
+            PathBuf::from("Title_ 1/_")
+        );
+        // Values can't escape, since separators and leading dots are sanitized away
+        assert_eq!(
+            render_path_template("by/{author}", lookup).unwrap(),
+            PathBuf::from("by/_")
+        );
+
+        for template in [
+            "../{title}",
+            "/{title}",
+            "./{title}",
+            "a/../{title}",
+            "{unknown}",
+            "{title",
+        ] {
+            assert!(
+                render_path_template(template, lookup).is_err(),
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn checkpoint_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = Checkpoint {
+            path: dir.path().join("checkpoint"),
+        };
+        let resume = |ids: Vec<u64>| checkpoint.resume(ids).unwrap().collect::<Vec<_>>();
+
+        assert_eq!(resume(vec![1, 2, 3]), [1, 2, 3]);
+        checkpoint.save(2).unwrap();
+        assert_eq!(resume(vec![1, 2, 3]), [3]);
+        // Unknown checkpoints start over
+        assert_eq!(resume(vec![4, 5]), [4, 5]);
+        checkpoint.clear().unwrap();
+        assert_eq!(resume(vec![1, 2, 3]), [1, 2, 3]);
+    }
+}